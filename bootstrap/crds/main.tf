@@ -1,11 +1,33 @@
 resource "kubernetes_manifest" "customresourcedefinition_blockfrostports_demeter_run" {
+  # The caBundle of the conversion webhook is injected by cert-manager.
+  computed_fields = ["metadata.labels", "metadata.annotations", "spec.conversion.webhook.clientConfig.caBundle"]
+
   manifest = {
     "apiVersion" = "apiextensions.k8s.io/v1"
     "kind"       = "CustomResourceDefinition"
     "metadata" = {
+      "annotations" = {
+        "cert-manager.io/inject-ca-from" = "${var.namespace}/blockfrost-operator-webhook"
+      }
       "name" = "blockfrostports.demeter.run"
     }
     "spec" = {
+      "conversion" = {
+        "strategy" = "Webhook"
+        "webhook" = {
+          "clientConfig" = {
+            "service" = {
+              "name"      = "blockfrost-operator"
+              "namespace" = var.namespace
+              "path"      = "/convert"
+              "port"      = 9443
+            }
+          }
+          "conversionReviewVersions" = [
+            "v1",
+          ]
+        }
+      }
       "group" = "demeter.run"
       "names" = {
         "categories" = [
@@ -38,15 +60,20 @@ resource "kubernetes_manifest" "customresourcedefinition_blockfrostports_demeter
               "type"     = "string"
             },
             {
-              "jsonPath" = ".status.authenticatedEndpointUrl"
-              "name"     = "Authenticated Endpoint URL"
+              "jsonPath" = ".status.secretName"
+              "name"     = "Auth Secret"
               "type"     = "string"
             },
             {
-              "jsonPath" = ".status.authToken"
-              "name"     = "Auth Token"
+              "jsonPath" = ".status.conditions[?(@.type==\"Ready\")].status"
+              "name"     = "Ready"
               "type"     = "string"
             },
+            {
+              "jsonPath" = ".spec.suspended"
+              "name"     = "Suspended"
+              "type"     = "boolean"
+            },
           ]
           "name" = "v1alpha1"
           "schema" = {
@@ -55,6 +82,22 @@ resource "kubernetes_manifest" "customresourcedefinition_blockfrostports_demeter
               "properties" = {
                 "spec" = {
                   "properties" = {
+                    "allowedIps" = {
+                      "description" = "Client addresses allowed to use the port, as CIDRs or single addresses."
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "nullable" = true
+                      "type"     = "array"
+                    }
+                    "allowedOrigins" = {
+                      "description" = "Origins allowed to call the port from a browser, e.g. `https://app.example.com`, or `*` for any origin."
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "nullable" = true
+                      "type"     = "array"
+                    }
                     "authToken" = {
                       "nullable" = true
                       "type"     = "string"
@@ -63,12 +106,92 @@ resource "kubernetes_manifest" "customresourcedefinition_blockfrostports_demeter
                       "nullable" = true
                       "type"     = "string"
                     }
+                    "customDomains" = {
+                      "description" = "Hostnames serving the port besides the extension hostname, authenticated as the port key."
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "nullable" = true
+                      "type"     = "array"
+                    }
+                    "endpoints" = {
+                      "description" = "Endpoints the port can call, besides the tier policy."
+                      "nullable"    = true
+                      "properties" = {
+                        "allow" = {
+                          "description" = "Only paths matching one of these patterns are allowed, when set."
+                          "items" = {
+                            "type" = "string"
+                          }
+                          "nullable" = true
+                          "type"     = "array"
+                        }
+                        "deny" = {
+                          "description" = "Paths matching one of these patterns are denied."
+                          "items" = {
+                            "type" = "string"
+                          }
+                          "nullable" = true
+                          "type"     = "array"
+                        }
+                        "readOnly" = {
+                          "description" = "Only GET, HEAD and OPTIONS requests are allowed."
+                          "nullable"    = true
+                          "type"        = "boolean"
+                        }
+                      }
+                      "type" = "object"
+                    }
+                    "keys" = {
+                      "description" = "Additional named keys sharing the port tier limits."
+                      "items" = {
+                        "properties" = {
+                          "expiresAt" = {
+                            "nullable" = true
+                            "type"     = "string"
+                          }
+                          "name" = {
+                            "type" = "string"
+                          }
+                          "rotation" = {
+                            "description" = "Bump to mint a new key under the same name. The previous key is accepted during the grace period."
+                            "format"      = "uint32"
+                            "minimum"     = 0.0
+                            "nullable"    = true
+                            "type"        = "integer"
+                          }
+                        }
+                        "required" = [
+                          "name",
+                        ]
+                        "type" = "object"
+                      }
+                      "nullable" = true
+                      "type"     = "array"
+                    }
                     "network" = {
                       "type" = "string"
                     }
                     "operatorVersion" = {
                       "type" = "string"
                     }
+                    "rotation" = {
+                      "description" = "Bump to mint a new key. The previous key is accepted during the grace period."
+                      "format"      = "uint32"
+                      "minimum"     = 0.0
+                      "nullable"    = true
+                      "type"        = "integer"
+                    }
+                    "suspended" = {
+                      "description" = "Rejects the port requests without deleting it, so its keys are kept."
+                      "nullable"    = true
+                      "type"        = "boolean"
+                    }
+                    "suspensionReason" = {
+                      "description" = "Reason returned to the consumer while the port is suspended."
+                      "nullable"    = true
+                      "type"        = "string"
+                    }
                     "throughputTier" = {
                       "type" = "string"
                     }
@@ -83,23 +206,493 @@ resource "kubernetes_manifest" "customresourcedefinition_blockfrostports_demeter
                 "status" = {
                   "nullable" = true
                   "properties" = {
-                    "authToken" = {
-                      "type" = "string"
+                    "authTokenHash" = {
+                      "default" = ""
+                      "type"    = "string"
+                    }
+                    "conditions" = {
+                      "default" = []
+                      "items" = {
+                        "properties" = {
+                          "lastTransitionTime" = {
+                            "nullable" = true
+                            "type"     = "string"
+                          }
+                          "message" = {
+                            "nullable" = true
+                            "type"     = "string"
+                          }
+                          "reason" = {
+                            "nullable" = true
+                            "type"     = "string"
+                          }
+                          "status" = {
+                            "type" = "string"
+                          }
+                          "type" = {
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "status",
+                          "type",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
+                    "customDomains" = {
+                      "default" = []
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "type" = "array"
+                    }
+                    "endpointUrl" = {
+                      "default" = ""
+                      "type"    = "string"
+                    }
+                    "keySalt" = {
+                      "description" = "Fingerprint of the salt the keys were issued with."
+                      "nullable"    = true
+                      "type"        = "string"
+                    }
+                    "keys" = {
+                      "default" = []
+                      "items" = {
+                        "properties" = {
+                          "authTokenHash" = {
+                            "type" = "string"
+                          }
+                          "expiresAt" = {
+                            "nullable" = true
+                            "type"     = "string"
+                          }
+                          "name" = {
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "authTokenHash",
+                          "name",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
                     }
-                    "authenticatedEndpointUrl" = {
+                    "legacyAuthToken" = {
+                      "default"     = false
+                      "description" = "The port key is a malformed token migrated from `spec.authToken`, which the proxy only looks up when some port has one."
+                      "type"        = "boolean"
+                    }
+                    "observedGeneration" = {
+                      "format"   = "int64"
+                      "nullable" = true
+                      "type"     = "integer"
+                    }
+                    "previousAuthTokenExpiresAt" = {
                       "nullable" = true
                       "type"     = "string"
                     }
-                    "endpointUrl" = {
+                    "previousAuthTokenHash" = {
+                      "nullable" = true
+                      "type"     = "string"
+                    }
+                    "previousKeys" = {
+                      "default"     = []
+                      "description" = "Replaced named keys, accepted until they expire like the previous port key."
+                      "items" = {
+                        "properties" = {
+                          "authTokenHash" = {
+                            "type" = "string"
+                          }
+                          "expiresAt" = {
+                            "nullable" = true
+                            "type"     = "string"
+                          }
+                          "name" = {
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "authTokenHash",
+                          "name",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
+                    "secretName" = {
+                      "description" = "Secret holding the plaintext keys and the authenticated endpoint urls."
+                      "nullable"    = true
+                      "type"        = "string"
+                    }
+                    "usage" = {
+                      "description" = "Requests counted in the current quota period, published by the usage collector. The reconciler never writes it, so a stale object can't overwrite newer usage."
+                      "nullable"    = true
+                      "properties" = {
+                        "period" = {
+                          "description" = "Calendar month in UTC, e.g. 2024-01."
+                          "type"        = "string"
+                        }
+                        "requests" = {
+                          "format"  = "uint64"
+                          "minimum" = 0.0
+                          "type"    = "integer"
+                        }
+                        "updatedAt" = {
+                          "type" = "string"
+                        }
+                      }
+                      "required" = [
+                        "period",
+                        "requests",
+                        "updatedAt",
+                      ]
+                      "type" = "object"
+                    }
+                  }
+                  "type" = "object"
+                }
+              }
+              "required" = [
+                "spec",
+              ]
+              "title" = "BlockfrostPort"
+              "type"  = "object"
+            }
+          }
+          "served"  = true
+          "storage" = true
+          "subresources" = {
+            "status" = {}
+          }
+        },
+        {
+          "additionalPrinterColumns" = [
+            {
+              "jsonPath" = ".spec.network"
+              "name"     = "Network"
+              "type"     = "string"
+            },
+            {
+              "jsonPath" = ".spec.throughputTier"
+              "name"     = "Throughput Tier"
+              "type"     = "string"
+            },
+            {
+              "jsonPath" = ".status.endpointUrl"
+              "name"     = "Endpoint URL"
+              "type"     = "string"
+            },
+            {
+              "jsonPath" = ".status.secretName"
+              "name"     = "Auth Secret"
+              "type"     = "string"
+            },
+            {
+              "jsonPath" = ".status.conditions[?(@.type==\"Ready\")].status"
+              "name"     = "Ready"
+              "type"     = "string"
+            },
+            {
+              "jsonPath" = ".spec.suspended"
+              "name"     = "Suspended"
+              "type"     = "boolean"
+            },
+          ]
+          "name" = "v1alpha2"
+          "schema" = {
+            "openAPIV3Schema" = {
+              "description" = "Auto-generated derived type for BlockfrostPortSpec via `CustomResource`"
+              "properties" = {
+                "spec" = {
+                  "properties" = {
+                    "allowedIps" = {
+                      "description" = "Client addresses allowed to use the port, as CIDRs or single addresses."
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "nullable" = true
+                      "type"     = "array"
+                    }
+                    "allowedOrigins" = {
+                      "description" = "Origins allowed to call the port from a browser, or `*` for any origin."
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "nullable" = true
+                      "type"     = "array"
+                    }
+                    "auth" = {
+                      "default" = {
+                        "keys"     = []
+                        "rotation" = null
+                        "token"    = null
+                      }
+                      "properties" = {
+                        "keys" = {
+                          "default"     = []
+                          "description" = "Additional named keys sharing the port tier limits."
+                          "items" = {
+                            "properties" = {
+                              "expiresAt" = {
+                                "nullable" = true
+                                "type"     = "string"
+                              }
+                              "name" = {
+                                "type" = "string"
+                              }
+                              "rotation" = {
+                                "description" = "Bump to mint a new key under the same name. The previous key is accepted during the grace period."
+                                "format"      = "uint32"
+                                "minimum"     = 0.0
+                                "nullable"    = true
+                                "type"        = "integer"
+                              }
+                            }
+                            "required" = [
+                              "name",
+                            ]
+                            "type" = "object"
+                          }
+                          "type" = "array"
+                        }
+                        "rotation" = {
+                          "description" = "Bump to mint a new key. The previous key is accepted during the grace period."
+                          "format"      = "uint32"
+                          "minimum"     = 0.0
+                          "nullable"    = true
+                          "type"        = "integer"
+                        }
+                        "token" = {
+                          "description" = "Fixed key for the port instead of the generated one."
+                          "nullable"    = true
+                          "type"        = "string"
+                        }
+                      }
+                      "type" = "object"
+                    }
+                    "blockfrostVersion" = {
+                      "nullable" = true
+                      "type"     = "string"
+                    }
+                    "customDomains" = {
+                      "default"     = []
+                      "description" = "Hostnames serving the port besides the extension hostname, authenticated as the port key."
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "type" = "array"
+                    }
+                    "endpoints" = {
+                      "description" = "Endpoints the port can call, besides the tier policy."
+                      "nullable"    = true
+                      "properties" = {
+                        "allow" = {
+                          "description" = "Only paths matching one of these patterns are allowed, when set."
+                          "items" = {
+                            "type" = "string"
+                          }
+                          "nullable" = true
+                          "type"     = "array"
+                        }
+                        "deny" = {
+                          "description" = "Paths matching one of these patterns are denied."
+                          "items" = {
+                            "type" = "string"
+                          }
+                          "nullable" = true
+                          "type"     = "array"
+                        }
+                        "readOnly" = {
+                          "description" = "Only GET, HEAD and OPTIONS requests are allowed."
+                          "nullable"    = true
+                          "type"        = "boolean"
+                        }
+                      }
+                      "type" = "object"
+                    }
+                    "network" = {
+                      "description" = "Network name from the networks configured on the operator, e.g. `cardano-mainnet`."
+                      "type"        = "string"
+                    }
+                    "operatorVersion" = {
                       "type" = "string"
                     }
+                    "suspended" = {
+                      "description" = "Rejects the port requests without deleting it, so its keys are kept."
+                      "nullable"    = true
+                      "type"        = "boolean"
+                    }
+                    "suspensionReason" = {
+                      "description" = "Reason returned to the consumer while the port is suspended."
+                      "nullable"    = true
+                      "type"        = "string"
+                    }
+                    "throughputTier" = {
+                      "description" = "Tier name from the tiers configured on the operator and the proxy."
+                      "type"        = "string"
+                    }
                   }
                   "required" = [
-                    "authToken",
-                    "endpointUrl",
+                    "network",
+                    "operatorVersion",
+                    "throughputTier",
                   ]
                   "type" = "object"
                 }
+                "status" = {
+                  "nullable" = true
+                  "properties" = {
+                    "authTokenHash" = {
+                      "default" = ""
+                      "type"    = "string"
+                    }
+                    "conditions" = {
+                      "default" = []
+                      "items" = {
+                        "properties" = {
+                          "lastTransitionTime" = {
+                            "nullable" = true
+                            "type"     = "string"
+                          }
+                          "message" = {
+                            "nullable" = true
+                            "type"     = "string"
+                          }
+                          "reason" = {
+                            "nullable" = true
+                            "type"     = "string"
+                          }
+                          "status" = {
+                            "type" = "string"
+                          }
+                          "type" = {
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "status",
+                          "type",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
+                    "customDomains" = {
+                      "default" = []
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "type" = "array"
+                    }
+                    "endpointUrl" = {
+                      "default" = ""
+                      "type"    = "string"
+                    }
+                    "keySalt" = {
+                      "description" = "Fingerprint of the salt the keys were issued with."
+                      "nullable"    = true
+                      "type"        = "string"
+                    }
+                    "keys" = {
+                      "default" = []
+                      "items" = {
+                        "properties" = {
+                          "authTokenHash" = {
+                            "type" = "string"
+                          }
+                          "expiresAt" = {
+                            "nullable" = true
+                            "type"     = "string"
+                          }
+                          "name" = {
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "authTokenHash",
+                          "name",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
+                    "legacyAuthToken" = {
+                      "default"     = false
+                      "description" = "The port key is a malformed token migrated from `spec.authToken`, which the proxy only looks up when some port has one."
+                      "type"        = "boolean"
+                    }
+                    "observedGeneration" = {
+                      "format"   = "int64"
+                      "nullable" = true
+                      "type"     = "integer"
+                    }
+                    "previousAuthTokenExpiresAt" = {
+                      "nullable" = true
+                      "type"     = "string"
+                    }
+                    "previousAuthTokenHash" = {
+                      "nullable" = true
+                      "type"     = "string"
+                    }
+                    "previousKeys" = {
+                      "default"     = []
+                      "description" = "Replaced named keys, accepted until they expire like the previous port key."
+                      "items" = {
+                        "properties" = {
+                          "authTokenHash" = {
+                            "type" = "string"
+                          }
+                          "expiresAt" = {
+                            "nullable" = true
+                            "type"     = "string"
+                          }
+                          "name" = {
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "authTokenHash",
+                          "name",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
+                    "secretName" = {
+                      "description" = "Secret holding the plaintext keys and the authenticated endpoint urls."
+                      "nullable"    = true
+                      "type"        = "string"
+                    }
+                    "usage" = {
+                      "description" = "Requests counted in the current quota period, published by the usage collector. The reconciler never writes it, so a stale object can't overwrite newer usage."
+                      "nullable"    = true
+                      "properties" = {
+                        "period" = {
+                          "description" = "Calendar month in UTC, e.g. 2024-01."
+                          "type"        = "string"
+                        }
+                        "requests" = {
+                          "format"  = "uint64"
+                          "minimum" = 0.0
+                          "type"    = "integer"
+                        }
+                        "updatedAt" = {
+                          "type" = "string"
+                        }
+                      }
+                      "required" = [
+                        "period",
+                        "requests",
+                        "updatedAt",
+                      ]
+                      "type" = "object"
+                    }
+                  }
+                  "type" = "object"
+                }
               }
               "required" = [
                 "spec",
@@ -109,7 +702,7 @@ resource "kubernetes_manifest" "customresourcedefinition_blockfrostports_demeter
             }
           }
           "served"  = true
-          "storage" = true
+          "storage" = false
           "subresources" = {
             "status" = {}
           }
@@ -118,3 +711,275 @@ resource "kubernetes_manifest" "customresourcedefinition_blockfrostports_demeter
     }
   }
 }
+
+resource "kubernetes_manifest" "customresourcedefinition_blockfrosttiers_demeter_run" {
+  manifest = {
+    "apiVersion" = "apiextensions.k8s.io/v1"
+    "kind"       = "CustomResourceDefinition"
+    "metadata" = {
+      "name" = "blockfrosttiers.demeter.run"
+    }
+    "spec" = {
+      "group" = "demeter.run"
+      "names" = {
+        "categories" = [
+          "demeter-port",
+        ]
+        "kind"   = "BlockfrostTier"
+        "plural" = "blockfrosttiers"
+        "shortNames" = [
+          "bftiers",
+        ]
+        "singular" = "blockfrosttier"
+      }
+      "scope" = "Cluster"
+      "versions" = [
+        {
+          "additionalPrinterColumns" = [
+            {
+              "jsonPath" = ".spec.monthlyQuota"
+              "name"     = "Monthly Quota"
+              "type"     = "integer"
+            },
+          ]
+          "name" = "v1alpha1"
+          "schema" = {
+            "openAPIV3Schema" = {
+              "description" = "Auto-generated derived type for BlockfrostTierSpec via `CustomResource`"
+              "properties" = {
+                "spec" = {
+                  "description" = "Throughput tier of the proxy. The object name is the tier name used by the ports `throughputTier`."
+                  "properties" = {
+                    "endpoints" = {
+                      "description" = "Endpoints the ports of the tier can call."
+                      "nullable"    = true
+                      "properties" = {
+                        "allow" = {
+                          "description" = "Only paths matching one of these patterns are allowed, when set."
+                          "items" = {
+                            "type" = "string"
+                          }
+                          "nullable" = true
+                          "type"     = "array"
+                        }
+                        "deny" = {
+                          "description" = "Paths matching one of these patterns are denied."
+                          "items" = {
+                            "type" = "string"
+                          }
+                          "nullable" = true
+                          "type"     = "array"
+                        }
+                        "readOnly" = {
+                          "description" = "Only GET, HEAD and OPTIONS requests are allowed."
+                          "nullable"    = true
+                          "type"        = "boolean"
+                        }
+                      }
+                      "type" = "object"
+                    }
+                    "monthlyQuota" = {
+                      "description" = "Requests allowed per calendar month, shared by every key of the port."
+                      "format"      = "uint64"
+                      "minimum"     = 0.0
+                      "nullable"    = true
+                      "type"        = "integer"
+                    }
+                    "rates" = {
+                      "items" = {
+                        "properties" = {
+                          "interval" = {
+                            "description" = "Number followed by s, m, h or d, e.g. `1s` or `30d`."
+                            "pattern"     = "^\\d+[smhd]$"
+                            "type"        = "string"
+                          }
+                          "limit" = {
+                            "description" = "Requests allowed per interval, at least 1."
+                            "format"      = "int64"
+                            "minimum"     = 1.0
+                            "type"        = "integer"
+                          }
+                        }
+                        "required" = [
+                          "interval",
+                          "limit",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
+                  }
+                  "required" = [
+                    "rates",
+                  ]
+                  "type" = "object"
+                }
+              }
+              "required" = [
+                "spec",
+              ]
+              "title" = "BlockfrostTier"
+              "type"  = "object"
+            }
+          }
+          "served"       = true
+          "storage"      = true
+          "subresources" = {}
+        },
+      ]
+    }
+  }
+}
+
+resource "kubernetes_manifest" "customresourcedefinition_blockfrostcacherules_demeter_run" {
+  manifest = {
+    "apiVersion" = "apiextensions.k8s.io/v1"
+    "kind"       = "CustomResourceDefinition"
+    "metadata" = {
+      "name" = "blockfrostcacherules.demeter.run"
+    }
+    "spec" = {
+      "group" = "demeter.run"
+      "names" = {
+        "categories" = [
+          "demeter-port",
+        ]
+        "kind"   = "BlockfrostCacheRule"
+        "plural" = "blockfrostcacherules"
+        "shortNames" = [
+          "bfcrules",
+        ]
+        "singular" = "blockfrostcacherule"
+      }
+      "scope" = "Cluster"
+      "versions" = [
+        {
+          "additionalPrinterColumns" = [
+            {
+              "jsonPath" = ".spec.endpoint"
+              "name"     = "Endpoint"
+              "type"     = "string"
+            },
+            {
+              "jsonPath" = ".spec.durationSeconds"
+              "name"     = "Duration"
+              "type"     = "integer"
+            },
+          ]
+          "name" = "v1alpha1"
+          "schema" = {
+            "openAPIV3Schema" = {
+              "description" = "Auto-generated derived type for BlockfrostCacheRuleSpec via `CustomResource`"
+              "properties" = {
+                "spec" = {
+                  "description" = "Cache duration for the endpoints matching a regex. Rules are matched in object name order and the first match wins."
+                  "properties" = {
+                    "durationSeconds" = {
+                      "format"  = "uint64"
+                      "minimum" = 0.0
+                      "type"    = "integer"
+                    }
+                    "endpoint" = {
+                      "type" = "string"
+                    }
+                  }
+                  "required" = [
+                    "durationSeconds",
+                    "endpoint",
+                  ]
+                  "type" = "object"
+                }
+              }
+              "required" = [
+                "spec",
+              ]
+              "title" = "BlockfrostCacheRule"
+              "type"  = "object"
+            }
+          }
+          "served"       = true
+          "storage"      = true
+          "subresources" = {}
+        },
+      ]
+    }
+  }
+}
+
+resource "kubernetes_manifest" "customresourcedefinition_blockfrostroutes_demeter_run" {
+  manifest = {
+    "apiVersion" = "apiextensions.k8s.io/v1"
+    "kind"       = "CustomResourceDefinition"
+    "metadata" = {
+      "name" = "blockfrostroutes.demeter.run"
+    }
+    "spec" = {
+      "group" = "demeter.run"
+      "names" = {
+        "categories" = [
+          "demeter-port",
+        ]
+        "kind"   = "BlockfrostRoute"
+        "plural" = "blockfrostroutes"
+        "shortNames" = [
+          "bfroutes",
+        ]
+        "singular" = "blockfrostroute"
+      }
+      "scope" = "Cluster"
+      "versions" = [
+        {
+          "additionalPrinterColumns" = [
+            {
+              "jsonPath" = ".spec.path"
+              "name"     = "Path"
+              "type"     = "string"
+            },
+            {
+              "jsonPath" = ".spec.backend"
+              "name"     = "Backend"
+              "type"     = "string"
+            },
+          ]
+          "name" = "v1alpha1"
+          "schema" = {
+            "openAPIV3Schema" = {
+              "description" = "Auto-generated derived type for BlockfrostRouteSpec via `CustomResource`"
+              "properties" = {
+                "spec" = {
+                  "description" = "Backend serving the requests matching a path, e.g. `/blocks/{hash}`."
+                  "properties" = {
+                    "backend" = {
+                      "enum" = [
+                        "blockfrost",
+                        "dolos",
+                        "submitapi",
+                      ]
+                      "type" = "string"
+                    }
+                    "path" = {
+                      "type" = "string"
+                    }
+                  }
+                  "required" = [
+                    "backend",
+                    "path",
+                  ]
+                  "type" = "object"
+                }
+              }
+              "required" = [
+                "spec",
+              ]
+              "title" = "BlockfrostRoute"
+              "type"  = "object"
+            }
+          }
+          "served"       = true
+          "storage"      = true
+          "subresources" = {}
+        },
+      ]
+    }
+  }
+}
@@ -0,0 +1,4 @@
+variable "namespace" {
+  description = "Namespace of the operator webhook server, which converts the BlockfrostPort versions"
+  type        = string
+}
@@ -6,6 +6,11 @@ variable "api_key_salt" {
   type = string
 }
 
+variable "api_key_hash_secret" {
+  type      = string
+  sensitive = true
+}
+
 variable "extension_subdomain" {
   type = string
 }
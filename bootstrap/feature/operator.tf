@@ -15,7 +15,8 @@ resource "kubernetes_deployment_v1" "operator" {
   }
 
   spec {
-    replicas = 1
+    # Every replica serves the CRD conversion, only the leader reconciles.
+    replicas = 2
 
     selector {
       match_labels = {
@@ -60,6 +61,11 @@ resource "kubernetes_deployment_v1" "operator" {
             value = var.api_key_salt
           }
 
+          env {
+            name  = "API_KEY_HASH_SECRET"
+            value = var.api_key_hash_secret
+          }
+
           env {
             name  = "EXTENSION_SUBDOMAIN"
             value = var.extension_subdomain
@@ -70,6 +76,37 @@ resource "kubernetes_deployment_v1" "operator" {
             value = var.dns_zone
           }
 
+          env {
+            name  = "LEADER_ELECTION"
+            value = "true"
+          }
+
+          env {
+            name  = "LEADER_ELECTION_NAMESPACE"
+            value = var.namespace
+          }
+
+          env {
+            name  = "USAGE_CURSOR_CONFIG_MAP"
+            value = "blockfrost-operator-usage-cursor"
+          }
+
+          env {
+            name  = "USAGE_CURSOR_NAMESPACE"
+            value = var.namespace
+          }
+
+          env {
+            name  = "WEBHOOK_ADDR"
+            value = "0.0.0.0:${local.webhook_port}"
+          }
+
+          volume_mount {
+            name       = "webhook-certs"
+            mount_path = "/certs"
+            read_only  = true
+          }
+
           resources {
             limits = {
               cpu    = var.resources.limits.cpu
@@ -86,6 +123,34 @@ resource "kubernetes_deployment_v1" "operator" {
             container_port = local.port
             protocol       = "TCP"
           }
+
+          port {
+            name           = "webhook"
+            container_port = local.webhook_port
+            protocol       = "TCP"
+          }
+
+          liveness_probe {
+            http_get {
+              path = "/healthz"
+              port = "metrics"
+            }
+          }
+
+          readiness_probe {
+            http_get {
+              path = "/readyz"
+              port = "metrics"
+            }
+            period_seconds = 10
+          }
+        }
+
+        volume {
+          name = "webhook-certs"
+          secret {
+            secret_name = "${local.webhook_certificate}-tls"
+          }
         }
 
         toleration {
@@ -110,3 +175,20 @@ resource "kubernetes_deployment_v1" "operator" {
     }
   }
 }
+
+resource "kubernetes_pod_disruption_budget_v1" "operator" {
+  metadata {
+    namespace = var.namespace
+    name      = local.role
+  }
+
+  spec {
+    min_available = 1
+
+    selector {
+      match_labels = {
+        role = local.role
+      }
+    }
+  }
+}
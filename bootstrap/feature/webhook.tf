@@ -0,0 +1,111 @@
+locals {
+  webhook_name        = "blockfrost-operator"
+  webhook_port        = 9443
+  webhook_certificate = "blockfrost-operator-webhook"
+}
+
+# The API server only calls webhooks over https. The certificate is self-signed, and
+# cert-manager injects it as the caBundle of the CRD conversion and the admission webhook.
+resource "kubernetes_manifest" "webhook_issuer" {
+  manifest = {
+    "apiVersion" = "cert-manager.io/v1"
+    "kind"       = "Issuer"
+    "metadata" = {
+      "name"      = local.webhook_certificate
+      "namespace" = var.namespace
+    }
+    "spec" = {
+      "selfSigned" = {}
+    }
+  }
+}
+
+resource "kubernetes_manifest" "webhook_certificate" {
+  manifest = {
+    "apiVersion" = "cert-manager.io/v1"
+    "kind"       = "Certificate"
+    "metadata" = {
+      "name"      = local.webhook_certificate
+      "namespace" = var.namespace
+    }
+    "spec" = {
+      "dnsNames" = [
+        "${local.webhook_name}.${var.namespace}.svc",
+        "${local.webhook_name}.${var.namespace}.svc.cluster.local",
+      ]
+      "issuerRef" = {
+        "kind" = "Issuer"
+        "name" = local.webhook_certificate
+      }
+      "secretName" = "${local.webhook_certificate}-tls"
+    }
+  }
+}
+
+resource "kubernetes_service_v1" "webhook" {
+  metadata {
+    namespace = var.namespace
+    name      = local.webhook_name
+  }
+
+  spec {
+    selector = {
+      role = local.role
+    }
+
+    port {
+      name        = "webhook"
+      port        = local.webhook_port
+      target_port = "webhook"
+      protocol    = "TCP"
+    }
+
+    type = "ClusterIP"
+  }
+}
+
+# Rejects invalid ports and tiers at apply time, with the caBundle injected by cert-manager.
+resource "kubernetes_manifest" "validating_webhook" {
+  computed_fields = ["metadata.labels", "metadata.annotations", "webhooks[0].clientConfig.caBundle"]
+
+  manifest = {
+    "apiVersion" = "admissionregistration.k8s.io/v1"
+    "kind"       = "ValidatingWebhookConfiguration"
+    "metadata" = {
+      "name" = "${local.webhook_name}-${var.namespace}"
+      "annotations" = {
+        "cert-manager.io/inject-ca-from" = "${var.namespace}/${local.webhook_certificate}"
+      }
+    }
+    "webhooks" = [
+      {
+        "name"                    = "blockfrostports.demeter.run"
+        "admissionReviewVersions" = ["v1"]
+        "sideEffects"             = "None"
+        "failurePolicy"           = "Fail"
+        "rules" = [
+          {
+            "apiGroups"   = ["demeter.run"]
+            "apiVersions" = ["*"]
+            "operations"  = ["CREATE", "UPDATE"]
+            "resources"   = ["blockfrostports", "blockfrosttiers"]
+          },
+        ]
+        "clientConfig" = {
+          "service" = {
+            "name"      = local.webhook_name
+            "namespace" = var.namespace
+            "path"      = "/validate"
+            "port"      = local.webhook_port
+          }
+        }
+      },
+    ]
+  }
+}
+
+# The BlockfrostPort conversion calls the webhook Service and certificate of this namespace.
+module "crds" {
+  source    = "../crds"
+  namespace = var.namespace
+}
@@ -32,6 +32,7 @@ module "blockfrost_v1_feature" {
   extension_subdomain = var.extension_subdomain
   dns_zone            = var.dns_zone
   api_key_salt        = var.api_key_salt
+  api_key_hash_secret = var.api_key_hash_secret
   resources           = var.operator_resources
 }
 
@@ -49,6 +50,7 @@ module "blockfrost_v1_proxy" {
   dns_names            = var.dns_names
   cache_max_size_bytes = var.proxy_cache_max_size_bytes
   routing_routes       = var.proxy_blue_routing_routes
+  api_key_hash_secret  = var.api_key_hash_secret
 }
 
 module "blockfrost_v1_proxy_green" {
@@ -66,6 +68,7 @@ module "blockfrost_v1_proxy_green" {
   dns_names            = var.dns_names
   cache_max_size_bytes = var.proxy_cache_max_size_bytes
   routing_routes       = var.proxy_green_routing_routes
+  api_key_hash_secret  = var.api_key_hash_secret
 }
 
 module "blockfrost_instances" {
@@ -75,6 +75,11 @@ resource "kubernetes_deployment_v1" "blockfrost_proxy" {
             value = var.namespace
           }
 
+          env {
+            name  = "API_KEY_HASH_SECRET"
+            value = var.api_key_hash_secret
+          }
+
           env {
             name  = "PROXY_ADDR"
             value = local.proxy_addr
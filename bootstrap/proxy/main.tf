@@ -10,6 +10,11 @@ locals {
   proxy_labels = var.environment != null ? { role = "${local.role}-${var.environment}" } : { role = local.role }
 }
 
+variable "api_key_hash_secret" {
+  type      = string
+  sensitive = true
+}
+
 variable "name" {
   type    = string
   default = "proxy"
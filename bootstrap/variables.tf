@@ -36,6 +36,12 @@ variable "api_key_salt" {
   type = string
 }
 
+// Key of the hashes published on the port status, shared by the operator and the proxy.
+variable "api_key_hash_secret" {
+  type      = string
+  sensitive = true
+}
+
 variable "operator_resources" {
   type = object({
     limits = object({
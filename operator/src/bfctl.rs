@@ -0,0 +1,184 @@
+use std::{
+    env,
+    error::Error,
+    fs,
+    io::{self, Read},
+    process::ExitCode,
+};
+
+use clap::{Parser, Subcommand};
+use dotenv::dotenv;
+use kube::{api::ListParams, Api, Client, ResourceExt};
+use operator::{
+    build_api_key_with_salt, build_named_api_key_with_salt, get_config, hash_api_key,
+    parse_api_key, parse_port, validate_manifest, BlockfrostPort, CONDITION_READY,
+};
+use serde_json::Value;
+
+/// Inspects and debugs Blockfrost ports.
+#[derive(Parser)]
+#[command(name = "bfctl")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Lists the ports of the cluster with their network and tier.
+    Ports {
+        /// Namespace of the ports, every namespace when not set.
+        #[arg(short, long)]
+        namespace: Option<String>,
+    },
+    /// Derives the key of a port manifest offline, with the current operator salt.
+    Key {
+        /// Port manifest, `-` to read it from stdin.
+        file: String,
+        /// Named key to derive instead of the port key.
+        #[arg(long)]
+        name: Option<String>,
+        /// Salt to derive the key with instead of the current one, e.g. a previous salt.
+        #[arg(long)]
+        salt: Option<String>,
+    },
+    /// Validates a manifest against the CRD schema and the operator validation.
+    Validate {
+        /// Manifest, `-` to read it from stdin.
+        file: String,
+    },
+    /// Shows the version and network of a key.
+    Decode { key: String },
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode, Box<dyn Error>> {
+    dotenv().ok();
+
+    // Keys and validation only use the salt, versions, networks and tiers of the config.
+    for (name, value) in [("METRICS_DELAY", "40"), ("PROMETHEUS_URL", "")] {
+        if env::var(name).is_err() {
+            env::set_var(name, value);
+        }
+    }
+
+    match Cli::parse().command {
+        Command::Ports { namespace } => list_ports(namespace).await,
+        Command::Key { file, name, salt } => derive_key(&file, name, salt).await,
+        Command::Validate { file } => validate(&file),
+        Command::Decode { key } => decode(&key),
+    }
+}
+
+async fn list_ports(namespace: Option<String>) -> Result<ExitCode, Box<dyn Error>> {
+    let client = Client::try_default().await?;
+    let api: Api<BlockfrostPort> = match &namespace {
+        Some(namespace) => Api::namespaced(client, namespace),
+        None => Api::all(client),
+    };
+
+    let mut rows =
+        vec![["NAMESPACE", "NAME", "NETWORK", "TIER", "READY", "SUSPENDED"].map(String::from)];
+    for port in api.list(&ListParams::default()).await? {
+        let ready = port
+            .status
+            .iter()
+            .flat_map(|status| status.conditions.iter())
+            .find(|condition| condition.r#type == CONDITION_READY)
+            .map_or("-".to_string(), |condition| condition.status.clone());
+
+        rows.push([
+            port.namespace().unwrap_or_default(),
+            port.name_any(),
+            port.spec.network.clone(),
+            port.spec.throughput_tier.clone(),
+            ready,
+            port.spec.suspended.unwrap_or_default().to_string(),
+        ]);
+    }
+
+    let widths: Vec<usize> = (0..6)
+        .map(|i| {
+            rows.iter()
+                .map(|row| row[i].len())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    for row in rows {
+        let columns: Vec<String> = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(column, width)| format!("{column:width$}"))
+            .collect();
+        println!("{}", columns.join("  ").trim_end());
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn derive_key(
+    file: &str,
+    name: Option<String>,
+    salt: Option<String>,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let port = parse_port(&read_manifest(file)?)?;
+    if port.namespace().is_none() {
+        return Err("the port manifest needs metadata.namespace".into());
+    }
+
+    let salt = salt.unwrap_or(get_config().api_key_salt.clone());
+    let key = match name {
+        Some(name) => build_named_api_key_with_salt(&port, &name, &salt).await?,
+        None => build_api_key_with_salt(&port, &salt).await?,
+    };
+    println!("key: {key}");
+    println!(
+        "hash: {}",
+        hash_api_key(&key, &get_config().api_key_hash_secret)
+    );
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn validate(file: &str) -> Result<ExitCode, Box<dyn Error>> {
+    let errors = validate_manifest(&read_manifest(file)?, get_config())?;
+    if errors.is_empty() {
+        println!("valid");
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    for error in errors {
+        println!("{error}");
+    }
+    Ok(ExitCode::FAILURE)
+}
+
+fn decode(key: &str) -> Result<ExitCode, Box<dyn Error>> {
+    let Some(prefix) = parse_api_key(key) else {
+        eprintln!("not a bech32 key with the dmtr_blockfrost_{{version}}_{{network}}_ prefix");
+        return Ok(ExitCode::FAILURE);
+    };
+
+    println!("version: {}", prefix.version);
+    println!("network: {}", prefix.network);
+    println!(
+        "hash: {}",
+        hash_api_key(key, &get_config().api_key_hash_secret)
+    );
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Reads a YAML or JSON manifest from a file or stdin.
+fn read_manifest(file: &str) -> Result<Value, Box<dyn Error>> {
+    let content = if file == "-" {
+        let mut content = String::new();
+        io::stdin().read_to_string(&mut content)?;
+        content
+    } else {
+        fs::read_to_string(file)?
+    };
+
+    Ok(serde_yaml::from_str(&content)?)
+}
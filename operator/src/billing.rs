@@ -0,0 +1,354 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fs::OpenOptions, io::Write, time::Duration};
+use tracing::{error, warn};
+
+use crate::{Config, Error};
+
+/// Usage counted for a port in a collection window.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageEvent {
+    /// Same for the same port and window, so consumers can drop duplicates.
+    pub id: String,
+    pub project: String,
+    pub resource_name: String,
+    pub tier: String,
+    pub network: String,
+    pub window_start: String,
+    pub window_end: String,
+    pub count: u64,
+    /// Requests weighted by the usage policy, the count when events predate credits.
+    #[serde(default)]
+    pub credits: u64,
+}
+
+impl UsageEvent {
+    pub fn new(
+        project: &str,
+        resource_name: &str,
+        tier: &str,
+        network: &str,
+        window_start: DateTime<Utc>,
+        window_end: DateTime<Utc>,
+        count: u64,
+    ) -> Self {
+        let window_start = window_start.to_rfc3339_opts(SecondsFormat::Secs, true);
+        let window_end = window_end.to_rfc3339_opts(SecondsFormat::Secs, true);
+        let id = hex::encode(Sha256::digest(format!(
+            "{project}.{resource_name}:{tier}:{network}:{window_start}:{window_end}"
+        )));
+
+        Self {
+            id,
+            project: project.into(),
+            resource_name: resource_name.into(),
+            tier: tier.into(),
+            network: network.into(),
+            window_start,
+            window_end,
+            count,
+            credits: count,
+        }
+    }
+
+    pub fn with_credits(self, credits: u64) -> Self {
+        Self { credits, ..self }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UsageEventBatch {
+    pub events: Vec<UsageEvent>,
+}
+
+/// Destination of the usage events, configured with the USAGE_EVENTS_* env.
+#[derive(Clone, Debug)]
+pub enum UsageSink {
+    /// Appends one JSON event per line.
+    File { path: String },
+    /// Posts each batch with an `Idempotency-Key` header, retrying with backoff
+    /// on connection errors, 429 and 5xx responses.
+    Webhook {
+        url: String,
+        retries: u32,
+        backoff: Duration,
+    },
+}
+
+impl UsageSink {
+    pub fn from_config(config: &Config) -> Vec<Self> {
+        let mut sinks = vec![];
+        if let Some(path) = &config.usage_events_file {
+            sinks.push(UsageSink::File { path: path.clone() });
+        }
+        if let Some(url) = &config.usage_events_webhook_url {
+            sinks.push(UsageSink::Webhook {
+                url: url.clone(),
+                retries: config.usage_events_webhook_retries,
+                backoff: Duration::from_millis(500),
+            });
+        }
+        sinks
+    }
+
+    pub async fn publish(
+        &self,
+        client: &reqwest::Client,
+        events: &[UsageEvent],
+    ) -> Result<(), Error> {
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        match self {
+            UsageSink::File { path } => write_events(path, events),
+            UsageSink::Webhook {
+                url,
+                retries,
+                backoff,
+            } => post_events(client, url, *retries, *backoff, events).await,
+        }
+    }
+}
+
+fn write_events(path: &str, events: &[UsageEvent]) -> Result<(), Error> {
+    let mut lines = String::new();
+    for event in events {
+        lines.push_str(&serde_json::to_string(event)?);
+        lines.push('\n');
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(lines.as_bytes()))
+        .map_err(|err| Error::UsageSinkError(format!("{path}: {err}")))
+}
+
+fn batch_idempotency_key(events: &[UsageEvent]) -> String {
+    let mut hasher = Sha256::new();
+    for event in events {
+        hasher.update(&event.id);
+    }
+    hex::encode(hasher.finalize())
+}
+
+async fn post_events(
+    client: &reqwest::Client,
+    url: &str,
+    retries: u32,
+    backoff: Duration,
+    events: &[UsageEvent],
+) -> Result<(), Error> {
+    let idempotency_key = batch_idempotency_key(events);
+    let batch = UsageEventBatch {
+        events: events.to_vec(),
+    };
+
+    let mut attempt = 0;
+    loop {
+        let result = client
+            .post(url)
+            .header("Idempotency-Key", &idempotency_key)
+            .json(&batch)
+            .send()
+            .await;
+
+        let error = match result {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => {
+                let status = response.status();
+                let error = Error::UsageSinkError(format!("{url}: status {status}"));
+                if !(status.is_server_error() || status.as_u16() == 429) {
+                    return Err(error);
+                }
+                error
+            }
+            Err(err) => Error::UsageSinkError(format!("{url}: {err}")),
+        };
+
+        if attempt >= retries {
+            return Err(error);
+        }
+        warn!(error = error.to_string(), attempt, "retrying usage events");
+        tokio::time::sleep(backoff * 2u32.pow(attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// Publishes the events to every configured sink, even after a failure, returning the
+/// last error so the window is not marked as collected.
+pub async fn publish_usage_events(
+    client: &reqwest::Client,
+    config: &Config,
+    events: &[UsageEvent],
+) -> Result<(), Error> {
+    let mut result = Ok(());
+    for sink in UsageSink::from_config(config) {
+        if let Err(err) = sink.publish(client, events).await {
+            error!(error = err.to_string(), "error to publish usage events");
+            result = Err(err);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env, fs,
+        sync::{Arc, Mutex},
+    };
+
+    use http_body_util::{BodyExt, Full};
+    use hyper::{
+        body::{Bytes, Incoming},
+        server::conn::http1,
+        service::service_fn,
+        Request, Response, StatusCode,
+    };
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    type Received = Arc<Mutex<Vec<(String, UsageEventBatch)>>>;
+
+    /// Answers with `statuses` in order, then 200, recording each request.
+    async fn mock_webhook(statuses: Vec<StatusCode>) -> (String, Received) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received: Received = Arc::default();
+        let statuses = Arc::new(Mutex::new(statuses));
+
+        let requests = received.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let requests = requests.clone();
+                let statuses = statuses.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let requests = requests.clone();
+                    let statuses = statuses.clone();
+                    async move {
+                        let key = req.headers()["idempotency-key"]
+                            .to_str()
+                            .unwrap()
+                            .to_string();
+                        let body = req.into_body().collect().await?.to_bytes();
+                        let batch = serde_json::from_slice(&body).unwrap();
+                        requests.lock().unwrap().push((key, batch));
+
+                        let mut statuses = statuses.lock().unwrap();
+                        let status = if statuses.is_empty() {
+                            StatusCode::OK
+                        } else {
+                            statuses.remove(0)
+                        };
+                        Ok::<_, hyper::Error>(
+                            Response::builder()
+                                .status(status)
+                                .body(Full::new(Bytes::new()))
+                                .unwrap(),
+                        )
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        (format!("http://{addr}/usage"), received)
+    }
+
+    fn events() -> Vec<UsageEvent> {
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let end = DateTime::parse_from_rfc3339("2024-01-01T01:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        vec![
+            UsageEvent::new(
+                "mainnet-test",
+                "port-a123ds",
+                "0",
+                "mainnet",
+                start,
+                end,
+                10,
+            ),
+            UsageEvent::new("mainnet-test", "port-b456ef", "1", "mainnet", start, end, 5),
+        ]
+    }
+
+    fn webhook(url: String, retries: u32) -> UsageSink {
+        UsageSink::Webhook {
+            url,
+            retries,
+            backoff: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn test_usage_event_id() {
+        let first = events();
+        assert_eq!(first[0].id, events()[0].id);
+        assert_ne!(first[0].id, first[1].id);
+        assert_eq!(first[0].window_start, "2024-01-01T00:00:00Z");
+    }
+
+    #[tokio::test]
+    async fn test_file_sink() {
+        let path = env::temp_dir().join("blockfrost-usage-events.jsonl");
+        let _ = fs::remove_file(&path);
+        let sink = UsageSink::File {
+            path: path.to_string_lossy().into(),
+        };
+
+        let client = reqwest::Client::new();
+        sink.publish(&client, &events()).await.unwrap();
+        sink.publish(&client, &events()[..1]).await.unwrap();
+
+        let lines: Vec<UsageEvent> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[2], events()[0]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_webhook_sink_retries() {
+        let (url, received) = mock_webhook(vec![
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::TOO_MANY_REQUESTS,
+        ])
+        .await;
+
+        let client = reqwest::Client::new();
+        webhook(url, 3).publish(&client, &events()).await.unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        assert!(received.iter().all(|(key, _)| *key == received[0].0));
+        assert_eq!(received[2].1.events, events());
+    }
+
+    #[tokio::test]
+    async fn test_webhook_sink_failures() {
+        let client = reqwest::Client::new();
+
+        let (url, received) = mock_webhook(vec![StatusCode::BAD_REQUEST]).await;
+        assert!(webhook(url, 3).publish(&client, &events()).await.is_err());
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        let (url, received) = mock_webhook(vec![StatusCode::BAD_GATEWAY; 3]).await;
+        assert!(webhook(url, 2).publish(&client, &events()).await.is_err());
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+}
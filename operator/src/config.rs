@@ -1,6 +1,8 @@
 use lazy_static::lazy_static;
 use std::{env, time::Duration};
 
+use crate::UsagePolicy;
+
 lazy_static! {
     static ref CONTROLLER_CONFIG: Config = Config::from_env();
 }
@@ -13,18 +15,72 @@ pub fn get_config() -> &'static Config {
 pub struct Config {
     pub dns_zone: String,
     pub extension_subdomain: String,
+
+    // Key salts, new keys use the current one. Ports issued with a previous salt keep it until
+    // the migration moves them to the current one, at most this many per hour.
     pub api_key_salt: String,
+    pub api_key_previous_salts: Vec<String>,
+    pub api_key_salt_migration: bool,
+    pub api_key_salt_migrations_per_hour: u32,
+    // Key of the hashes published on the port status, shared with the proxy.
+    pub api_key_hash_secret: String,
+
     pub metrics_delay: Duration,
     pub prometheus_url: String,
     pub default_blockfrost_version: String,
+    pub networks: Vec<String>,
+    pub tiers: Vec<String>,
+    pub key_rotation_grace_period: Duration,
+    pub blockfrost_versions: Vec<String>,
+
+    // ConfigMap of the usage collector cursor, shared by the replicas so a new leader resumes
+    // from it. The cursor is kept in memory only when the name is not set.
+    pub usage_cursor_config_map: Option<String>,
+    pub usage_cursor_namespace: String,
+    pub usage_max_window: Duration,
+    pub usage_max_backfill: Duration,
+    pub usage_max_window_retries: u32,
+
+    // Billable status codes and endpoint class weights, read from USAGE_POLICY_PATH.
+    pub usage_policy: UsagePolicy,
+
+    // Usage event sinks, each one enabled when set.
+    pub usage_events_file: Option<String>,
+    pub usage_events_webhook_url: Option<String>,
+    pub usage_events_webhook_retries: u32,
+
+    // Lease based leader election, so only one replica reconciles and collects usage.
+    pub leader_election: bool,
+    pub leader_election_namespace: String,
+    pub leader_election_lease_name: String,
+    pub leader_election_lease_duration: Duration,
+
+    // Admission webhook, only served when the address is set.
+    pub webhook_addr: Option<String>,
+    pub webhook_cert_path: String,
+    pub webhook_key_path: String,
+
+    // Serves the last reconcile result of each port on /debug/ports.
+    pub debug_endpoint: bool,
 }
 
 impl Config {
     pub fn from_env() -> Self {
+        let (api_key_salt, api_key_previous_salts) = salts_from_env();
+
         Self {
             dns_zone: env::var("DNS_ZONE").unwrap_or("demeter.run".into()),
             extension_subdomain: env::var("EXTENSION_SUBDOMAIN").unwrap_or("blockfrost-m1".into()),
-            api_key_salt: env::var("API_KEY_SALT").unwrap_or("blockfrost-salt".into()),
+            api_key_salt,
+            api_key_previous_salts,
+            api_key_salt_migration: env::var("API_KEY_SALT_MIGRATION")
+                .is_ok_and(|value| value == "true"),
+            api_key_salt_migrations_per_hour: env::var("API_KEY_SALT_MIGRATIONS_PER_HOUR")
+                .unwrap_or("100".into())
+                .parse::<u32>()
+                .expect("API_KEY_SALT_MIGRATIONS_PER_HOUR must be a number"),
+            api_key_hash_secret: env::var("API_KEY_HASH_SECRET")
+                .expect("API_KEY_HASH_SECRET must be set"),
             metrics_delay: Duration::from_secs(
                 std::env::var("METRICS_DELAY")
                     .expect("METRICS_DELAY must be set")
@@ -34,6 +90,129 @@ impl Config {
             prometheus_url: env::var("PROMETHEUS_URL").expect("PROMETHEUS_URL must be set"),
             default_blockfrost_version: env::var("default_blockfrost_version")
                 .unwrap_or("v1".into()),
+            networks: list_from_env(
+                "NETWORKS",
+                "mainnet,preprod,preview,cardano-mainnet,cardano-preprod,cardano-preview",
+            ),
+            tiers: list_from_env("TIERS", "0,1,2,3"),
+            key_rotation_grace_period: duration_from_env("KEY_ROTATION_GRACE_PERIOD", 86400),
+            blockfrost_versions: list_from_env("BLOCKFROST_VERSIONS", "v1"),
+            usage_cursor_config_map: env::var("USAGE_CURSOR_CONFIG_MAP").ok(),
+            usage_cursor_namespace: env::var("USAGE_CURSOR_NAMESPACE").unwrap_or("default".into()),
+            usage_max_window: duration_from_env("USAGE_MAX_WINDOW", 3600),
+            usage_max_backfill: duration_from_env("USAGE_MAX_BACKFILL", 86400),
+            usage_max_window_retries: env::var("USAGE_MAX_WINDOW_RETRIES")
+                .unwrap_or("5".into())
+                .parse::<u32>()
+                .expect("USAGE_MAX_WINDOW_RETRIES must be a number"),
+            usage_policy: env::var("USAGE_POLICY_PATH")
+                .map(|path| UsagePolicy::load(&path).unwrap_or_else(|err| panic!("{err}")))
+                .unwrap_or_default(),
+            usage_events_file: env::var("USAGE_EVENTS_FILE").ok(),
+            usage_events_webhook_url: env::var("USAGE_EVENTS_WEBHOOK_URL").ok(),
+            usage_events_webhook_retries: env::var("USAGE_EVENTS_WEBHOOK_RETRIES")
+                .unwrap_or("3".into())
+                .parse::<u32>()
+                .expect("USAGE_EVENTS_WEBHOOK_RETRIES must be a number"),
+            leader_election: env::var("LEADER_ELECTION").is_ok_and(|value| value == "true"),
+            leader_election_namespace: env::var("LEADER_ELECTION_NAMESPACE")
+                .unwrap_or("default".into()),
+            leader_election_lease_name: env::var("LEADER_ELECTION_LEASE_NAME")
+                .unwrap_or("blockfrost-operator".into()),
+            leader_election_lease_duration: duration_from_env("LEADER_ELECTION_LEASE_DURATION", 15),
+            webhook_addr: env::var("WEBHOOK_ADDR").ok(),
+            webhook_cert_path: env::var("WEBHOOK_CERT_PATH").unwrap_or("/certs/tls.crt".into()),
+            webhook_key_path: env::var("WEBHOOK_KEY_PATH").unwrap_or("/certs/tls.key".into()),
+            debug_endpoint: env::var("DEBUG_ENDPOINT").is_ok_and(|value| value == "true"),
         }
     }
 }
+
+impl Config {
+    /// Salts a port key may have been issued with, the current one first.
+    pub fn api_key_salts(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.api_key_salt).chain(self.api_key_previous_salts.iter())
+    }
+}
+
+/// API_KEY_SALTS lists every salt in use, the current one marked with `*`, e.g.
+/// `*salt-2025,salt-2024`. Without it API_KEY_SALT is the only salt.
+fn salts_from_env() -> (String, Vec<String>) {
+    match env::var("API_KEY_SALTS") {
+        Ok(value) => {
+            parse_salts(&value).expect("API_KEY_SALTS must mark exactly one current salt with *")
+        }
+        Err(_) => (
+            env::var("API_KEY_SALT").unwrap_or("blockfrost-salt".into()),
+            vec![],
+        ),
+    }
+}
+
+/// Current and previous salts of a comma separated list. A single salt is the current one.
+pub fn parse_salts(value: &str) -> Option<(String, Vec<String>)> {
+    let salts: Vec<&str> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|salt| !salt.is_empty())
+        .collect();
+
+    if let [salt] = salts[..] {
+        return Some((salt.trim_start_matches('*').to_string(), vec![]));
+    }
+
+    let mut current = salts.iter().filter_map(|salt| salt.strip_prefix('*'));
+    let salt = current.next()?;
+    if current.next().is_some() {
+        return None;
+    }
+
+    let previous = salts
+        .iter()
+        .filter(|salt| !salt.starts_with('*'))
+        .map(|salt| salt.to_string())
+        .collect();
+    Some((salt.to_string(), previous))
+}
+
+fn list_from_env(name: &str, default: &str) -> Vec<String> {
+    env::var(name)
+        .unwrap_or(default.into())
+        .split(',')
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+fn duration_from_env(name: &str, default: u64) -> Duration {
+    Duration::from_secs(
+        env::var(name)
+            .map(|value| {
+                value
+                    .parse::<u64>()
+                    .unwrap_or_else(|_| panic!("{name} must be a number in seconds"))
+            })
+            .unwrap_or(default),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_salts() {
+        assert_eq!(parse_salts("salt"), Some(("salt".into(), vec![])));
+        assert_eq!(parse_salts("*salt"), Some(("salt".into(), vec![])));
+        assert_eq!(
+            parse_salts("salt-2023, *salt-2025,salt-2024"),
+            Some((
+                "salt-2025".into(),
+                vec!["salt-2023".into(), "salt-2024".into()]
+            ))
+        );
+        assert_eq!(parse_salts("salt-2024,salt-2025"), None);
+        assert_eq!(parse_salts("*salt-2024,*salt-2025"), None);
+        assert_eq!(parse_salts(""), None);
+    }
+}
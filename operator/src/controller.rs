@@ -1,28 +1,160 @@
+use chrono::{DateTime, Utc};
 use futures::StreamExt;
+use k8s_openapi::api::core::v1::Secret;
 use kube::{
-    runtime::{controller::Action, watcher::Config as WatcherConfig, Controller},
-    Api, Client, CustomResource, CustomResourceExt, ResourceExt,
+    api::DeleteParams,
+    runtime::{
+        controller::Action,
+        events::{Event, EventType, Recorder, Reporter},
+        finalizer::{finalizer, Event as Finalizer},
+        reflector::{self, Store},
+        watcher,
+        watcher::Config as WatcherConfig,
+        Controller, WatchStreamExt,
+    },
+    Api, Client, CustomResource, CustomResourceExt, Resource, ResourceExt,
 };
+use lazy_static::lazy_static;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::Duration};
-use tracing::{error, info, instrument};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::{Hash, Hasher},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tracing::{error, info, instrument, warn};
 
-use crate::{build_api_key, build_hostname, patch_resource_status, Error, Metrics, Result, State};
+use crate::{
+    apply_auth_secret, build_api_key_with_salt, build_hostname, build_named_api_key_with_salt,
+    build_secret_name, claimed_custom_domains, clear_spec_auth_token, flush_port_usage, get_config,
+    get_fixed_auth_token, grandfather_auth_token, hash_api_key, is_api_key_hash,
+    is_legacy_auth_token, leader::Shutdown, legacy_hash_api_key, patch_resource_status, salt_id,
+    validate_spec_with_tiers, Error, Result, State, ValidationError, CONDITION_READY,
+    ERROR_CONDITIONS, FIXED_AUTH_TOKEN_KEY,
+};
 
 pub static BLOCKFROST_PORT_FINALIZER: &str = "blockfrostports.demeter.run";
 
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconcileResult {
+    pub reconciled_at: String,
+    pub generation: Option<i64>,
+    pub error: Option<String>,
+}
+
 struct Context {
     pub client: Client,
-    pub metrics: Metrics,
+    pub state: Arc<State>,
+    pub reporter: Reporter,
+    // Every port, to find the custom domains claimed by other ports.
+    pub ports: Store<BlockfrostPort>,
 }
 impl Context {
-    pub fn new(client: Client, metrics: Metrics) -> Self {
-        Self { client, metrics }
+    pub fn new(client: Client, state: Arc<State>, ports: Store<BlockfrostPort>) -> Self {
+        let reporter = Reporter {
+            controller: "blockfrost-operator".into(),
+            instance: std::env::var("HOSTNAME").ok(),
+        };
+        Self {
+            client,
+            state,
+            reporter,
+            ports,
+        }
+    }
+
+    fn recorder(&self, crd: &BlockfrostPort) -> Recorder {
+        Recorder::new(
+            self.client.clone(),
+            self.reporter.clone(),
+            crd.object_ref(&()),
+        )
+    }
+
+    /// Publishes an event on the port. Failures are only logged, events are informative.
+    async fn publish_event(&self, crd: &BlockfrostPort, event: Event) {
+        if let Err(err) = self.recorder(crd).publish(event).await {
+            warn!(
+                resource = crd.name_any(),
+                error = err.to_string(),
+                "Failed to publish event"
+            );
+        }
+    }
+}
+
+fn build_event(type_: EventType, reason: &str, action: &str, note: String) -> Event {
+    Event {
+        type_,
+        reason: reason.into(),
+        note: Some(note),
+        action: action.into(),
+        secondary: None,
     }
 }
 
-#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+/// Events describing what a reconcile changed on the port status.
+pub fn build_status_events(
+    previous: Option<&BlockfrostPortStatus>,
+    status: &BlockfrostPortStatus,
+) -> Vec<Event> {
+    let mut events = vec![];
+
+    let previous_hash = previous
+        .map(|previous| previous.auth_token_hash.as_str())
+        .unwrap_or_default();
+    if previous_hash.is_empty() {
+        events.push(build_event(
+            EventType::Normal,
+            "KeyGenerated",
+            "GenerateKey",
+            "Generated the port key".into(),
+        ));
+    } else if previous_hash != status.auth_token_hash {
+        events.push(build_event(
+            EventType::Normal,
+            "KeyRotated",
+            "GenerateKey",
+            "Rotated the port key, the previous key is accepted during the grace period".into(),
+        ));
+    }
+
+    for key in status.keys.iter() {
+        let is_new = previous
+            .map(|previous| {
+                previous
+                    .keys
+                    .iter()
+                    .all(|k| k.auth_token_hash != key.auth_token_hash)
+            })
+            .unwrap_or(true);
+        if is_new {
+            events.push(build_event(
+                EventType::Normal,
+                "KeyGenerated",
+                "GenerateKey",
+                format!("Generated the key {}", key.name),
+            ));
+        }
+    }
+
+    let previous_generation = previous.and_then(|previous| previous.observed_generation);
+    let previous_ready = previous.is_some_and(|previous| !previous.endpoint_url.is_empty());
+    if previous_generation != status.observed_generation || !previous_ready {
+        events.push(build_event(
+            EventType::Normal,
+            "StatusUpdated",
+            "UpdateStatus",
+            format!("Port is ready at {}", status.endpoint_url),
+        ));
+    }
+
+    events
+}
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
 #[kube(
     kind = "BlockfrostPort",
     group = "demeter.run",
@@ -36,8 +168,9 @@ impl Context {
         {"name": "Network", "jsonPath": ".spec.network", "type": "string"},
         {"name": "Throughput Tier", "jsonPath":".spec.throughputTier", "type": "string"}, 
         {"name": "Endpoint URL", "jsonPath": ".status.endpointUrl", "type": "string"},
-        {"name": "Authenticated Endpoint URL", "jsonPath": ".status.authenticatedEndpointUrl", "type": "string"},
-        {"name": "Auth Token", "jsonPath": ".status.authToken", "type": "string"}
+        {"name": "Auth Secret", "jsonPath": ".status.secretName", "type": "string"},
+        {"name": "Ready", "jsonPath": ".status.conditions[?(@.type==\"Ready\")].status", "type": "string"},
+        {"name": "Suspended", "jsonPath": ".spec.suspended", "type": "boolean"}
     "#)]
 #[serde(rename_all = "camelCase")]
 pub struct BlockfrostPortSpec {
@@ -46,54 +179,653 @@ pub struct BlockfrostPortSpec {
     pub throughput_tier: String,
     pub blockfrost_version: Option<String>,
     pub auth_token: Option<String>,
+    /// Bump to mint a new key. The previous key is accepted during the grace period.
+    pub rotation: Option<u32>,
+    /// Additional named keys sharing the port tier limits.
+    pub keys: Option<Vec<BlockfrostPortKey>>,
+    /// Hostnames serving the port besides the extension hostname, authenticated as the port key.
+    pub custom_domains: Option<Vec<String>>,
+    /// Origins allowed to call the port from a browser, e.g. `https://app.example.com`,
+    /// or `*` for any origin.
+    pub allowed_origins: Option<Vec<String>>,
+    /// Client addresses allowed to use the port, as CIDRs or single addresses.
+    pub allowed_ips: Option<Vec<String>>,
+    /// Endpoints the port can call, besides the tier policy.
+    pub endpoints: Option<BlockfrostEndpointPolicy>,
+    /// Rejects the port requests without deleting it, so its keys are kept.
+    pub suspended: Option<bool>,
+    /// Reason returned to the consumer while the port is suspended.
+    pub suspension_reason: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockfrostPortKey {
+    pub name: String,
+    pub expires_at: Option<String>,
+    /// Bump to mint a new key under the same name. The previous key is accepted during the
+    /// grace period.
+    pub rotation: Option<u32>,
+}
+
+/// Endpoint restrictions of a port or a tier. Patterns are regexes matched against the
+/// request path, e.g. `^/tx/submit$`.
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockfrostEndpointPolicy {
+    /// Only paths matching one of these patterns are allowed, when set.
+    pub allow: Option<Vec<String>>,
+    /// Paths matching one of these patterns are denied.
+    pub deny: Option<Vec<String>>,
+    /// Only GET, HEAD and OPTIONS requests are allowed.
+    #[serde(alias = "read_only")]
+    pub read_only: Option<bool>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct BlockfrostPortStatus {
+    // Ports that never passed validation only have conditions on their status.
+    #[serde(default)]
     pub endpoint_url: String,
-    pub authenticated_endpoint_url: Option<String>,
-    pub auth_token: String,
+    /// Secret holding the plaintext keys and the authenticated endpoint urls.
+    pub secret_name: Option<String>,
+    #[serde(default)]
+    pub auth_token_hash: String,
+    pub previous_auth_token_hash: Option<String>,
+    pub previous_auth_token_expires_at: Option<String>,
+    #[serde(default)]
+    pub keys: Vec<BlockfrostPortKeyStatus>,
+    /// Replaced named keys, accepted until they expire like the previous port key.
+    #[serde(default)]
+    pub previous_keys: Vec<BlockfrostPortKeyStatus>,
+    /// Fingerprint of the salt the keys were issued with.
+    pub key_salt: Option<String>,
+    /// The port key is a malformed token migrated from `spec.authToken`, which the proxy only
+    /// looks up when some port has one.
+    #[serde(default)]
+    pub legacy_auth_token: bool,
+    #[serde(default)]
+    pub custom_domains: Vec<String>,
+    pub observed_generation: Option<i64>,
+    #[serde(default)]
+    pub conditions: Vec<BlockfrostPortCondition>,
+    /// Requests counted in the current quota period, published by the usage collector. The
+    /// reconciler never writes it, so a stale object can't overwrite newer usage.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<BlockfrostPortUsage>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockfrostPortUsage {
+    /// Calendar month in UTC, e.g. 2024-01.
+    pub period: String,
+    pub requests: u64,
+    pub updated_at: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockfrostPortKeyStatus {
+    pub name: String,
+    pub auth_token_hash: String,
+    pub expires_at: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockfrostPortCondition {
+    pub r#type: String,
+    pub status: String,
+    pub reason: Option<String>,
+    pub message: Option<String>,
+    pub last_transition_time: Option<String>,
+}
+
+fn build_condition(
+    previous: &[BlockfrostPortCondition],
+    r#type: &str,
+    error: Option<&ValidationError>,
+    true_on_error: bool,
+) -> BlockfrostPortCondition {
+    let has_error = error.is_some();
+    let status = if has_error == true_on_error {
+        "True"
+    } else {
+        "False"
+    };
+
+    // Keep the transition time while the condition status doesn't change.
+    let last_transition_time = previous
+        .iter()
+        .find(|c| c.r#type == r#type && c.status == status)
+        .and_then(|c| c.last_transition_time.clone())
+        .unwrap_or(Utc::now().to_rfc3339());
+
+    BlockfrostPortCondition {
+        r#type: r#type.into(),
+        status: status.into(),
+        reason: error.map(|e| e.condition_type().to_string()),
+        message: error.map(|e| e.to_string()),
+        last_transition_time: Some(last_transition_time),
+    }
+}
+
+pub fn build_conditions(
+    previous: &[BlockfrostPortCondition],
+    errors: &[ValidationError],
+) -> Vec<BlockfrostPortCondition> {
+    let mut conditions = vec![build_condition(
+        previous,
+        CONDITION_READY,
+        errors.first(),
+        false,
+    )];
+
+    for r#type in ERROR_CONDITIONS {
+        let error = errors.iter().find(|e| e.condition_type() == r#type);
+        conditions.push(build_condition(previous, r#type, error, true));
+    }
+
+    conditions
+}
+
+/// Keeps the replaced key hash, and how long it is still accepted, when the port key changes.
+/// Status with the legacy hashes of the current keys replaced by their keyed hash, so moving
+/// a port to keyed hashes isn't taken for a key rotation.
+pub fn with_keyed_hashes(
+    status: &BlockfrostPortStatus,
+    rehashed: &BTreeMap<String, String>,
+) -> BlockfrostPortStatus {
+    let mut status = status.clone();
+    let rehash = |hash: &mut String| {
+        if let Some(keyed) = rehashed.get(hash.as_str()) {
+            hash.clone_from(keyed);
+        }
+    };
+    rehash(&mut status.auth_token_hash);
+    status
+        .keys
+        .iter_mut()
+        .for_each(|key| rehash(&mut key.auth_token_hash));
+    status
+}
+
+pub fn build_previous_key(
+    status: Option<&BlockfrostPortStatus>,
+    key_hash: &str,
+    now: DateTime<Utc>,
+    grace_period: Duration,
+) -> Option<(String, DateTime<Utc>)> {
+    let status = status?;
+
+    if !status.auth_token_hash.is_empty() && status.auth_token_hash != key_hash {
+        let grace_period = chrono::Duration::from_std(grace_period).unwrap_or_default();
+        return Some((status.auth_token_hash.clone(), now + grace_period));
+    }
+
+    let previous_key = status.previous_auth_token_hash.clone()?;
+    let expires_at = parse_time(&status.previous_auth_token_expires_at)?;
+
+    (previous_key != key_hash && expires_at > now).then_some((previous_key, expires_at))
+}
+
+/// Keeps the replaced hash of a named key that is still configured but derives another key,
+/// e.g. after a rotation bump or a salt migration, until the rotation grace period or the key
+/// itself expires. Removed and renamed keys are revoked at once.
+pub fn build_previous_keys(
+    status: Option<&BlockfrostPortStatus>,
+    keys: &[BlockfrostPortKeyStatus],
+    now: DateTime<Utc>,
+    grace_period: Duration,
+) -> Vec<BlockfrostPortKeyStatus> {
+    let Some(status) = status else {
+        return vec![];
+    };
+
+    let grace_period = chrono::Duration::from_std(grace_period).unwrap_or_default();
+    let replaced = status.keys.iter().map(|key| {
+        let expires_at = parse_time(&key.expires_at).map_or(now + grace_period, |expires_at| {
+            expires_at.min(now + grace_period)
+        });
+        BlockfrostPortKeyStatus {
+            expires_at: Some(expires_at.to_rfc3339()),
+            ..key.clone()
+        }
+    });
+
+    replaced
+        .chain(status.previous_keys.iter().cloned())
+        .filter(|previous| {
+            parse_time(&previous.expires_at).is_some_and(|expires_at| expires_at > now)
+                && keys.iter().any(|key| key.name == previous.name)
+                && !keys
+                    .iter()
+                    .any(|key| key.auth_token_hash == previous.auth_token_hash)
+        })
+        .collect()
+}
+
+fn parse_time(value: &Option<String>) -> Option<DateTime<Utc>> {
+    value
+        .as_ref()
+        .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+        .map(|v| v.with_timezone(&Utc))
+}
+
+lazy_static! {
+    /// Fingerprints of the configured salts, derived once.
+    static ref SALT_IDS: Vec<(String, &'static String)> = get_config()
+        .api_key_salts()
+        .map(|salt| (salt_id(salt), salt))
+        .collect();
+}
+
+/// Salt the port keys were issued with. Ports keep it until they are migrated, so a new
+/// current salt doesn't change their keys. Rotated and new ports don't match any salt and
+/// use the current one.
+async fn issued_salt(crd: &BlockfrostPort, fixed: bool) -> Result<Option<&'static String>> {
+    let Some(status) = crd.status.as_ref() else {
+        return Ok(None);
+    };
+
+    // A spec change may rotate the keys onto the current salt, so the recorded salt is only
+    // used for the generation it was recorded on, or when an invalid spec cleared the keys.
+    if status.observed_generation == crd.metadata.generation || status.auth_token_hash.is_empty() {
+        if let Some(key_salt) = &status.key_salt {
+            return Ok(SALT_IDS
+                .iter()
+                .find(|(id, _)| id == key_salt)
+                .map(|(_, salt)| *salt));
+        }
+    }
+
+    // Statuses written before the salt was recorded are looked up from the current salt to
+    // the oldest.
+    for salt in get_config().api_key_salts() {
+        // Fixed port keys don't use the salt, the named keys tell which one was used.
+        let issued = match fixed {
+            false => {
+                let key = build_api_key_with_salt(crd, salt).await?;
+                is_api_key_hash(
+                    &key,
+                    &get_config().api_key_hash_secret,
+                    &status.auth_token_hash,
+                )
+            }
+            true => {
+                let mut issued = false;
+                for port_key in status.keys.iter() {
+                    let key = build_named_api_key_with_salt(crd, &port_key.name, salt).await?;
+                    let secret = &get_config().api_key_hash_secret;
+                    if is_api_key_hash(&key, secret, &port_key.auth_token_hash) {
+                        issued = true;
+                        break;
+                    }
+                }
+                issued
+            }
+        };
+        if issued {
+            return Ok(Some(salt));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Takes one of the salt migrations allowed per hour.
+pub fn take_migration_slot(
+    migrations: &mut Vec<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    per_hour: u32,
+) -> bool {
+    migrations.retain(|migrated_at| now - *migrated_at < chrono::Duration::try_hours(1).unwrap());
+    if migrations.len() >= per_hour as usize {
+        return false;
+    }
+
+    migrations.push(now);
+    true
 }
 
 async fn reconcile(crd: Arc<BlockfrostPort>, ctx: Arc<Context>) -> Result<Action> {
-    let key = match &crd.spec.auth_token {
+    let namespace = crd.namespace().unwrap();
+    let api: Api<BlockfrostPort> = Api::namespaced(ctx.client.clone(), &namespace);
+    let port = format!("{namespace}/{}", crd.name_any());
+    let generation = crd.metadata.generation;
+    let is_deleted = crd.metadata.deletion_timestamp.is_some();
+
+    let result = finalizer(&api, BLOCKFROST_PORT_FINALIZER, crd, |event| async {
+        match event {
+            Finalizer::Apply(crd) => apply(crd, ctx.clone()).await,
+            Finalizer::Cleanup(crd) => cleanup(crd, ctx.clone()).await,
+        }
+    })
+    .await
+    .map_err(|err| Error::FinalizerError(Box::new(err)));
+
+    let mut reconciles = ctx.state.reconciles.lock().unwrap();
+    if is_deleted && result.is_ok() {
+        reconciles.remove(&port);
+    } else {
+        let reconcile_result = ReconcileResult {
+            reconciled_at: Utc::now().to_rfc3339(),
+            generation,
+            error: result.as_ref().err().map(|err| err.to_string()),
+        };
+        reconciles.insert(port, reconcile_result);
+    }
+
+    result
+}
+
+async fn cleanup(crd: Arc<BlockfrostPort>, ctx: Arc<Context>) -> Result<Action> {
+    let namespace = crd.namespace().unwrap();
+    let consumer = format!("{namespace}.{}", crd.name_any());
+
+    // A failed flush is not fatal, the port usage is counted by the next collection instead.
+    if let Err(err) = flush_port_usage(&ctx.state, &consumer).await {
+        warn!(
+            resource = crd.name_any(),
+            error = err.to_string(),
+            "Failed to flush port usage"
+        );
+        ctx.state.metrics.metrics_failure(&err);
+    }
+
+    let secrets: Api<Secret> = Api::namespaced(ctx.client.clone(), &namespace);
+    match secrets
+        .delete(&build_secret_name(&crd), &DeleteParams::default())
+        .await
+    {
+        Ok(_) => {}
+        Err(kube::Error::Api(err)) if err.code == 404 => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    let event = build_event(
+        EventType::Normal,
+        "Deleted",
+        "Delete",
+        format!("Port {} deleted", crd.name_any()),
+    );
+    ctx.publish_event(&crd, event).await;
+
+    info!(resource = crd.name_any(), "Cleanup completed");
+
+    Ok(Action::await_change())
+}
+
+async fn apply(crd: Arc<BlockfrostPort>, ctx: Arc<Context>) -> Result<Action> {
+    let namespace = crd.namespace().unwrap();
+    let blockfrost_port = BlockfrostPort::api_resource();
+
+    let previous_conditions = crd
+        .status
+        .as_ref()
+        .map(|status| status.conditions.clone())
+        .unwrap_or_default();
+    let config = get_config();
+    let Some(tiers) = ctx.state.known_tiers(config) else {
+        info!(
+            resource = crd.name_any(),
+            "waiting for the tiers to be listed"
+        );
+        return Ok(Action::requeue(Duration::from_secs(5)));
+    };
+    let ports = ctx.ports.state();
+    let claimed_domains = claimed_custom_domains(ports.iter().map(Arc::as_ref), &crd);
+    let mut errors = validate_spec_with_tiers(&crd.spec, config, &tiers, &claimed_domains);
+    let served_auth_token = crd
+        .spec
+        .auth_token
+        .as_ref()
+        .zip(crd.status.as_ref())
+        .is_some_and(|(token, status)| {
+            is_api_key_hash(token, &config.api_key_hash_secret, &status.auth_token_hash)
+        });
+    grandfather_auth_token(&mut errors, &crd.spec, served_auth_token);
+    let conditions = build_conditions(&previous_conditions, &errors);
+
+    if !errors.is_empty() {
+        let retry = errors.iter().any(|error| {
+            matches!(
+                error,
+                ValidationError::UnknownTier(_) | ValidationError::CustomDomainConflict(_, _)
+            )
+        });
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        warn!(resource = crd.name_any(), ?errors, "Invalid port spec");
+
+        // Patching the conditions triggers another reconcile, only the first one is reported.
+        let previous_generation = crd
+            .status
+            .as_ref()
+            .and_then(|status| status.observed_generation);
+        if previous_generation != crd.metadata.generation {
+            let event = build_event(
+                EventType::Warning,
+                "ValidationFailed",
+                "Validate",
+                errors.join(", "),
+            );
+            ctx.publish_event(&crd, event).await;
+        }
+
+        // The key hashes are cleared so the proxy stops serving the port until it is fixed.
+        // The salt is kept, so the fixed port gets its keys back.
+        patch_resource_status(
+            ctx.client.clone(),
+            &namespace,
+            blockfrost_port,
+            &crd.name_any(),
+            serde_json::json!({
+                "authTokenHash": "",
+                "previousAuthTokenHash": null,
+                "previousAuthTokenExpiresAt": null,
+                "keys": [],
+                "previousKeys": [],
+                "customDomains": [],
+                "observedGeneration": crd.metadata.generation,
+                "conditions": conditions,
+            }),
+        )
+        .await?;
+
+        // Creating a tier or releasing a domain doesn't change the port, so ports of unknown
+        // tiers or conflicting domains check again.
+        if retry {
+            return Ok(Action::requeue(Duration::from_secs(60)));
+        }
+        return Ok(Action::await_change());
+    }
+
+    let now = Utc::now();
+
+    let fixed_auth_token = get_fixed_auth_token(ctx.client.clone(), &crd).await?;
+    let salt = match issued_salt(&crd, fixed_auth_token.is_some()).await? {
+        Some(salt) if *salt != config.api_key_salt => {
+            let migrate = config.api_key_salt_migration
+                && take_migration_slot(
+                    &mut ctx.state.key_migrations.lock().unwrap(),
+                    now,
+                    config.api_key_salt_migrations_per_hour,
+                );
+            if migrate {
+                info!(
+                    resource = crd.name_any(),
+                    "Migrating keys to the current salt"
+                );
+                &config.api_key_salt
+            } else {
+                salt
+            }
+        }
+        _ => &config.api_key_salt,
+    };
+
+    let key = match &fixed_auth_token {
         Some(key) => key.clone(),
-        None => build_api_key(&crd).await?,
+        None => build_api_key_with_salt(&crd, salt).await?,
     };
     let (hostname, hostname_key) = build_hostname(&key);
+    let key_hash = hash_api_key(&key, &config.api_key_hash_secret);
+    let mut rehashed = BTreeMap::from([(legacy_hash_api_key(&key), key_hash.clone())]);
+
+    let mut secret_data = BTreeMap::from([
+        ("authToken".to_string(), key),
+        (
+            "authenticatedEndpointUrl".to_string(),
+            format!("https://{hostname_key}"),
+        ),
+    ]);
+
+    if let Some(token) = &fixed_auth_token {
+        secret_data.insert(FIXED_AUTH_TOKEN_KEY.to_string(), token.clone());
+    }
+
+    let mut keys = vec![];
+    for port_key in crd.spec.keys.iter().flatten() {
+        let key = build_named_api_key_with_salt(&crd, &port_key.name, salt).await?;
+        let (_, hostname_key) = build_hostname(&key);
+        let auth_token_hash = hash_api_key(&key, &config.api_key_hash_secret);
+        rehashed.insert(legacy_hash_api_key(&key), auth_token_hash.clone());
+
+        keys.push(BlockfrostPortKeyStatus {
+            name: port_key.name.clone(),
+            auth_token_hash,
+            expires_at: port_key.expires_at.clone(),
+        });
+        secret_data.insert(
+            format!("authenticatedEndpointUrl.{}", port_key.name),
+            format!("https://{hostname_key}"),
+        );
+        secret_data.insert(format!("authToken.{}", port_key.name), key);
+    }
+
+    let secret_name = apply_auth_secret(ctx.client.clone(), &crd, secret_data).await?;
+
+    // The legacy token is kept in the Secret, so the port spec no longer carries it.
+    if crd.spec.auth_token.is_some() {
+        clear_spec_auth_token(ctx.client.clone(), &crd).await?;
+        info!(
+            resource = crd.name_any(),
+            "Migrated the auth token into the Secret"
+        );
+    }
+
+    let previous_status = crd
+        .status
+        .as_ref()
+        .map(|status| with_keyed_hashes(status, &rehashed));
+    let previous_key = build_previous_key(
+        previous_status.as_ref(),
+        &key_hash,
+        now,
+        config.key_rotation_grace_period,
+    );
+    let previous_keys = build_previous_keys(
+        previous_status.as_ref(),
+        &keys,
+        now,
+        config.key_rotation_grace_period,
+    );
 
     let status = BlockfrostPortStatus {
         endpoint_url: format!("https://{hostname}",),
-        authenticated_endpoint_url: format!("https://{hostname_key}").into(),
-        auth_token: key,
+        secret_name: Some(secret_name),
+        auth_token_hash: key_hash,
+        previous_auth_token_hash: previous_key.as_ref().map(|(hash, _)| hash.clone()),
+        previous_auth_token_expires_at: previous_key
+            .as_ref()
+            .map(|(_, expires_at)| expires_at.to_rfc3339()),
+        keys,
+        previous_keys: previous_keys.clone(),
+        key_salt: Some(salt_id(salt)),
+        legacy_auth_token: fixed_auth_token
+            .as_deref()
+            .is_some_and(is_legacy_auth_token),
+        custom_domains: crd.spec.custom_domains.clone().unwrap_or_default(),
+        observed_generation: crd.metadata.generation,
+        conditions,
+        usage: None,
     };
 
-    let namespace = crd.namespace().unwrap();
-    let blockfrost_port = BlockfrostPort::api_resource();
+    let events = build_status_events(previous_status.as_ref(), &status);
+
+    // Plaintext fields written by previous versions are removed from the status.
+    let mut payload = serde_json::to_value(status)?;
+    payload["authToken"] = serde_json::Value::Null;
+    payload["authenticatedEndpointUrl"] = serde_json::Value::Null;
+    payload["previousAuthToken"] = serde_json::Value::Null;
 
     patch_resource_status(
         ctx.client.clone(),
         &namespace,
         blockfrost_port,
         &crd.name_any(),
-        serde_json::to_value(status)?,
+        payload,
     )
     .await?;
 
+    for event in events {
+        ctx.publish_event(&crd, event).await;
+    }
+
     info!(resource = crd.name_any(), "Reconcile completed");
 
-    Ok(Action::await_change())
+    // Reconcile again when a previous key expires to remove it from the status, or to retry
+    // a migration that didn't fit in the last hour.
+    let expirations = previous_key
+        .map(|(_, expires_at)| expires_at)
+        .into_iter()
+        .chain(
+            previous_keys
+                .iter()
+                .filter_map(|key| parse_time(&key.expires_at)),
+        );
+    let pending_migration = (config.api_key_salt_migration && *salt != config.api_key_salt)
+        .then_some(Duration::from_secs(3600));
+    let requeue = expirations
+        .map(|expires_at| (expires_at - now).to_std().unwrap_or_default() + Duration::from_secs(1))
+        .chain(pending_migration)
+        .min();
+
+    match requeue {
+        Some(duration) => Ok(Action::requeue(duration)),
+        None => Ok(Action::await_change()),
+    }
 }
 
 fn error_policy(crd: Arc<BlockfrostPort>, err: &Error, ctx: Arc<Context>) -> Action {
     error!(error = err.to_string(), "reconcile failed");
-    ctx.metrics.reconcile_failure(&crd, err);
+    ctx.state.metrics.reconcile_failure(&crd, err);
+
+    let event = build_event(
+        EventType::Warning,
+        "ReconcileFailed",
+        "Reconcile",
+        err.to_string(),
+    );
+    tokio::spawn(async move { ctx.publish_event(&crd, event).await });
     Action::requeue(Duration::from_secs(5))
 }
 
+/// Watch events that need a reconcile: spec changes, deletions and finalizer updates, which
+/// the finalizer helper waits for. Status patches, e.g. of the quota collector, are skipped.
+fn reconcile_trigger(crd: &BlockfrostPort) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    crd.metadata.generation.hash(&mut hasher);
+    crd.metadata.deletion_timestamp.is_some().hash(&mut hasher);
+    crd.metadata.finalizers.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// Reconciles the ports until SIGTERM or the shutdown, waiting for the reconciles in progress.
 #[instrument("controller run", skip_all)]
-pub async fn run(state: Arc<State>) {
+pub async fn run(state: Arc<State>, shutdown: Shutdown) {
     info!("listening crds running");
 
     let client = Client::try_default()
@@ -102,12 +834,731 @@ pub async fn run(state: Arc<State>) {
 
     let crds = Api::<BlockfrostPort>::all(client.clone());
 
-    let ctx = Context::new(client, state.metrics.clone());
+    let (store, writer) = reflector::store();
+    let ctx = Context::new(client, state.clone(), store.clone());
+
+    let ports = watcher(crds, WatcherConfig::default().any_semantic())
+        .default_backoff()
+        .reflect(writer)
+        .applied_objects()
+        .predicate_filter(reconcile_trigger);
+    let controller = Controller::for_stream(ports, store.clone());
+    tokio::spawn(async move {
+        if store.wait_until_ready().await.is_ok() {
+            info!("controller watcher ready");
+            state.watcher_ready.store(true, Ordering::Release);
+        }
+    });
 
-    Controller::new(crds, WatcherConfig::default().any_semantic())
+    controller
         .shutdown_on_signal()
+        .graceful_shutdown_on(async move { shutdown.wait().await })
         .run(reconcile, error_policy, Arc::new(ctx))
         .filter_map(|x| async move { std::result::Result::ok(x) })
         .for_each(|_| futures::future::ready(()))
         .await;
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use hyper_014::{body::to_bytes, Body, Method, Request, Response, StatusCode};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+    use serde_json::{json, Value};
+    use tower_test::mock::{self, Handle};
+
+    use super::*;
+    use crate::build_api_key;
+    use crate::utils::test::{set_configs, spec};
+
+    /// Method, path with query and JSON body of a request to the mocked API.
+    type Requests = Arc<Mutex<Vec<(Method, String, Value)>>>;
+
+    /// Answers every request with `respond`, recording them. Event requests are
+    /// answered with the event itself.
+    fn mock_api<F>(mut handle: Handle<Request<Body>, Response<Body>>, respond: F) -> Requests
+    where
+        F: Fn(&Method, &str) -> (StatusCode, Value) + Send + 'static,
+    {
+        let requests: Requests = Arc::default();
+
+        let received = requests.clone();
+        tokio::spawn(async move {
+            while let Some((request, send)) = handle.next_request().await {
+                let method = request.method().clone();
+                let path = request.uri().to_string();
+                let body = to_bytes(request.into_body()).await.unwrap();
+                let body: Value = serde_json::from_slice(&body).unwrap_or_default();
+
+                let (status, response) = if path.contains("/events") {
+                    (StatusCode::CREATED, body.clone())
+                } else {
+                    respond(&method, &path)
+                };
+                received.lock().unwrap().push((method, path, body));
+
+                send.send_response(
+                    Response::builder()
+                        .status(status)
+                        .body(Body::from(response.to_string()))
+                        .unwrap(),
+                );
+            }
+        });
+
+        requests
+    }
+
+    fn context() -> (Arc<Context>, Handle<Request<Body>, Response<Body>>) {
+        context_with_ports(vec![])
+    }
+
+    /// Context of a cluster where the controller watcher listed `ports`.
+    fn context_with_ports(
+        ports: Vec<BlockfrostPort>,
+    ) -> (Arc<Context>, Handle<Request<Body>, Response<Body>>) {
+        set_configs();
+
+        let (service, handle) = mock::pair::<Request<Body>, Response<Body>>();
+        let client = Client::new(service, "default");
+        let state = State::default();
+        state.tiers_ready.store(true, Ordering::Release);
+        let (store, mut writer) = reflector::store();
+        writer.apply_watcher_event(&watcher::Event::Restarted(ports));
+        (
+            Arc::new(Context::new(client, Arc::new(state), store)),
+            handle,
+        )
+    }
+
+    fn port(throughput_tier: &str) -> BlockfrostPort {
+        let mut crd = BlockfrostPort::new(
+            "port",
+            BlockfrostPortSpec {
+                throughput_tier: throughput_tier.into(),
+                ..spec()
+            },
+        );
+        crd.metadata.namespace = Some("prj-test".into());
+        crd.metadata.uid = Some("6a3d8e0c-0c2b-4c59-b3f4-3b8b0c1b5f2e".into());
+        crd.metadata.generation = Some(1);
+        crd.metadata.finalizers = Some(vec![BLOCKFROST_PORT_FINALIZER.into()]);
+        crd
+    }
+
+    /// Responses of a cluster where every write succeeds.
+    fn respond_ok(method: &Method, path: &str) -> (StatusCode, Value) {
+        match (method, path) {
+            (&Method::PATCH, path) if path.contains("/secrets/") => (
+                StatusCode::OK,
+                json!({ "apiVersion": "v1", "kind": "Secret", "metadata": { "name": "blockfrost-auth-port" } }),
+            ),
+            (&Method::GET, path) if path.contains("/secrets/") => (
+                StatusCode::NOT_FOUND,
+                json!({ "kind": "Status", "apiVersion": "v1", "status": "Failure", "message": "not found", "reason": "NotFound", "code": 404 }),
+            ),
+            (&Method::DELETE, _) => (
+                StatusCode::NOT_FOUND,
+                json!({ "kind": "Status", "apiVersion": "v1", "status": "Failure", "message": "not found", "reason": "NotFound", "code": 404 }),
+            ),
+            _ => (StatusCode::OK, serde_json::to_value(port("0")).unwrap()),
+        }
+    }
+
+    fn status_patches(requests: &Requests) -> Vec<Value> {
+        requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, path, _)| path.contains("/blockfrostports/port/status"))
+            .map(|(_, _, body)| body["status"].clone())
+            .collect()
+    }
+
+    fn event_reasons(requests: &Requests) -> Vec<String> {
+        requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, path, _)| path.contains("/events"))
+            .map(|(_, _, body)| body["reason"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_status() {
+        let (ctx, handle) = context();
+        let requests = mock_api(handle, respond_ok);
+
+        let mut crd = port("0");
+        crd.status = Some(BlockfrostPortStatus {
+            usage: Some(BlockfrostPortUsage {
+                period: "2024-01".into(),
+                requests: 10,
+                updated_at: "2024-01-10T00:00:00Z".into(),
+            }),
+            ..Default::default()
+        });
+        let action = reconcile(Arc::new(crd.clone()), ctx.clone()).await.unwrap();
+        assert_eq!(action, Action::await_change());
+
+        let secret = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, path, _)| path.contains("/secrets/blockfrost-auth-port"))
+            .map(|(_, _, body)| body.clone())
+            .unwrap();
+        assert_eq!(secret["metadata"]["ownerReferences"][0]["name"], "port");
+
+        let status = &status_patches(&requests)[0];
+        let key = build_api_key(&crd).await.unwrap();
+        assert_eq!(
+            status["authTokenHash"],
+            hash_api_key(&key, "api_key_hash_secret")
+        );
+        assert_eq!(status["secretName"], "blockfrost-auth-port");
+        assert_eq!(status["observedGeneration"], 1);
+        assert_eq!(status["conditions"][0]["status"], "True");
+        assert!(status["authToken"].is_null());
+        // Usage is left to the quota collector, a null would clear it.
+        assert!(status.get("usage").is_none());
+        assert_eq!(
+            event_reasons(&requests),
+            vec!["KeyGenerated", "StatusUpdated"]
+        );
+
+        let reconciles = ctx.state.reconciles.lock().unwrap();
+        assert!(reconciles["prj-test/port"].error.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_idempotent_key() {
+        let (ctx, handle) = context();
+        let requests = mock_api(handle, respond_ok);
+
+        let mut crd = port("0");
+        reconcile(Arc::new(crd.clone()), ctx.clone()).await.unwrap();
+        let first = status_patches(&requests)[0].clone();
+
+        // The next reconcile sees the status written by the first one.
+        crd.status = Some(serde_json::from_value(first.clone()).unwrap());
+        reconcile(Arc::new(crd), ctx).await.unwrap();
+        let second = status_patches(&requests)[1].clone();
+
+        assert_eq!(first["authTokenHash"], second["authTokenHash"]);
+        assert!(second["previousAuthTokenHash"].is_null());
+        assert_eq!(
+            first["conditions"][0]["lastTransitionTime"],
+            second["conditions"][0]["lastTransitionTime"]
+        );
+        assert_eq!(
+            event_reasons(&requests),
+            vec!["KeyGenerated", "StatusUpdated"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_rotates_named_key() {
+        let (ctx, handle) = context();
+        let requests = mock_api(handle, respond_ok);
+
+        let mut crd = port("0");
+        crd.spec.keys = Some(vec![BlockfrostPortKey {
+            name: "ci".into(),
+            ..Default::default()
+        }]);
+        reconcile(Arc::new(crd.clone()), ctx.clone()).await.unwrap();
+        let first = status_patches(&requests)[0].clone();
+
+        // Bumping the key rotation keeps the replaced key through the grace period.
+        crd.spec.keys.as_mut().unwrap()[0].rotation = Some(1);
+        crd.status = Some(serde_json::from_value(first.clone()).unwrap());
+        let action = reconcile(Arc::new(crd), ctx).await.unwrap();
+        let second = &status_patches(&requests)[1];
+
+        assert_ne!(
+            second["keys"][0]["authTokenHash"],
+            first["keys"][0]["authTokenHash"]
+        );
+        assert_eq!(second["previousKeys"][0]["name"], "ci");
+        assert_eq!(
+            second["previousKeys"][0]["authTokenHash"],
+            first["keys"][0]["authTokenHash"]
+        );
+        assert_eq!(second["authTokenHash"], first["authTokenHash"]);
+        assert_ne!(action, Action::await_change());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_keeps_previous_salt() {
+        let (ctx, handle) = context();
+        let requests = mock_api(handle, respond_ok);
+
+        let mut crd = port("0");
+        let old_key = build_api_key_with_salt(&crd, "old_api_key_salt")
+            .await
+            .unwrap();
+        crd.status = Some(BlockfrostPortStatus {
+            auth_token_hash: hash_api_key(&old_key, "api_key_hash_secret"),
+            ..Default::default()
+        });
+        let action = reconcile(Arc::new(crd.clone()), ctx.clone()).await.unwrap();
+        assert_eq!(action, Action::await_change());
+
+        // Without migration the port keeps the key of its salt, recorded on the status.
+        let status = &status_patches(&requests)[0];
+        assert_eq!(
+            status["authTokenHash"],
+            hash_api_key(&old_key, "api_key_hash_secret")
+        );
+        assert!(status["previousAuthTokenHash"].is_null());
+        assert_eq!(status["keySalt"], salt_id("old_api_key_salt"));
+
+        // The recorded salt is used without matching the keys against every salt.
+        crd.status = Some(BlockfrostPortStatus {
+            auth_token_hash: "unknown".into(),
+            key_salt: Some(salt_id("old_api_key_salt")),
+            observed_generation: Some(1),
+            ..Default::default()
+        });
+        reconcile(Arc::new(crd.clone()), ctx.clone()).await.unwrap();
+        let status = &status_patches(&requests)[1];
+        assert_eq!(
+            status["authTokenHash"],
+            hash_api_key(&old_key, "api_key_hash_secret")
+        );
+
+        // Rotated ports get a key of the current salt.
+        crd.spec.rotation = Some(1);
+        crd.metadata.generation = Some(2);
+        crd.status = Some(BlockfrostPortStatus {
+            auth_token_hash: hash_api_key(&old_key, "api_key_hash_secret"),
+            key_salt: Some(salt_id("old_api_key_salt")),
+            observed_generation: Some(1),
+            ..Default::default()
+        });
+        reconcile(Arc::new(crd.clone()), ctx).await.unwrap();
+        let status = &status_patches(&requests)[2];
+        let key = build_api_key(&crd).await.unwrap();
+        assert_eq!(
+            status["authTokenHash"],
+            hash_api_key(&key, "api_key_hash_secret")
+        );
+        assert_eq!(
+            status["previousAuthTokenHash"],
+            hash_api_key(&old_key, "api_key_hash_secret")
+        );
+        assert_eq!(status["keySalt"], salt_id("api_key_salt"));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_rehashes_legacy_status() {
+        let (ctx, handle) = context();
+        let requests = mock_api(handle, respond_ok);
+
+        let crd = port("0");
+        let key = build_api_key(&crd).await.unwrap();
+        let mut legacy = crd.clone();
+        legacy.status = Some(BlockfrostPortStatus {
+            auth_token_hash: legacy_hash_api_key(&key),
+            ..Default::default()
+        });
+        reconcile(Arc::new(legacy), ctx).await.unwrap();
+
+        // The unkeyed hash of the same key is replaced without rotating it.
+        let status = &status_patches(&requests)[0];
+        assert_eq!(
+            status["authTokenHash"],
+            hash_api_key(&key, "api_key_hash_secret")
+        );
+        assert!(status["previousAuthTokenHash"].is_null());
+        assert!(!event_reasons(&requests).contains(&"KeyRotated".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_migrates_auth_token() {
+        let (ctx, handle) = context();
+        let requests = mock_api(handle, respond_ok);
+
+        let mut crd = port("0");
+        crd.spec.auth_token = Some("legacy-token".into());
+        crd.status = Some(BlockfrostPortStatus {
+            auth_token_hash: legacy_hash_api_key("legacy-token"),
+            secret_name: Some("blockfrost-auth-port".into()),
+            ..Default::default()
+        });
+        reconcile(Arc::new(crd.clone()), ctx).await.unwrap();
+
+        // The token moves into the Secret and is cleared from the spec.
+        let requests = requests.lock().unwrap();
+        let secret = requests
+            .iter()
+            .find(|(method, path, _)| method == Method::PATCH && path.contains("/secrets/"))
+            .map(|(_, _, body)| body.clone())
+            .unwrap();
+        assert_eq!(secret["data"]["fixedAuthToken"], "bGVnYWN5LXRva2Vu");
+        let spec = requests
+            .iter()
+            .find(|(method, path, _)| {
+                method == Method::PATCH && path.contains("/blockfrostports/port?")
+            })
+            .map(|(_, _, body)| body.clone())
+            .unwrap();
+        assert!(spec["spec"]["authToken"].is_null());
+        assert!(spec["spec"].get("authToken").is_some());
+
+        let status = requests
+            .iter()
+            .find(|(_, path, _)| path.contains("/blockfrostports/port/status"))
+            .map(|(_, _, body)| body["status"].clone())
+            .unwrap();
+        assert_eq!(
+            status["authTokenHash"],
+            hash_api_key("legacy-token", "api_key_hash_secret")
+        );
+        assert!(status["previousAuthTokenHash"].is_null());
+        assert_eq!(status["legacyAuthToken"], true);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_reads_migrated_auth_token() {
+        let (ctx, handle) = context();
+        let requests = mock_api(handle, |method, path| match (method, path) {
+            (&Method::GET, path) if path.contains("/secrets/") => (
+                StatusCode::OK,
+                json!({
+                    "apiVersion": "v1",
+                    "kind": "Secret",
+                    "metadata": { "name": "blockfrost-auth-port" },
+                    "data": { "fixedAuthToken": "bGVnYWN5LXRva2Vu" },
+                }),
+            ),
+            _ => respond_ok(method, path),
+        });
+
+        let mut crd = port("0");
+        crd.status = Some(BlockfrostPortStatus {
+            auth_token_hash: hash_api_key("legacy-token", "api_key_hash_secret"),
+            secret_name: Some("blockfrost-auth-port".into()),
+            ..Default::default()
+        });
+        reconcile(Arc::new(crd), ctx).await.unwrap();
+
+        // The migrated token stays the port key, and the spec isn't patched again.
+        let status = &status_patches(&requests)[0];
+        assert_eq!(
+            status["authTokenHash"],
+            hash_api_key("legacy-token", "api_key_hash_secret")
+        );
+        assert!(status["previousAuthTokenHash"].is_null());
+        assert!(!requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(_, path, _)| path.contains("/blockfrostports/port?")));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_waits_for_tiers() {
+        let (ctx, handle) = context();
+        let requests = mock_api(handle, respond_ok);
+        ctx.state.tiers_ready.store(false, Ordering::Release);
+
+        let action = reconcile(Arc::new(port("9")), ctx).await.unwrap();
+        assert_eq!(action, Action::requeue(Duration::from_secs(5)));
+        assert!(status_patches(&requests).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_invalid_spec() {
+        let (ctx, handle) = context();
+        let requests = mock_api(handle, respond_ok);
+
+        // A port that becomes invalid loses its keys, so the proxy stops serving it.
+        let mut crd = port("9");
+        crd.status = Some(BlockfrostPortStatus {
+            auth_token_hash: "port".into(),
+            keys: vec![BlockfrostPortKeyStatus {
+                name: "ci".into(),
+                auth_token_hash: "ci".into(),
+                expires_at: None,
+            }],
+            custom_domains: vec!["api.example.com".into()],
+            key_salt: Some(salt_id("api_key_salt")),
+            ..Default::default()
+        });
+        let action = reconcile(Arc::new(crd), ctx).await.unwrap();
+        // The tier can be created later, so the port checks again.
+        assert_eq!(action, Action::requeue(Duration::from_secs(60)));
+
+        let status = &status_patches(&requests)[0];
+        assert_eq!(status["authTokenHash"], "");
+        assert_eq!(status["keys"], json!([]));
+        assert_eq!(status["customDomains"], json!([]));
+        assert!(status.get("keySalt").is_none());
+        assert_eq!(status["conditions"][0]["status"], "False");
+        assert!(!requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(_, path, _)| path.contains("/secrets/")));
+        assert_eq!(event_reasons(&requests), vec!["ValidationFailed"]);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_custom_domain_conflict() {
+        let created = |seconds: i64| Some(Time(DateTime::from_timestamp(seconds, 0).unwrap()));
+        let mut owner = port("0");
+        owner.metadata.namespace = Some("prj-other".into());
+        owner.metadata.creation_timestamp = created(1_700_000_000);
+        owner.spec.custom_domains = Some(vec!["api.example.com".into()]);
+
+        let mut crd = port("0");
+        crd.metadata.creation_timestamp = created(1_700_000_100);
+        crd.spec.custom_domains = Some(vec!["api.example.com".into()]);
+
+        let (ctx, handle) = context_with_ports(vec![owner.clone(), crd.clone()]);
+        let requests = mock_api(handle, respond_ok);
+
+        // The port created later can't take the domain, and checks again until it is released.
+        let action = reconcile(Arc::new(crd), ctx.clone()).await.unwrap();
+        assert_eq!(action, Action::requeue(Duration::from_secs(60)));
+
+        let status = &status_patches(&requests)[0];
+        assert_eq!(status["authTokenHash"], "");
+        assert_eq!(status["customDomains"], json!([]));
+        let conflict = status["conditions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|condition| condition["type"] == "CustomDomainConflict")
+            .unwrap();
+        assert_eq!(conflict["status"], "True");
+        assert!(conflict["message"]
+            .as_str()
+            .unwrap()
+            .contains("prj-other/port"));
+
+        // The owner keeps the domain.
+        let action = reconcile(Arc::new(owner), ctx).await.unwrap();
+        assert_eq!(action, Action::await_change());
+        assert_eq!(
+            status_patches(&requests)[1]["customDomains"],
+            json!(["api.example.com"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_error_requeue() {
+        let (ctx, handle) = context();
+        let requests = mock_api(handle, |method, path| {
+            if !path.contains("/status") {
+                return respond_ok(method, path);
+            }
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({ "kind": "Status", "apiVersion": "v1", "status": "Failure", "message": "etcd unavailable", "reason": "InternalError", "code": 500 }),
+            )
+        });
+
+        let crd = Arc::new(port("0"));
+        let err = reconcile(crd.clone(), ctx.clone()).await.unwrap_err();
+        assert!(ctx.state.reconciles.lock().unwrap()["prj-test/port"]
+            .error
+            .is_some());
+
+        let action = error_policy(crd, &err, ctx.clone());
+        assert_eq!(action, Action::requeue(Duration::from_secs(5)));
+        let failures = ctx
+            .state
+            .metrics
+            .reconcile_failures
+            .with_label_values(&["port", err.metric_label().as_ref()])
+            .get();
+        assert_eq!(failures, 1);
+
+        // The failure event is published in the background.
+        for _ in 0..100 {
+            if event_reasons(&requests).contains(&"ReconcileFailed".to_string()) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("ReconcileFailed event not published");
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_cleanup() {
+        let (ctx, handle) = context();
+        let requests = mock_api(handle, respond_ok);
+
+        let mut crd = port("0");
+        crd.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        ctx.state.reconciles.lock().unwrap().insert(
+            "prj-test/port".into(),
+            ReconcileResult {
+                reconciled_at: Utc::now().to_rfc3339(),
+                generation: Some(1),
+                error: None,
+            },
+        );
+
+        reconcile(Arc::new(crd), ctx.clone()).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert!(requests
+            .iter()
+            .any(|(method, path, _)| method == Method::DELETE
+                && path.contains("/secrets/blockfrost-auth-port")));
+        let (_, _, finalizer_patch) = requests
+            .iter()
+            .find(|(method, path, _)| {
+                method == Method::PATCH && path.contains("/blockfrostports/port?")
+            })
+            .unwrap();
+        assert!(finalizer_patch.to_string().contains("/metadata/finalizers"));
+        assert!(ctx.state.reconciles.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_build_previous_key() {
+        let now = Utc::now();
+        let grace_period = Duration::from_secs(60);
+        let mut status = BlockfrostPortStatus {
+            auth_token_hash: "old".into(),
+            ..Default::default()
+        };
+
+        assert!(build_previous_key(None, "new", now, grace_period).is_none());
+        assert!(build_previous_key(Some(&status), "old", now, grace_period).is_none());
+
+        let (key, expires_at) =
+            build_previous_key(Some(&status), "new", now, grace_period).unwrap();
+        assert_eq!(key, "old");
+        assert_eq!(expires_at, now + chrono::Duration::try_seconds(60).unwrap());
+
+        status.auth_token_hash = "new".into();
+        status.previous_auth_token_hash = Some("old".into());
+        status.previous_auth_token_expires_at = Some(expires_at.to_rfc3339());
+        let (key, _) = build_previous_key(Some(&status), "new", now, grace_period).unwrap();
+        assert_eq!(key, "old");
+
+        let later = now + chrono::Duration::try_seconds(61).unwrap();
+        assert!(build_previous_key(Some(&status), "new", later, grace_period).is_none());
+    }
+
+    #[test]
+    fn test_build_previous_keys() {
+        let now = Utc::now();
+        let grace_period = Duration::from_secs(60);
+        let key = |name: &str, hash: &str| BlockfrostPortKeyStatus {
+            name: name.into(),
+            auth_token_hash: hash.into(),
+            expires_at: None,
+        };
+        let status = BlockfrostPortStatus {
+            keys: vec![key("ci", "old-ci"), key("partner", "partner")],
+            ..Default::default()
+        };
+
+        assert!(build_previous_keys(None, &[key("ci", "ci")], now, grace_period).is_empty());
+
+        // Only the key deriving another key is kept, until the grace period ends.
+        let keys = vec![key("ci", "ci"), key("partner", "partner")];
+        let previous_keys = build_previous_keys(Some(&status), &keys, now, grace_period);
+        assert_eq!(previous_keys.len(), 1);
+        assert_eq!(previous_keys[0].auth_token_hash, "old-ci");
+        let expires_at = now + chrono::Duration::try_seconds(60).unwrap();
+        assert_eq!(previous_keys[0].expires_at, Some(expires_at.to_rfc3339()));
+
+        // Kept by the next reconciles, and dropped when the key is removed or it expires.
+        let status = BlockfrostPortStatus {
+            keys: keys.clone(),
+            previous_keys,
+            ..Default::default()
+        };
+        assert_eq!(
+            build_previous_keys(Some(&status), &keys, now, grace_period).len(),
+            1
+        );
+        assert!(build_previous_keys(Some(&status), &keys[1..], now, grace_period).is_empty());
+        let later = now + chrono::Duration::try_seconds(61).unwrap();
+        assert!(build_previous_keys(Some(&status), &keys, later, grace_period).is_empty());
+    }
+
+    #[test]
+    fn test_take_migration_slot() {
+        let now = Utc::now();
+        let mut migrations = vec![];
+        assert!(take_migration_slot(&mut migrations, now, 2));
+        assert!(take_migration_slot(&mut migrations, now, 2));
+        assert!(!take_migration_slot(&mut migrations, now, 2));
+
+        let later = now + chrono::Duration::try_hours(1).unwrap();
+        assert!(take_migration_slot(&mut migrations, later, 2));
+        assert_eq!(migrations, vec![later]);
+    }
+
+    #[test]
+    fn test_reconcile_trigger() {
+        let crd = port("0");
+        let trigger = reconcile_trigger(&crd);
+
+        let mut updated = crd.clone();
+        updated.status = Some(BlockfrostPortStatus {
+            usage: Some(BlockfrostPortUsage::default()),
+            ..Default::default()
+        });
+        updated.metadata.resource_version = Some("2".into());
+        assert_eq!(reconcile_trigger(&updated), trigger);
+
+        updated.metadata.generation = Some(2);
+        assert_ne!(reconcile_trigger(&updated), trigger);
+
+        let mut deleted = crd.clone();
+        deleted.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        assert_ne!(reconcile_trigger(&deleted), trigger);
+
+        let mut finalized = crd.clone();
+        finalized.metadata.finalizers = None;
+        assert_ne!(reconcile_trigger(&finalized), trigger);
+    }
+
+    #[test]
+    fn test_build_status_events() {
+        let reasons = |events: Vec<Event>| -> Vec<String> {
+            events.into_iter().map(|event| event.reason).collect()
+        };
+        let key = |name: &str, hash: &str| BlockfrostPortKeyStatus {
+            name: name.into(),
+            auth_token_hash: hash.into(),
+            expires_at: None,
+        };
+
+        let mut status = BlockfrostPortStatus {
+            endpoint_url: "https://port.demeter.run".into(),
+            auth_token_hash: "port".into(),
+            keys: vec![key("ci", "ci-key")],
+            observed_generation: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            reasons(build_status_events(None, &status)),
+            vec!["KeyGenerated", "KeyGenerated", "StatusUpdated"]
+        );
+
+        // Reconciles without changes, e.g. after a usage report, don't publish events.
+        let previous = status.clone();
+        assert!(build_status_events(Some(&previous), &status).is_empty());
+
+        status.auth_token_hash = "rotated".into();
+        status.keys.push(key("backend", "backend-key"));
+        status.observed_generation = Some(2);
+        assert_eq!(
+            reasons(build_status_events(Some(&previous), &status)),
+            vec!["KeyRotated", "KeyGenerated", "StatusUpdated"]
+        );
+    }
+}
@@ -1,17 +1,24 @@
-use kube::CustomResourceExt;
+use operator::v1alpha2::with_conversion_webhook;
 
 fn main() {
+    // Namespace of the operator webhook server, which converts the port versions.
+    let namespace = std::env::var("WEBHOOK_NAMESPACE").unwrap_or("ext-cardano-blockfrost".into());
+    let port_crd = operator::blockfrost_port_crd().metadata.name;
+    let crds: Vec<_> = operator::crds()
+        .into_iter()
+        .map(|crd| match crd.metadata.name == port_crd {
+            true => with_conversion_webhook(crd, &namespace),
+            false => crd,
+        })
+        .collect();
+
     let args: Vec<String> = std::env::args().collect();
     if args.len() > 1 && args[1] == "json" {
-        print!(
-            "{}",
-            serde_json::to_string_pretty(&operator::BlockfrostPort::crd()).unwrap()
-        );
+        print!("{}", serde_json::to_string_pretty(&crds).unwrap());
         return;
     }
 
-    print!(
-        "{}",
-        serde_yaml::to_string(&operator::BlockfrostPort::crd()).unwrap()
-    )
+    for crd in crds {
+        print!("---\n{}", serde_yaml::to_string(&crd).unwrap())
+    }
 }
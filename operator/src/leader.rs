@@ -0,0 +1,280 @@
+use chrono::{DateTime, Utc};
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::MicroTime,
+};
+use kube::{
+    api::{ObjectMeta, PostParams},
+    Api, Client,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::watch;
+use tracing::{error, info, instrument, warn};
+
+use crate::{get_config, Config, Error, State};
+
+#[derive(Debug, PartialEq)]
+pub enum LeaseAction {
+    /// The lease is held by this replica.
+    Renew,
+    /// The lease is free or expired, this replica can take it.
+    Acquire,
+    /// The lease is held by another replica.
+    Wait,
+}
+
+/// Decides what a replica can do with the current lease.
+pub fn lease_action(spec: Option<&LeaseSpec>, identity: &str, now: DateTime<Utc>) -> LeaseAction {
+    let Some(spec) = spec else {
+        return LeaseAction::Acquire;
+    };
+    let Some(holder) = spec.holder_identity.as_deref().filter(|h| !h.is_empty()) else {
+        return LeaseAction::Acquire;
+    };
+    if holder == identity {
+        return LeaseAction::Renew;
+    }
+
+    let duration = chrono::Duration::try_seconds(spec.lease_duration_seconds.unwrap_or(0).into())
+        .unwrap_or_default();
+    match &spec.renew_time {
+        Some(MicroTime(renew_time)) if *renew_time + duration > now => LeaseAction::Wait,
+        _ => LeaseAction::Acquire,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutdownReason {
+    /// Another replica took the lease, or it expired without renewal.
+    LeaseLost,
+    /// The controller stopped, e.g. on SIGTERM.
+    Stopped,
+}
+
+/// Stops the controller and the collectors of the leader. Only the first reason is kept.
+#[derive(Clone)]
+pub struct Shutdown(Arc<watch::Sender<Option<ShutdownReason>>>);
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(None).0))
+    }
+}
+impl Shutdown {
+    pub fn trigger(&self, reason: ShutdownReason) {
+        self.0.send_if_modified(|current| match current {
+            Some(_) => false,
+            None => {
+                *current = Some(reason);
+                true
+            }
+        });
+    }
+
+    pub fn reason(&self) -> Option<ShutdownReason> {
+        *self.0.borrow()
+    }
+
+    /// Resolves once the shutdown is triggered.
+    pub async fn wait(&self) {
+        let mut receiver = self.0.subscribe();
+        let _ = receiver.wait_for(Option::is_some).await;
+    }
+}
+
+struct LeaderElector {
+    api: Api<Lease>,
+    name: String,
+    identity: String,
+    lease_duration: Duration,
+}
+impl LeaderElector {
+    fn new(client: Client, config: &Config) -> Self {
+        Self {
+            api: Api::namespaced(client, &config.leader_election_namespace),
+            name: config.leader_election_lease_name.clone(),
+            identity: std::env::var("HOSTNAME").unwrap_or("blockfrost-operator".into()),
+            lease_duration: config.leader_election_lease_duration,
+        }
+    }
+
+    fn spec(&self, now: DateTime<Utc>, acquire_time: DateTime<Utc>, transitions: i32) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: Some(self.identity.clone()),
+            lease_duration_seconds: Some(self.lease_duration.as_secs() as i32),
+            acquire_time: Some(MicroTime(acquire_time)),
+            renew_time: Some(MicroTime(now)),
+            lease_transitions: Some(transitions),
+        }
+    }
+
+    /// Takes or renews the lease, returning whether this replica is the leader. Updates use
+    /// the lease resourceVersion, so only one replica wins a race.
+    async fn try_acquire_or_renew(&self) -> Result<bool, Error> {
+        let now = Utc::now();
+        let Some(mut lease) = self.api.get_opt(&self.name).await? else {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(self.name.clone()),
+                    ..Default::default()
+                },
+                spec: Some(self.spec(now, now, 0)),
+            };
+            return match self.api.create(&PostParams::default(), &lease).await {
+                Ok(_) => Ok(true),
+                Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+                Err(err) => Err(err.into()),
+            };
+        };
+
+        let current = lease.spec.clone().unwrap_or_default();
+        lease.spec = match lease_action(Some(&current), &self.identity, now) {
+            LeaseAction::Wait => return Ok(false),
+            LeaseAction::Renew => {
+                let acquire_time = current.acquire_time.map(|t| t.0).unwrap_or(now);
+                let transitions = current.lease_transitions.unwrap_or(0);
+                Some(self.spec(now, acquire_time, transitions))
+            }
+            LeaseAction::Acquire => {
+                let transitions = current.lease_transitions.unwrap_or(0) + 1;
+                Some(self.spec(now, now, transitions))
+            }
+        };
+
+        match self
+            .api
+            .replace(&self.name, &PostParams::default(), &lease)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Blocks until this replica holds the lease, then keeps renewing it in the background. A
+/// replica that loses the lease triggers the returned shutdown, so it stops reconciling and
+/// collecting and restarts as a standby. Returns right away when leader election is disabled.
+#[instrument("leader election", skip_all)]
+pub async fn wait_for_leadership(state: Arc<State>) -> Shutdown {
+    let config = get_config();
+    let shutdown = Shutdown::default();
+    if !config.leader_election {
+        state.metrics.leader.set(1);
+        return shutdown;
+    }
+
+    let client = Client::try_default()
+        .await
+        .expect("failed to create kube client");
+    let elector = LeaderElector::new(client, config);
+    let retry_period = elector.lease_duration / 3;
+
+    info!(identity = elector.identity, "waiting for leadership");
+    loop {
+        match elector.try_acquire_or_renew().await {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(err) => {
+                warn!(error = err.to_string(), "failed to acquire lease");
+            }
+        }
+        tokio::time::sleep(retry_period).await;
+    }
+
+    info!(identity = elector.identity, "leadership acquired");
+    state.metrics.leader.set(1);
+
+    let lease_lost = shutdown.clone();
+    tokio::spawn(async move {
+        let mut last_renew = tokio::time::Instant::now();
+        loop {
+            tokio::select! {
+                _ = lease_lost.wait() => return,
+                _ = tokio::time::sleep(retry_period) => {}
+            }
+
+            match elector.try_acquire_or_renew().await {
+                Ok(true) => last_renew = tokio::time::Instant::now(),
+                Ok(false) => {
+                    error!("leadership lost to another replica, shutting down");
+                    break;
+                }
+                Err(err) => {
+                    warn!(error = err.to_string(), "failed to renew lease");
+                    if last_renew.elapsed() >= elector.lease_duration {
+                        error!("lease expired without renewal, shutting down");
+                        break;
+                    }
+                }
+            }
+        }
+        state.metrics.leader.set(0);
+        lease_lost.trigger(ShutdownReason::LeaseLost);
+    });
+
+    shutdown
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn spec(holder: Option<&str>, renewed_ago: i64) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: holder.map(|h| h.into()),
+            lease_duration_seconds: Some(15),
+            renew_time: Some(MicroTime(
+                Utc::now() - chrono::Duration::try_seconds(renewed_ago).unwrap(),
+            )),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let shutdown = Shutdown::default();
+        assert_eq!(shutdown.reason(), None);
+
+        let waiting = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.wait().await }
+        });
+        shutdown.trigger(ShutdownReason::LeaseLost);
+        shutdown.trigger(ShutdownReason::Stopped);
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // The first reason is kept, and a late waiter returns right away.
+        assert_eq!(shutdown.reason(), Some(ShutdownReason::LeaseLost));
+        shutdown.wait().await;
+    }
+
+    #[test]
+    fn test_lease_action() {
+        let now = Utc::now();
+        assert_eq!(lease_action(None, "a", now), LeaseAction::Acquire);
+        assert_eq!(
+            lease_action(Some(&spec(None, 0)), "a", now),
+            LeaseAction::Acquire
+        );
+        assert_eq!(
+            lease_action(Some(&spec(Some("a"), 0)), "a", now),
+            LeaseAction::Renew
+        );
+        assert_eq!(
+            lease_action(Some(&spec(Some("a"), 60)), "a", now),
+            LeaseAction::Renew
+        );
+        assert_eq!(
+            lease_action(Some(&spec(Some("b"), 5)), "a", now),
+            LeaseAction::Wait
+        );
+        assert_eq!(
+            lease_action(Some(&spec(Some("b"), 20)), "a", now),
+            LeaseAction::Acquire
+        );
+    }
+}
@@ -1,3 +1,13 @@
+use chrono::{DateTime, Utc};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+use kube::runtime::finalizer;
 use prometheus::Registry;
 use thiserror::Error;
 
@@ -17,6 +27,21 @@ pub enum Error {
 
     #[error("Config Error: {0}")]
     ConfigError(String),
+
+    #[error("Usage Cursor Error: {0}")]
+    UsageCursorError(String),
+
+    #[error("Usage Sink Error: {0}")]
+    UsageSinkError(String),
+
+    #[error("Conversion Error: {0}")]
+    ConversionError(String),
+
+    #[error("Manifest Error: {0}")]
+    ManifestError(String),
+
+    #[error("Finalizer Error: {0}")]
+    FinalizerError(#[source] Box<finalizer::Error<Error>>),
 }
 
 impl Error {
@@ -41,12 +66,50 @@ impl From<kube::Error> for Error {
 pub struct State {
     registry: Registry,
     pub metrics: Metrics,
+    pub usage_window: Arc<Mutex<UsageWindow>>,
+    pub started_at: DateTime<Utc>,
+    /// Set once the controller watcher listed the ports.
+    pub watcher_ready: Arc<AtomicBool>,
+    pub last_collection: Arc<Mutex<Option<DateTime<Utc>>>>,
+    /// Last reconcile result of each port, by `namespace/name`.
+    pub reconciles: Arc<Mutex<BTreeMap<String, ReconcileResult>>>,
+    /// Salt migrations of the last hour, limited by API_KEY_SALT_MIGRATIONS_PER_HOUR.
+    pub key_migrations: Arc<Mutex<Vec<DateTime<Utc>>>>,
+    /// Names of the BlockfrostTier objects, kept by the tier watcher.
+    pub tiers: Arc<RwLock<BTreeSet<String>>>,
+    /// Set once the tier watcher listed the BlockfrostTier objects.
+    pub tiers_ready: Arc<AtomicBool>,
 }
 impl State {
     pub fn new() -> Self {
         let registry = Registry::default();
         let metrics = Metrics::default().register(&registry).unwrap();
-        Self { registry, metrics }
+        Self {
+            registry,
+            metrics,
+            usage_window: Arc::default(),
+            started_at: Utc::now(),
+            watcher_ready: Arc::default(),
+            last_collection: Arc::default(),
+            reconciles: Arc::default(),
+            key_migrations: Arc::default(),
+            tiers: Arc::default(),
+            tiers_ready: Arc::default(),
+        }
+    }
+
+    /// Tiers a port can use: the BlockfrostTier objects, or TIERS when the cluster has none.
+    /// None until the tier watcher listed them.
+    pub fn known_tiers(&self, config: &Config) -> Option<Vec<String>> {
+        if !self.tiers_ready.load(Ordering::Acquire) {
+            return None;
+        }
+        let tiers = self.tiers.read().unwrap();
+        if tiers.is_empty() {
+            Some(config.tiers.clone())
+        } else {
+            Some(tiers.iter().cloned().collect())
+        }
     }
 
     pub fn metrics_collected(&self) -> Vec<prometheus::proto::MetricFamily> {
@@ -67,11 +130,39 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
 pub mod controller;
 pub use crate::controller::*;
 
+pub mod v1alpha2;
+pub use v1alpha2::blockfrost_port_crd;
+
 pub mod metrics;
 pub use metrics::*;
 
+pub mod billing;
+pub use billing::*;
+
+pub mod usage_policy;
+pub use usage_policy::*;
+
+pub mod quota;
+pub use quota::*;
+
+pub mod leader;
+
+pub mod tiers;
+
+pub mod manifest;
+pub use manifest::*;
+
+pub mod proxy_config;
+pub use proxy_config::*;
+
 mod config;
 pub use config::*;
 
 mod utils;
 pub use utils::*;
+
+mod validation;
+pub use validation::*;
+
+pub mod webhook;
+pub use webhook::*;
@@ -1,8 +1,14 @@
 use dotenv::dotenv;
-use std::{io, sync::Arc};
-use tracing::Level;
+use std::{io, sync::Arc, time::Duration};
+use tracing::{info, warn, Level};
 
-use operator::{controller, metrics as metrics_collector, State};
+use operator::{
+    controller, leader, leader::ShutdownReason, metrics as metrics_collector, quota, tiers,
+    webhook, State,
+};
+
+// How long the collectors get to finish a collection in progress once the controller stopped.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
@@ -12,10 +18,37 @@ async fn main() -> io::Result<()> {
 
     let state = Arc::new(State::default());
 
-    metrics_collector::run_metrics_collector(state.clone());
+    webhook::run_webhook_server(state.clone());
     metrics_collector::run_metrics_server(state.clone());
+    tiers::run_tier_watcher(state.clone());
+
+    // Standby replicas only serve metrics and webhooks.
+    let shutdown = leader::wait_for_leadership(state.clone()).await;
+
+    let collectors = [
+        metrics_collector::run_metrics_collector(state.clone(), shutdown.clone()),
+        quota::run_quota_collector(state.clone(), shutdown.clone()),
+    ];
+
+    controller::run(state.clone(), shutdown.clone()).await;
 
-    controller::run(state.clone()).await;
+    shutdown.trigger(ShutdownReason::Stopped);
+    for mut collector in collectors {
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, &mut collector)
+            .await
+            .is_err()
+        {
+            warn!("collector didn't stop in time, aborting it");
+            collector.abort();
+        }
+    }
 
-    Ok(())
+    match shutdown.reason() {
+        // Exits with an error so the replica restarts as a standby.
+        Some(ShutdownReason::LeaseLost) => Err(io::Error::other("leadership lost")),
+        _ => {
+            info!("operator stopped");
+            Ok(())
+        }
+    }
 }
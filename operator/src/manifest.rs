@@ -0,0 +1,236 @@
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+    JSONSchemaProps, JSONSchemaPropsOrArray,
+};
+use kube::Resource;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{
+    crds, v1alpha2::convert_object, validate_spec, validate_tier_spec, BlockfrostPort,
+    BlockfrostTier, Config, Error,
+};
+
+const OBJECT_FIELDS: [&str; 3] = ["apiVersion", "kind", "metadata"];
+
+/// Validates a manifest against the schema of its kind and apiVersion, as the API server
+/// would, returning the path and reason of each problem. Ports are also checked with the
+/// operator spec validation, and tiers as the proxy loads them.
+pub fn validate_manifest(manifest: &Value, config: &Config) -> Result<Vec<String>, Error> {
+    let api_version = manifest["apiVersion"].as_str().unwrap_or_default();
+    let kind = manifest["kind"].as_str().unwrap_or_default();
+    let (group, version) = api_version.split_once('/').unwrap_or_default();
+
+    let schema = crds()
+        .into_iter()
+        .filter(|crd| crd.spec.group == group && crd.spec.names.kind == kind)
+        .flat_map(|crd| crd.spec.versions)
+        .find(|crd_version| crd_version.name == version)
+        .and_then(|crd_version| crd_version.schema?.open_api_v3_schema)
+        .ok_or_else(|| Error::ManifestError(format!("unknown kind {kind} {api_version}")))?;
+
+    let mut errors = vec![];
+    validate_value("", manifest, &schema, &mut errors);
+
+    if errors.is_empty() && kind == BlockfrostPort::kind(&()) {
+        let port = parse_port(manifest)?;
+        errors.extend(
+            validate_spec(&port.spec, config)
+                .iter()
+                .map(|error| format!(".spec: {error}")),
+        );
+    }
+
+    if errors.is_empty() && kind == BlockfrostTier::kind(&()) {
+        let tier: BlockfrostTier = serde_json::from_value(manifest.clone())?;
+        errors.extend(
+            validate_tier_spec(&tier.spec)
+                .iter()
+                .map(|error| format!(".spec: {error}")),
+        );
+    }
+
+    Ok(errors)
+}
+
+/// Reads a BlockfrostPort manifest of any served version.
+pub fn parse_port(manifest: &Value) -> Result<BlockfrostPort, Error> {
+    let api_version = BlockfrostPort::api_version(&());
+    let object = convert_object(manifest.clone(), &api_version)?;
+    Ok(serde_json::from_value(object)?)
+}
+
+fn validate_value(path: &str, value: &Value, schema: &JSONSchemaProps, errors: &mut Vec<String>) {
+    let path = if path.is_empty() { "." } else { path };
+
+    if value.is_null() {
+        if schema.nullable != Some(true) {
+            errors.push(format!("{path}: must not be null"));
+        }
+        return;
+    }
+
+    if let Some(type_) = &schema.type_ {
+        let valid = match type_.as_str() {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            "boolean" => value.is_boolean(),
+            _ => true,
+        };
+        if !valid {
+            errors.push(format!("{path}: must be {type_}"));
+            return;
+        }
+    }
+
+    if let Some(values) = &schema.enum_ {
+        if !values.iter().any(|allowed| allowed.0 == *value) {
+            let values: Vec<String> = values.iter().map(|allowed| allowed.0.to_string()).collect();
+            errors.push(format!("{path}: must be one of {}", values.join(", ")));
+        }
+    }
+
+    if let (Some(pattern), Some(value)) = (&schema.pattern, value.as_str()) {
+        if Regex::new(pattern).is_ok_and(|regex| !regex.is_match(value)) {
+            errors.push(format!("{path}: must match {pattern}"));
+        }
+    }
+
+    if let (Some(minimum), Some(value)) = (schema.minimum, value.as_f64()) {
+        if value < minimum {
+            errors.push(format!("{path}: must be at least {minimum}"));
+        }
+    }
+
+    let prefix = path.trim_end_matches('.');
+    match value {
+        Value::Object(fields) => {
+            // Objects without properties, e.g. metadata, are not checked by the CRD schema.
+            let Some(properties) = &schema.properties else {
+                return;
+            };
+            for required in schema.required.iter().flatten() {
+                if !fields.contains_key(required) {
+                    errors.push(format!("{prefix}.{required}: is required"));
+                }
+            }
+            for (name, value) in fields {
+                // The API server adds the object fields to every CRD schema.
+                if prefix.is_empty() && OBJECT_FIELDS.contains(&name.as_str()) {
+                    continue;
+                }
+                match properties.get(name) {
+                    Some(schema) => {
+                        validate_value(&format!("{prefix}.{name}"), value, schema, errors)
+                    }
+                    None => errors.push(format!("{prefix}.{name}: unknown field")),
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(JSONSchemaPropsOrArray::Schema(schema)) = &schema.items {
+                for (i, item) in items.iter().enumerate() {
+                    validate_value(&format!("{prefix}[{i}]"), item, schema, errors);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::get_config;
+    use crate::utils::test::set_configs;
+
+    use super::*;
+
+    fn manifest() -> Value {
+        json!({
+            "apiVersion": "demeter.run/v1alpha2",
+            "kind": "BlockfrostPort",
+            "metadata": { "name": "port", "namespace": "prj-test" },
+            "spec": {
+                "operatorVersion": "1",
+                "network": "cardano-mainnet",
+                "throughputTier": "0",
+                "auth": { "keys": [{ "name": "ci" }] }
+            }
+        })
+    }
+
+    #[test]
+    fn test_validate_manifest() {
+        set_configs();
+        let config = get_config();
+        assert!(validate_manifest(&manifest(), config).unwrap().is_empty());
+
+        let mut invalid = manifest();
+        invalid["spec"]["rotation"] = 1.into();
+        invalid["spec"]["auth"]["keys"][0]["name"] = 1.into();
+        invalid["spec"]
+            .as_object_mut()
+            .unwrap()
+            .remove("throughputTier");
+        let errors = validate_manifest(&invalid, config).unwrap();
+        assert_eq!(errors.len(), 3);
+        assert!(errors.contains(&".spec.throughputTier: is required".to_string()));
+        assert!(errors.contains(&".spec.auth.keys[0].name: must be string".to_string()));
+        assert!(errors.contains(&".spec.rotation: unknown field".to_string()));
+
+        // The operator validation runs once the schema is valid.
+        let mut invalid = manifest();
+        invalid["spec"]["network"] = "cardano-sepolia".into();
+        invalid["spec"]["throughputTier"] = "9".into();
+        let errors = validate_manifest(&invalid, config).unwrap();
+        assert_eq!(
+            errors,
+            vec![
+                ".spec: network cardano-sepolia is not supported",
+                ".spec: throughput tier 9 is not configured"
+            ]
+        );
+
+        let mut unknown = manifest();
+        unknown["apiVersion"] = "demeter.run/v1".into();
+        assert!(validate_manifest(&unknown, config).is_err());
+    }
+
+    #[test]
+    fn test_validate_tier_manifest() {
+        set_configs();
+        let mut tier = serde_json::json!({
+            "apiVersion": "demeter.run/v1alpha1",
+            "kind": "BlockfrostTier",
+            "metadata": { "name": "tier0" },
+            "spec": {
+                "rates": [{ "limit": 5, "interval": "1s" }],
+                "endpoints": { "deny": ["^/tx/submit$"] }
+            }
+        });
+        assert!(validate_manifest(&tier, get_config()).unwrap().is_empty());
+
+        tier["spec"]["rates"][0]["limit"] = 0.into();
+        let errors = validate_manifest(&tier, get_config()).unwrap();
+        assert_eq!(errors, vec![".spec.rates[0].limit: must be at least 1"]);
+
+        tier["spec"]["rates"][0]["limit"] = 5.into();
+        tier["spec"]["endpoints"]["deny"][0] = "^/tx(".into();
+        let errors = validate_manifest(&tier, get_config()).unwrap();
+        assert_eq!(
+            errors,
+            vec![".spec: endpoint pattern ^/tx( is not a valid regex"]
+        );
+    }
+
+    #[test]
+    fn test_parse_port() {
+        let port = parse_port(&manifest()).unwrap();
+        assert_eq!(port.spec.network, "cardano-mainnet");
+        assert_eq!(port.spec.keys.unwrap()[0].name, "ci");
+    }
+}
@@ -1,22 +1,42 @@
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use http_body_util::{combinators::BoxBody, BodyExt, Full};
-use hyper::{body::Bytes, server::conn::http1, service::service_fn, Response};
+use hyper::{
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
 use hyper_util::rt::TokioIo;
-use kube::{Resource, ResourceExt};
-use prometheus::{opts, Encoder, IntCounterVec, Registry, TextEncoder};
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{
+    api::{Patch, PatchParams},
+    Api, Client, Resource, ResourceExt,
+};
+use prometheus::{opts, Encoder, IntCounter, IntCounterVec, IntGauge, Registry, TextEncoder};
 use regex::Regex;
 use serde::{Deserialize, Deserializer};
-use std::{net::SocketAddr, str::FromStr, sync::Arc};
-use tokio::net::TcpListener;
-use tracing::{error, info, instrument};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    str::FromStr,
+    sync::{atomic::Ordering, Arc},
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+use tracing::{error, info, instrument, warn};
 
-use crate::{get_config, BlockfrostPort, Error, State};
+use crate::{
+    get_config, leader::Shutdown, publish_usage_events, BlockfrostPort, Config, Error, State,
+    UsageEvent, UsagePolicy,
+};
 
 #[derive(Clone)]
 pub struct Metrics {
     pub usage: IntCounterVec,
+    pub usage_credits: IntCounterVec,
     pub reconcile_failures: IntCounterVec,
     pub metrics_failures: IntCounterVec,
+    pub usage_gaps: IntCounter,
+    pub leader: IntGauge,
 }
 
 impl Default for Metrics {
@@ -27,6 +47,15 @@ impl Default for Metrics {
         )
         .unwrap();
 
+        let usage_credits = IntCounterVec::new(
+            opts!(
+                "usage_credits",
+                "Resource usage in credits, requests weighted by the usage policy.",
+            ),
+            &["feature", "project", "resource_name", "tier"],
+        )
+        .unwrap();
+
         let reconcile_failures = IntCounterVec::new(
             opts!(
                 "blockfrost_operator_crd_reconciliation_errors_total",
@@ -45,10 +74,25 @@ impl Default for Metrics {
         )
         .unwrap();
 
+        let usage_gaps = IntCounter::new(
+            "blockfrost_operator_usage_gaps_total",
+            "usage windows skipped after too many malformed Prometheus responses",
+        )
+        .unwrap();
+
+        let leader = IntGauge::new(
+            "blockfrost_operator_leader",
+            "1 when this replica is the leader running the controller and collectors",
+        )
+        .unwrap();
+
         Metrics {
             usage,
+            usage_credits,
             reconcile_failures,
             metrics_failures,
+            usage_gaps,
+            leader,
         }
     }
 }
@@ -57,7 +101,10 @@ impl Metrics {
     pub fn register(self, registry: &Registry) -> Result<Self, prometheus::Error> {
         registry.register(Box::new(self.reconcile_failures.clone()))?;
         registry.register(Box::new(self.metrics_failures.clone()))?;
+        registry.register(Box::new(self.usage_gaps.clone()))?;
         registry.register(Box::new(self.usage.clone()))?;
+        registry.register(Box::new(self.usage_credits.clone()))?;
+        registry.register(Box::new(self.leader.clone()))?;
 
         Ok(self)
     }
@@ -74,88 +121,406 @@ impl Metrics {
             .inc()
     }
 
-    pub fn count_usage(&self, project: &str, resource_name: &str, tier: &str, value: f64) {
+    pub fn count_usage(&self, project: &str, resource_name: &str, tier: &str, value: f64) -> u64 {
         let feature = &BlockfrostPort::kind(&());
         let value: u64 = value.ceil() as u64;
         self.usage
             .with_label_values(&[feature, project, resource_name, tier])
             .inc_by(value);
+        value
+    }
+
+    pub fn count_credits(&self, project: &str, resource_name: &str, tier: &str, value: f64) -> u64 {
+        let feature = &BlockfrostPort::kind(&());
+        let value: u64 = value.ceil() as u64;
+        self.usage_credits
+            .with_label_values(&[feature, project, resource_name, tier])
+            .inc_by(value);
+        value
     }
 }
 
-#[instrument("metrics collector run", skip_all)]
-pub fn run_metrics_collector(state: Arc<State>) {
-    tokio::spawn(async move {
-        info!("collecting metrics running");
+/// Window of the usage collector, shared with the reconciler to flush deleted ports.
+pub struct UsageWindow {
+    pub last_execution: DateTime<Utc>,
+    /// Consumers counted by a flush, with the time they were counted up to.
+    pub flushed: HashMap<String, DateTime<Utc>>,
+    /// End of a window whose events a sink rejected. It is collected again with the same
+    /// bounds, so sinks that already accepted it get the same event ids.
+    pub pending_end: Option<DateTime<Utc>>,
+    /// Malformed Prometheus responses for the window at the cursor.
+    pub failures: u32,
+}
+impl Default for UsageWindow {
+    fn default() -> Self {
+        Self {
+            last_execution: Utc::now(),
+            flushed: HashMap::new(),
+            pending_end: None,
+            failures: 0,
+        }
+    }
+}
 
-        let config = get_config();
-        let client = reqwest::Client::builder().build().unwrap();
-        let project_regex = Regex::new(r"prj-(.+)\.(.+)$").unwrap();
-        let mut last_execution = Utc::now();
+/// Time the usage was collected up to, saved on a ConfigMap so the next leader resumes
+/// from it.
+pub struct UsageCursor {
+    api: Api<ConfigMap>,
+    name: String,
+}
+impl UsageCursor {
+    const KEY: &'static str = "cursor";
 
-        loop {
-            tokio::time::sleep(config.metrics_delay).await;
+    pub fn new(client: Client, namespace: &str, name: &str) -> Self {
+        Self {
+            api: Api::namespaced(client, namespace),
+            name: name.into(),
+        }
+    }
 
-            let end = Utc::now();
-            let start = (end - last_execution).num_seconds();
+    /// Reads the cursor saved by a previous leader, if any.
+    pub async fn load(&self) -> Result<Option<DateTime<Utc>>, Error> {
+        let config_map = self
+            .api
+            .get_opt(&self.name)
+            .await
+            .map_err(|err| Error::UsageCursorError(format!("{}: {err}", self.name)))?;
+        let Some(cursor) = config_map
+            .and_then(|config_map| config_map.data)
+            .and_then(|mut data| data.remove(Self::KEY))
+        else {
+            return Ok(None);
+        };
 
-            last_execution = end;
+        DateTime::parse_from_rfc3339(cursor.trim())
+            .map(|cursor| Some(cursor.with_timezone(&Utc)))
+            .map_err(|err| Error::UsageCursorError(format!("{}: {err}", self.name)))
+    }
 
-            let query = format!(
-                "sum by (consumer, network, tier) (increase(blockfrost_proxy_http_total_request{{status_code!~\"401|429|503\"}}[{start}s] @ {}))",
-                end.timestamp_millis() / 1000
-            );
+    pub async fn save(&self, cursor: DateTime<Utc>) -> Result<(), Error> {
+        let config_map = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": { "name": self.name },
+            "data": { Self::KEY: cursor.to_rfc3339() },
+        });
+        self.api
+            .patch(
+                &self.name,
+                &PatchParams::apply("blockfrost-operator").force(),
+                &Patch::Apply(config_map),
+            )
+            .await
+            .map_err(|err| Error::UsageCursorError(format!("{}: {err}", self.name)))?;
+        Ok(())
+    }
+}
+
+/// Moves the usage window to the cursor of the previous leader, when one was saved.
+pub async fn resume_usage(state: &State, cursor: &UsageCursor) {
+    match cursor.load().await {
+        Ok(Some(cursor)) => {
+            info!(cursor = cursor.to_rfc3339(), "resuming usage collection");
+            state.usage_window.lock().unwrap().last_execution = cursor;
+        }
+        Ok(None) => info!("no usage cursor found, collecting from now"),
+        Err(err) => {
+            error!(error = err.to_string(), "error to load usage cursor");
+            state.metrics.metrics_failure(&err);
+        }
+    }
+}
 
-            let result = client
-                .get(format!("{}/query?query={query}", config.prometheus_url))
-                .send()
-                .await;
+/// Billable requests of each consumer and endpoint class in the `start` seconds before `end`.
+pub(crate) async fn query_usage(
+    client: &reqwest::Client,
+    config: &Config,
+    selector: &str,
+    start: i64,
+    end: DateTime<Utc>,
+) -> Result<PrometheusResponse, Error> {
+    let query = format!(
+        "sum by (consumer, network, tier, endpoint_class) (increase(blockfrost_proxy_http_total_request{{{}{selector}}}[{start}s] @ {}))",
+        config.usage_policy.status_code_matcher(),
+        end.timestamp_millis() / 1000
+    );
+    query_prometheus(client, config, &query).await
+}
 
-            if let Err(err) = result {
-                error!(error = err.to_string(), "error to make prometheus request");
-                state
-                    .metrics
-                    .metrics_failure(&Error::HttpError(err.to_string()));
-                continue;
-            }
+/// Runs an instant `query` on Prometheus.
+pub(crate) async fn query_prometheus(
+    client: &reqwest::Client,
+    config: &Config,
+    query: &str,
+) -> Result<PrometheusResponse, Error> {
+    let prometheus_url = &config.prometheus_url;
+    let response = client
+        .get(format!("{prometheus_url}/query"))
+        .query(&[("query", query)])
+        .send()
+        .await
+        .map_err(|err| Error::HttpError(err.to_string()))?;
 
-            let response = result.unwrap();
-            let status = response.status();
-            if status.is_client_error() || status.is_server_error() {
-                error!(status = status.to_string(), "request status code fail");
-                state.metrics.metrics_failure(&Error::HttpError(format!(
-                    "Prometheus request error. Status: {status} Query: {query}",
-                )));
-                continue;
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        return Err(Error::HttpError(format!(
+            "Prometheus request error. Status: {status} Query: {query}",
+        )));
+    }
+
+    let body = response
+        .text()
+        .await
+        .map_err(|err| Error::HttpError(err.to_string()))?;
+    Ok(serde_json::from_str(&body)?)
+}
+
+/// Usage events of the window, with the requests weighted by their endpoint class into
+/// credits.
+fn build_usage_events(
+    policy: &UsagePolicy,
+    response: PrometheusResponse,
+    skip: &HashSet<String>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Vec<UsageEvent> {
+    let project_regex = Regex::new(r"prj-(.+)\.(.+)$").unwrap();
+
+    // Requests and credits of each consumer, network and tier over the endpoint classes.
+    let mut usage: BTreeMap<(String, String, String), (f64, f64)> = BTreeMap::new();
+
+    for result in response.data.result {
+        if result.value == 0.0
+            || result.metric.consumer.is_none()
+            || result.metric.network.is_none()
+            || result.metric.tier.is_none()
+        {
+            continue;
+        }
+
+        let consumer = result.metric.consumer.unwrap();
+        if skip.contains(&consumer) {
+            continue;
+        }
+
+        let weight = policy.weight(result.metric.endpoint_class.as_deref());
+        let tier = result.metric.tier.unwrap();
+        let network = result.metric.network.unwrap();
+
+        let (requests, credits) = usage.entry((consumer, network, tier)).or_default();
+        *requests += result.value;
+        *credits += result.value * weight;
+    }
+
+    let mut events = vec![];
+    for ((consumer, network, tier), (requests, credits)) in usage {
+        let project_captures = project_regex.captures(&consumer);
+        if project_captures.is_none() {
+            continue;
+        }
+        let project_captures = project_captures.unwrap();
+        let project = project_captures.get(1).unwrap().as_str();
+        let resource_name = project_captures.get(2).unwrap().as_str();
+
+        let count = requests.ceil() as u64;
+        events.push(
+            UsageEvent::new(project, resource_name, &tier, &network, start, end, count)
+                .with_credits(credits.ceil() as u64),
+        );
+    }
+
+    events
+}
+
+/// Counts published events on the `usage` and `usage_credits` metrics.
+fn count_usage_events(state: &State, events: &[UsageEvent]) {
+    for event in events {
+        state.metrics.count_usage(
+            &event.project,
+            &event.resource_name,
+            &event.tier,
+            event.count as f64,
+        );
+        state.metrics.count_credits(
+            &event.project,
+            &event.resource_name,
+            &event.tier,
+            event.credits as f64,
+        );
+    }
+}
+
+/// Counts the usage of a port since the last collection, before the port is deleted.
+pub async fn flush_port_usage(state: &State, consumer: &str) -> Result<(), Error> {
+    let config = get_config();
+    let end = Utc::now();
+    let start = {
+        let mut usage_window = state.usage_window.lock().unwrap();
+        usage_window.flushed.insert(consumer.to_string(), end);
+        usage_window.last_execution
+    };
+    let seconds = (end - start).num_seconds();
+    if seconds <= 0 {
+        return Ok(());
+    }
+
+    let client = reqwest::Client::new();
+    let selector = format!(",consumer=\"{consumer}\"");
+    let result = async {
+        let response = query_usage(&client, config, &selector, seconds, end).await?;
+        let policy = &config.usage_policy;
+        let events = build_usage_events(policy, response, &HashSet::new(), start, end);
+        publish_usage_events(&client, config, &events).await?;
+        count_usage_events(state, &events);
+        Ok(())
+    }
+    .await;
+
+    if result.is_err() {
+        // The next collection counts the port instead.
+        state.usage_window.lock().unwrap().flushed.remove(consumer);
+    }
+    result
+}
+
+/// Collects the usage from the cursor up to `end`, in windows of at most
+/// `usage_max_window`. The cursor only moves after every sink accepted a window, so a
+/// failed window is collected again on the next run. A window Prometheus keeps answering
+/// with a malformed response is skipped after `usage_max_window_retries` runs, so it can't
+/// block the later windows.
+pub async fn collect_usage(
+    state: &State,
+    client: &reqwest::Client,
+    config: &Config,
+    cursor: Option<&UsageCursor>,
+    end: DateTime<Utc>,
+) -> Result<(), Error> {
+    let max_window = chrono::Duration::from_std(config.usage_max_window).unwrap();
+    let max_backfill = chrono::Duration::from_std(config.usage_max_backfill).unwrap();
+
+    loop {
+        let (start, window_end, skip) = {
+            let mut usage_window = state.usage_window.lock().unwrap();
+            if usage_window.last_execution < end - max_backfill {
+                warn!(
+                    cursor = usage_window.last_execution.to_rfc3339(),
+                    "usage cursor older than the max backfill, skipping the oldest usage"
+                );
+                usage_window.last_execution = end - max_backfill;
+                usage_window.pending_end = None;
+                usage_window.failures = 0;
             }
 
-            let response = response.json::<PrometheusResponse>().await.unwrap();
-            for result in response.data.result {
-                if result.value == 0.0
-                    || result.metric.consumer.is_none()
-                    || result.metric.network.is_none()
-                    || result.metric.tier.is_none()
-                {
-                    continue;
-                }
+            let start = usage_window.last_execution;
+            let window_end = usage_window
+                .pending_end
+                .filter(|pending_end| *pending_end > start)
+                .unwrap_or_else(|| (start + max_window).min(end));
+            let skip: HashSet<String> = usage_window
+                .flushed
+                .iter()
+                .filter(|(_, flushed_at)| **flushed_at > start)
+                .map(|(consumer, _)| consumer.clone())
+                .collect();
+            (start, window_end, skip)
+        };
 
-                let consumer = result.metric.consumer.unwrap();
-                let project_captures = project_regex.captures(&consumer);
-                if project_captures.is_none() {
-                    continue;
+        let seconds = (window_end - start).num_seconds();
+        if seconds <= 0 {
+            return Ok(());
+        }
+
+        let events = match query_usage(client, config, "", seconds, window_end).await {
+            Ok(response) => {
+                build_usage_events(&config.usage_policy, response, &skip, start, window_end)
+            }
+            Err(err @ Error::DeserializeError(_)) => {
+                let mut usage_window = state.usage_window.lock().unwrap();
+                usage_window.failures += 1;
+                if usage_window.failures <= config.usage_max_window_retries {
+                    return Err(err);
                 }
-                let project_captures = project_captures.unwrap();
-                let project = project_captures.get(1).unwrap().as_str();
-                let resource_name = project_captures.get(2).unwrap().as_str();
+                error!(
+                    error = err.to_string(),
+                    start = start.to_rfc3339(),
+                    end = window_end.to_rfc3339(),
+                    "malformed usage response, skipping the window"
+                );
+                state.metrics.metrics_failure(&err);
+                state.metrics.usage_gaps.inc();
+                vec![]
+            }
+            Err(err) => return Err(err),
+        };
+        if let Err(err) = publish_usage_events(client, config, &events).await {
+            state.usage_window.lock().unwrap().pending_end = Some(window_end);
+            return Err(err);
+        }
+        count_usage_events(state, &events);
 
-                let tier = result.metric.tier.unwrap();
+        {
+            let mut usage_window = state.usage_window.lock().unwrap();
+            usage_window.last_execution = window_end;
+            usage_window.pending_end = None;
+            usage_window.failures = 0;
+            usage_window
+                .flushed
+                .retain(|_, flushed_at| *flushed_at > window_end);
+        }
 
-                state
-                    .metrics
-                    .count_usage(project, resource_name, &tier, result.value);
+        if let Some(cursor) = cursor {
+            if let Err(err) = cursor.save(window_end).await {
+                error!(error = err.to_string(), "error to save usage cursor");
+                state.metrics.metrics_failure(&err);
             }
         }
-    });
+    }
+}
+
+/// Collects the usage every `metrics_delay` until the shutdown, finishing the collection in
+/// progress so the cursor is saved.
+#[instrument("metrics collector run", skip_all)]
+pub fn run_metrics_collector(state: Arc<State>, shutdown: Shutdown) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        info!("collecting metrics running");
+
+        let config = get_config();
+        let client = reqwest::Client::builder().build().unwrap();
+
+        // Started once this replica is the leader, so it resumes where the previous one stopped.
+        let cursor = match &config.usage_cursor_config_map {
+            Some(name) => {
+                let kube_client = Client::try_default()
+                    .await
+                    .expect("failed to create kube client");
+                Some(UsageCursor::new(
+                    kube_client,
+                    &config.usage_cursor_namespace,
+                    name,
+                ))
+            }
+            None => None,
+        };
+        if let Some(cursor) = &cursor {
+            resume_usage(&state, cursor).await;
+        }
+
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => break,
+                _ = tokio::time::sleep(config.metrics_delay) => {}
+            }
+
+            match collect_usage(&state, &client, config, cursor.as_ref(), Utc::now()).await {
+                Ok(()) => *state.last_collection.lock().unwrap() = Some(Utc::now()),
+                Err(err) => {
+                    error!(error = err.to_string(), "error to collect prometheus usage");
+                    state.metrics.metrics_failure(&err);
+                }
+            }
+        }
+        info!("collecting metrics stopped");
+    })
 }
 
 pub fn run_metrics_server(state: Arc<State>) {
@@ -193,7 +558,7 @@ pub fn run_metrics_server(state: Arc<State>) {
             let io = TokioIo::new(stream);
 
             tokio::task::spawn(async move {
-                let service = service_fn(move |_| api_get_metrics(state.clone()));
+                let service = service_fn(move |req| api_metrics_server(state.clone(), req));
 
                 if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
                     error!(error = err.to_string(), "failed metrics server connection");
@@ -203,58 +568,544 @@ pub fn run_metrics_server(state: Arc<State>) {
     });
 }
 
-async fn api_get_metrics(
+/// Whether the operator can do its work. Standby replicas only serve metrics and
+/// webhooks, so they are always ready. The leader is ready once the controller
+/// watcher is established and usage was collected in the last three delays.
+pub fn is_ready(state: &State, config: &Config, now: DateTime<Utc>) -> bool {
+    if state.metrics.leader.get() == 0 {
+        return true;
+    }
+    if !state.watcher_ready.load(Ordering::Acquire) {
+        return false;
+    }
+
+    let last_collection = state
+        .last_collection
+        .lock()
+        .unwrap()
+        .unwrap_or(state.started_at);
+    let max_delay = chrono::Duration::from_std(config.metrics_delay * 3).unwrap_or_default();
+    now - last_collection <= max_delay
+}
+
+async fn api_metrics_server(
     state: Arc<State>,
+    req: Request<Incoming>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
-    let metrics = state.metrics_collected();
+    let config = get_config();
+    if req.method() != Method::GET {
+        return Ok(response(StatusCode::NOT_FOUND, "not found"));
+    }
 
-    let encoder = TextEncoder::new();
-    let mut buffer = vec![];
-    encoder.encode(&metrics, &mut buffer).unwrap();
+    let res = match req.uri().path() {
+        "/metrics" => {
+            let metrics = state.metrics_collected();
 
-    let res = Response::builder()
+            let encoder = TextEncoder::new();
+            let mut buffer = vec![];
+            encoder.encode(&metrics, &mut buffer).unwrap();
+            response(StatusCode::OK, buffer)
+        }
+        "/healthz" => response(StatusCode::OK, "OK"),
+        "/readyz" => match is_ready(&state, config, Utc::now()) {
+            true => response(StatusCode::OK, "READY"),
+            false => response(StatusCode::SERVICE_UNAVAILABLE, "NOT READY"),
+        },
+        "/debug/ports" if config.debug_endpoint => {
+            let reconciles = state.reconciles.lock().unwrap().clone();
+            response(StatusCode::OK, serde_json::to_vec(&reconciles).unwrap())
+        }
+        _ => response(StatusCode::NOT_FOUND, "not found"),
+    };
+    Ok(res)
+}
+
+fn response(status: StatusCode, body: impl Into<Bytes>) -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(status)
         .body(
-            Full::new(buffer.into())
+            Full::new(body.into())
                 .map_err(|never| match never {})
                 .boxed(),
         )
-        .unwrap();
-    Ok(res)
+        .unwrap()
 }
 
 #[derive(Debug, Deserialize)]
-struct PrometheusDataResultMetric {
-    consumer: Option<String>,
-    network: Option<String>,
-    tier: Option<String>,
+pub(crate) struct PrometheusDataResultMetric {
+    pub consumer: Option<String>,
+    pub network: Option<String>,
+    pub tier: Option<String>,
+    pub endpoint_class: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
-struct PrometheusDataResult {
-    metric: PrometheusDataResultMetric,
+pub(crate) struct PrometheusDataResult {
+    pub metric: PrometheusDataResultMetric,
     #[serde(deserialize_with = "deserialize_value")]
-    value: f64,
+    pub value: f64,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct PrometheusData {
-    result: Vec<PrometheusDataResult>,
+pub(crate) struct PrometheusData {
+    pub result: Vec<PrometheusDataResult>,
 }
 
 #[derive(Debug, Deserialize)]
-struct PrometheusResponse {
-    data: PrometheusData,
+pub(crate) struct PrometheusResponse {
+    pub data: PrometheusData,
 }
 
 fn deserialize_value<'de, D>(deserializer: D) -> Result<f64, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let value: Vec<serde_json::Value> = Deserialize::deserialize(deserializer)?;
-    Ok(value.into_iter().as_slice()[1]
-        .as_str()
-        .unwrap()
-        .parse::<f64>()
-        .unwrap())
+    let value: (serde_json::Value, String) = Deserialize::deserialize(deserializer)?;
+    value.1.parse::<f64>().map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use serde_json::json;
+    use std::{env, fs, sync::Mutex, time::Duration};
+    use tower_test::mock;
+
+    use super::*;
+
+    pub(crate) type Queries = Arc<Mutex<Vec<String>>>;
+
+    /// Serves `body` on /query and records the queries received.
+    pub(crate) async fn mock_prometheus(
+        status: StatusCode,
+        body: &'static str,
+    ) -> (String, Queries) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let queries: Queries = Arc::default();
+
+        let received = queries.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let received = received.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    received
+                        .lock()
+                        .unwrap()
+                        .push(req.uri().query().unwrap_or_default().to_string());
+                    async move {
+                        Ok::<_, hyper::Error>(
+                            Response::builder()
+                                .status(status)
+                                .body(Full::new(Bytes::from(body)))
+                                .unwrap(),
+                        )
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        (format!("http://{addr}"), queries)
+    }
+
+    type ConfigMaps = Arc<Mutex<BTreeMap<String, serde_json::Value>>>;
+
+    /// Cursor on a mocked cluster keeping the applied ConfigMaps in `config_maps`, so the
+    /// cursors of several replicas share them.
+    fn mock_cursor(config_maps: &ConfigMaps) -> UsageCursor {
+        let (service, mut handle) = mock::pair::<
+            hyper_014::Request<hyper_014::Body>,
+            hyper_014::Response<hyper_014::Body>,
+        >();
+
+        let config_maps = config_maps.clone();
+        tokio::spawn(async move {
+            while let Some((request, send)) = handle.next_request().await {
+                let name = request.uri().path().rsplit('/').next().unwrap().to_string();
+                let method = request.method().clone();
+                let body = hyper_014::body::to_bytes(request.into_body())
+                    .await
+                    .unwrap();
+
+                let mut config_maps = config_maps.lock().unwrap();
+                if method == hyper_014::Method::PATCH {
+                    config_maps.insert(name.clone(), serde_json::from_slice(&body).unwrap());
+                }
+                let response = match config_maps.get(&name) {
+                    Some(config_map) => hyper_014::Response::new(config_map.to_string().into()),
+                    None => hyper_014::Response::builder()
+                        .status(404)
+                        .body(
+                            json!({ "kind": "Status", "apiVersion": "v1", "status": "Failure", "message": "not found", "reason": "NotFound", "code": 404 })
+                                .to_string()
+                                .into(),
+                        )
+                        .unwrap(),
+                };
+                send.send_response(response);
+            }
+        });
+
+        UsageCursor::new(Client::new(service, "default"), "default", "usage-cursor")
+    }
+
+    fn config(prometheus_url: String) -> Config {
+        Config {
+            prometheus_url,
+            usage_max_window: Duration::from_secs(3600),
+            usage_max_backfill: Duration::from_secs(6 * 3600),
+            ..crate::utils::test::config()
+        }
+    }
+
+    fn events_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("blockfrost-usage-events-{name}"));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().into()
+    }
+
+    fn usage(state: &State, tier: &str) -> u64 {
+        state
+            .metrics
+            .usage
+            .with_label_values(&["BlockfrostPort", "mainnet-test", "port-a123ds", tier])
+            .get()
+    }
+
+    static USAGE_RESPONSE: &str = r#"{
+        "status": "success",
+        "data": {
+            "resultType": "vector",
+            "result": [
+                {
+                    "metric": { "consumer": "prj-mainnet-test.port-a123ds", "network": "mainnet", "tier": "0" },
+                    "value": [1700000000.123, "10.2"]
+                },
+                {
+                    "metric": { "consumer": "prj-mainnet-test.port-b456ef", "network": "mainnet", "tier": "0" },
+                    "value": [1700000000.123, "5"]
+                }
+            ]
+        }
+    }"#;
+
+    #[tokio::test]
+    async fn test_collect_usage_backfill() {
+        let (url, queries) = mock_prometheus(StatusCode::OK, USAGE_RESPONSE).await;
+        let events_path = events_path("backfill");
+        let mut config = config(url);
+        let cursor = mock_cursor(&ConfigMaps::default());
+        config.usage_events_file = Some(events_path.clone());
+
+        let state = State::default();
+        let end = Utc::now();
+        let start = end - chrono::Duration::try_minutes(150).unwrap();
+        state.usage_window.lock().unwrap().last_execution = start;
+        state.usage_window.lock().unwrap().flushed.insert(
+            "prj-mainnet-test.port-b456ef".into(),
+            start + chrono::Duration::try_minutes(10).unwrap(),
+        );
+
+        let client = reqwest::Client::new();
+        collect_usage(&state, &client, &config, Some(&cursor), end)
+            .await
+            .unwrap();
+
+        // 150 minutes are collected in windows of at most one hour.
+        let queries = queries.lock().unwrap().clone();
+        assert_eq!(queries.len(), 3);
+        assert!(queries[0].contains("%5B3600s%5D"));
+        assert!(queries[2].contains("%5B1800s%5D"));
+        assert_eq!(usage(&state, "0"), 33);
+
+        // The flushed port is only skipped in the window it was flushed.
+        let flushed = state
+            .metrics
+            .usage
+            .with_label_values(&["BlockfrostPort", "mainnet-test", "port-b456ef", "0"])
+            .get();
+        assert_eq!(flushed, 10);
+        assert!(state.usage_window.lock().unwrap().flushed.is_empty());
+
+        assert_eq!(state.usage_window.lock().unwrap().last_execution, end);
+        assert_eq!(cursor.load().await.unwrap(), Some(end));
+
+        let events: Vec<UsageEvent> = fs::read_to_string(&events_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 5);
+        assert_eq!(events[0].resource_name, "port-a123ds");
+        assert_eq!(events[0].count, 11);
+        assert_eq!(events[4].window_end, events[3].window_end);
+        fs::remove_file(events_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_collect_usage_credits() {
+        let response = r#"{
+            "data": {
+                "result": [
+                    {
+                        "metric": { "consumer": "prj-mainnet-test.port-a123ds", "network": "mainnet", "tier": "1", "endpoint_class": "default" },
+                        "value": [1700000000, "10"]
+                    },
+                    {
+                        "metric": { "consumer": "prj-mainnet-test.port-a123ds", "network": "mainnet", "tier": "1", "endpoint_class": "cached" },
+                        "value": [1700000000, "4"]
+                    },
+                    {
+                        "metric": { "consumer": "prj-mainnet-test.port-a123ds", "network": "mainnet", "tier": "1", "endpoint_class": "submit" },
+                        "value": [1700000000, "2"]
+                    }
+                ]
+            }
+        }"#;
+        let (url, queries) = mock_prometheus(StatusCode::OK, response).await;
+        let events_path = events_path("credits");
+        let mut config = config(url);
+        config.usage_events_file = Some(events_path.clone());
+        config.usage_policy = UsagePolicy {
+            billable_status_codes: Some(vec!["2xx".into()]),
+            weights: BTreeMap::from([("cached".into(), 0.5), ("submit".into(), 5.0)]),
+        };
+
+        let state = State::default();
+        let end = Utc::now();
+        state.usage_window.lock().unwrap().last_execution =
+            end - chrono::Duration::try_minutes(30).unwrap();
+
+        let client = reqwest::Client::new();
+        collect_usage(&state, &client, &config, None, end)
+            .await
+            .unwrap();
+
+        let queries = queries.lock().unwrap().clone();
+        assert!(queries[0].contains("endpoint_class"));
+        assert!(queries[0].contains("%222..%22"));
+
+        // The classes of a port are counted as one usage, weighted into credits.
+        assert_eq!(usage(&state, "1"), 16);
+        let credits = state
+            .metrics
+            .usage_credits
+            .with_label_values(&["BlockfrostPort", "mainnet-test", "port-a123ds", "1"])
+            .get();
+        assert_eq!(credits, 22);
+
+        let events: Vec<UsageEvent> = fs::read_to_string(&events_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].count, events[0].credits), (16, 22));
+        fs::remove_file(events_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_collect_usage_sink_failure() {
+        let (url, _) = mock_prometheus(StatusCode::OK, USAGE_RESPONSE).await;
+        let mut config = config(url);
+        let cursor = mock_cursor(&ConfigMaps::default());
+        // A directory can't be appended to, so the file sink rejects the events.
+        config.usage_events_file = Some(env::temp_dir().to_string_lossy().into());
+
+        let state = State::default();
+        let end = Utc::now();
+        let start = end - chrono::Duration::try_minutes(30).unwrap();
+        state.usage_window.lock().unwrap().last_execution = start;
+
+        let client = reqwest::Client::new();
+        let result = collect_usage(&state, &client, &config, Some(&cursor), end).await;
+        assert!(result.is_err());
+        assert_eq!(state.usage_window.lock().unwrap().last_execution, start);
+        assert_eq!(state.usage_window.lock().unwrap().pending_end, Some(end));
+        assert_eq!(cursor.load().await.unwrap(), None);
+        assert_eq!(usage(&state, "0"), 0);
+
+        // The retry keeps the window bounds of the rejected events.
+        let events_path = events_path("sink-failure");
+        config.usage_events_file = Some(events_path.clone());
+        let later = end + chrono::Duration::try_minutes(1).unwrap();
+        collect_usage(&state, &client, &config, Some(&cursor), later)
+            .await
+            .unwrap();
+
+        let events: Vec<UsageEvent> = fs::read_to_string(&events_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            events[0].window_end,
+            end.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        );
+        assert_eq!(events.len(), 4);
+        assert_eq!(usage(&state, "0"), 22);
+        assert_eq!(state.usage_window.lock().unwrap().pending_end, None);
+        assert_eq!(cursor.load().await.unwrap(), Some(later));
+        fs::remove_file(events_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_collect_usage_max_backfill() {
+        let (url, queries) = mock_prometheus(StatusCode::OK, USAGE_RESPONSE).await;
+        let config = config(url);
+
+        let state = State::default();
+        let end = Utc::now();
+        state.usage_window.lock().unwrap().last_execution =
+            end - chrono::Duration::try_days(30).unwrap();
+
+        let client = reqwest::Client::new();
+        collect_usage(&state, &client, &config, None, end)
+            .await
+            .unwrap();
+
+        assert_eq!(queries.lock().unwrap().len(), 6);
+        assert_eq!(state.usage_window.lock().unwrap().last_execution, end);
+    }
+
+    #[tokio::test]
+    async fn test_collect_usage_failures() {
+        let client = reqwest::Client::new();
+        let end = Utc::now();
+        let start = end - chrono::Duration::try_minutes(30).unwrap();
+
+        let malformed = r#"{"data": {"result": [{"metric": {}, "value": [1700000000, "NaN?"]}]}}"#;
+        for (status, body) in [
+            (StatusCode::OK, malformed),
+            (StatusCode::OK, "not json"),
+            (StatusCode::SERVICE_UNAVAILABLE, ""),
+        ] {
+            let (url, _) = mock_prometheus(status, body).await;
+            let config = config(url);
+            let cursor = mock_cursor(&ConfigMaps::default());
+
+            let state = State::default();
+            state.usage_window.lock().unwrap().last_execution = start;
+
+            let result = collect_usage(&state, &client, &config, Some(&cursor), end).await;
+            assert!(result.is_err());
+            assert_eq!(state.usage_window.lock().unwrap().last_execution, start);
+            assert_eq!(cursor.load().await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collect_usage_skips_malformed_window() {
+        let (url, queries) = mock_prometheus(StatusCode::OK, "not json").await;
+        let mut config = config(url);
+        let cursor = mock_cursor(&ConfigMaps::default());
+        config.usage_max_window_retries = 2;
+
+        let state = State::default();
+        let client = reqwest::Client::new();
+        let end = Utc::now();
+        let start = end - chrono::Duration::try_minutes(30).unwrap();
+        state.usage_window.lock().unwrap().last_execution = start;
+
+        for _ in 0..2 {
+            let result = collect_usage(&state, &client, &config, Some(&cursor), end).await;
+            assert!(result.is_err());
+            assert_eq!(state.usage_window.lock().unwrap().last_execution, start);
+        }
+        assert_eq!(state.metrics.usage_gaps.get(), 0);
+
+        // The third malformed response skips the window with a gap, and the cursor moves on.
+        collect_usage(&state, &client, &config, Some(&cursor), end)
+            .await
+            .unwrap();
+        assert_eq!(queries.lock().unwrap().len(), 3);
+        assert_eq!(state.metrics.usage_gaps.get(), 1);
+        assert_eq!(state.usage_window.lock().unwrap().last_execution, end);
+        assert_eq!(state.usage_window.lock().unwrap().failures, 0);
+        assert_eq!(cursor.load().await.unwrap(), Some(end));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_collector_shutdown() {
+        crate::utils::test::set_configs();
+        let shutdown = Shutdown::default();
+        let collector = run_metrics_collector(Arc::new(State::default()), shutdown.clone());
+
+        // The collector stops while waiting for the next run, without collecting.
+        shutdown.trigger(crate::leader::ShutdownReason::LeaseLost);
+        tokio::time::timeout(Duration::from_secs(1), collector)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_usage_cursor() {
+        let config_maps = ConfigMaps::default();
+        let cursor = mock_cursor(&config_maps);
+        assert_eq!(cursor.load().await.unwrap(), None);
+
+        let now = Utc::now();
+        cursor.save(now).await.unwrap();
+        assert_eq!(cursor.load().await.unwrap(), Some(now));
+
+        config_maps.lock().unwrap().get_mut("usage-cursor").unwrap()["data"]["cursor"] =
+            "yesterday".into();
+        assert!(cursor.load().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_usage_cursor_failover() {
+        let (url, queries) = mock_prometheus(StatusCode::OK, USAGE_RESPONSE).await;
+        let config = config(url);
+        let config_maps = ConfigMaps::default();
+        let client = reqwest::Client::new();
+
+        // The first leader collects up to `end` and saves the cursor.
+        let end = Utc::now();
+        let leader = State::default();
+        leader.usage_window.lock().unwrap().last_execution =
+            end - chrono::Duration::try_minutes(30).unwrap();
+        let cursor = mock_cursor(&config_maps);
+        collect_usage(&leader, &client, &config, Some(&cursor), end)
+            .await
+            .unwrap();
+
+        // The replica taking over resumes from the cursor instead of its own start time.
+        let standby = State::default();
+        let cursor = mock_cursor(&config_maps);
+        resume_usage(&standby, &cursor).await;
+        assert_eq!(standby.usage_window.lock().unwrap().last_execution, end);
+
+        let later = end + chrono::Duration::try_minutes(10).unwrap();
+        collect_usage(&standby, &client, &config, Some(&cursor), later)
+            .await
+            .unwrap();
+        let queries = queries.lock().unwrap().clone();
+        assert_eq!(queries.len(), 2);
+        assert!(queries[1].contains("%5B600s%5D"));
+        assert_eq!(cursor.load().await.unwrap(), Some(later));
+    }
+
+    #[test]
+    fn test_is_ready() {
+        let config = config("prometheus_url".into());
+        let state = State::default();
+        let now = state.started_at;
+        assert!(is_ready(&state, &config, now));
+
+        state.metrics.leader.set(1);
+        assert!(!is_ready(&state, &config, now));
+
+        state.watcher_ready.store(true, Ordering::Release);
+        assert!(is_ready(&state, &config, now));
+
+        let later = now + chrono::Duration::try_seconds(301).unwrap();
+        assert!(!is_ready(&state, &config, later));
+
+        *state.last_collection.lock().unwrap() = Some(later);
+        assert!(is_ready(&state, &config, later));
+    }
 }
@@ -0,0 +1,139 @@
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use kube::{CustomResource, CustomResourceExt};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{blockfrost_port_crd, BlockfrostEndpointPolicy};
+
+/// Throughput tier of the proxy. The object name is the tier name used by
+/// the ports `throughputTier`.
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(
+    kind = "BlockfrostTier",
+    group = "demeter.run",
+    version = "v1alpha1",
+    shortname = "bftiers",
+    category = "demeter-port"
+)]
+#[kube(printcolumn = r#"
+        {"name": "Monthly Quota", "jsonPath": ".spec.monthlyQuota", "type": "integer"}
+    "#)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockfrostTierSpec {
+    pub rates: Vec<BlockfrostTierRate>,
+    /// Requests allowed per calendar month, shared by every key of the port.
+    pub monthly_quota: Option<u64>,
+    /// Endpoints the ports of the tier can call.
+    pub endpoints: Option<BlockfrostEndpointPolicy>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockfrostTierRate {
+    /// Requests allowed per interval, at least 1.
+    #[schemars(range(min = 1))]
+    pub limit: i64,
+    /// Number followed by s, m, h or d, e.g. `1s` or `30d`.
+    #[schemars(regex(pattern = r"^\d+[smhd]$"))]
+    pub interval: String,
+}
+
+/// Cache duration for the endpoints matching a regex. Rules are matched in
+/// object name order and the first match wins.
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(
+    kind = "BlockfrostCacheRule",
+    group = "demeter.run",
+    version = "v1alpha1",
+    shortname = "bfcrules",
+    category = "demeter-port"
+)]
+#[kube(printcolumn = r#"
+        {"name": "Endpoint", "jsonPath": ".spec.endpoint", "type": "string"},
+        {"name": "Duration", "jsonPath": ".spec.durationSeconds", "type": "integer"}
+    "#)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockfrostCacheRuleSpec {
+    pub endpoint: String,
+    pub duration_seconds: u64,
+}
+
+/// Backend serving the requests matching a path, e.g. `/blocks/{hash}`.
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(
+    kind = "BlockfrostRoute",
+    group = "demeter.run",
+    version = "v1alpha1",
+    shortname = "bfroutes",
+    category = "demeter-port"
+)]
+#[kube(printcolumn = r#"
+        {"name": "Path", "jsonPath": ".spec.path", "type": "string"},
+        {"name": "Backend", "jsonPath": ".spec.backend", "type": "string"}
+    "#)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockfrostRouteSpec {
+    pub path: String,
+    pub backend: RouteBackend,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, JsonSchema, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteBackend {
+    Blockfrost,
+    Dolos,
+    Submitapi,
+}
+
+impl RouteBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RouteBackend::Blockfrost => "blockfrost",
+            RouteBackend::Dolos => "dolos",
+            RouteBackend::Submitapi => "submitapi",
+        }
+    }
+}
+
+/// Every CRD managed by the operator, as emitted by crdgen.
+pub fn crds() -> Vec<CustomResourceDefinition> {
+    vec![
+        blockfrost_port_crd(),
+        BlockfrostTier::crd(),
+        BlockfrostCacheRule::crd(),
+        BlockfrostRoute::crd(),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crds() {
+        let crds = crds();
+        let names: Vec<_> = crds
+            .iter()
+            .map(|crd| crd.metadata.name.clone().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "blockfrostports.demeter.run",
+                "blockfrosttiers.demeter.run",
+                "blockfrostcacherules.demeter.run",
+                "blockfrostroutes.demeter.run",
+            ]
+        );
+        assert!(crds[1..].iter().all(|crd| crd.spec.scope == "Cluster"));
+
+        let tier: BlockfrostTierSpec = serde_json::from_value(serde_json::json!({
+            "rates": [{ "limit": 5, "interval": "1s" }],
+            "monthlyQuota": 1000,
+            "endpoints": { "deny": ["^/tx/submit$"] }
+        }))
+        .unwrap();
+        assert_eq!(tier.monthly_quota, Some(1000));
+        assert_eq!(tier.endpoints.unwrap().deny.unwrap(), vec!["^/tx/submit$"]);
+    }
+}
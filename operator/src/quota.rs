@@ -0,0 +1,269 @@
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use kube::{Api, Client, CustomResourceExt, ResourceExt};
+use std::{collections::HashMap, sync::Arc};
+use tokio::task::JoinHandle;
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    get_config, leader::Shutdown, patch_resource_status, query_prometheus, BlockfrostPort,
+    BlockfrostPortUsage, Config, Error, State,
+};
+
+/// Quota period of `now`, the calendar month in UTC.
+pub fn quota_period(now: DateTime<Utc>) -> String {
+    now.format("%Y-%m").to_string()
+}
+
+/// Start of the quota period of `now`.
+pub fn quota_period_start(now: DateTime<Utc>) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0)
+        .unwrap()
+}
+
+/// Requests of each consumer since the start of the period, with a single query for all
+/// the ports.
+async fn query_period_usage(
+    client: &reqwest::Client,
+    config: &Config,
+    now: DateTime<Utc>,
+) -> Result<HashMap<String, u64>, Error> {
+    let seconds = (now - quota_period_start(now)).num_seconds();
+    if seconds <= 0 {
+        return Ok(HashMap::new());
+    }
+
+    let query = format!(
+        "sum by (consumer) (increase(blockfrost_proxy_http_total_request{{{}}}[{seconds}s] @ {}))",
+        config.usage_policy.status_code_matcher(),
+        now.timestamp()
+    );
+    let response = query_prometheus(client, config, &query).await?;
+    Ok(response
+        .data
+        .result
+        .into_iter()
+        .filter_map(|result| Some((result.metric.consumer?, result.value.ceil() as u64)))
+        .collect())
+}
+
+/// Publishes the period usage on the status of every port where it changed. A port that
+/// can't be patched is counted as a failure and retried on the next run, without holding
+/// back the others.
+pub async fn update_ports_usage(
+    state: &State,
+    client: Client,
+    http_client: &reqwest::Client,
+    config: &Config,
+    now: DateTime<Utc>,
+) -> Result<(), Error> {
+    let usage = query_period_usage(http_client, config, now).await?;
+    let period = quota_period(now);
+
+    let ports = Api::<BlockfrostPort>::all(client.clone())
+        .list(&Default::default())
+        .await?;
+    for port in ports {
+        let namespace = port.namespace().unwrap();
+        let requests = usage
+            .get(&format!("{namespace}.{}", port.name_any()))
+            .copied()
+            .unwrap_or_default();
+
+        let current = port
+            .status
+            .as_ref()
+            .and_then(|status| status.usage.as_ref());
+        if current.is_some_and(|current| current.period == period && current.requests == requests) {
+            continue;
+        }
+
+        let usage = BlockfrostPortUsage {
+            period: period.clone(),
+            requests,
+            updated_at: now.to_rfc3339(),
+        };
+        if let Err(err) = patch_resource_status(
+            client.clone(),
+            &namespace,
+            BlockfrostPort::api_resource(),
+            &port.name_any(),
+            serde_json::json!({ "usage": usage }),
+        )
+        .await
+        {
+            warn!(
+                error = err.to_string(),
+                namespace,
+                port = port.name_any(),
+                "error to update port usage"
+            );
+            state.metrics.metrics_failure(&err.into());
+        }
+    }
+
+    Ok(())
+}
+
+#[instrument("quota collector run", skip_all)]
+pub fn run_quota_collector(state: Arc<State>, shutdown: Shutdown) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        info!("collecting quota usage running");
+
+        let config = get_config();
+        let client = Client::try_default()
+            .await
+            .expect("failed to create kube client");
+        let http_client = reqwest::Client::new();
+
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => break,
+                _ = tokio::time::sleep(config.metrics_delay) => {}
+            }
+
+            if let Err(err) =
+                update_ports_usage(&state, client.clone(), &http_client, config, Utc::now()).await
+            {
+                error!(error = err.to_string(), "error to update ports usage");
+                state.metrics.metrics_failure(&err);
+            }
+        }
+        info!("collecting quota usage stopped");
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use hyper_014::{body::to_bytes, Body, Method, Request, Response};
+    use serde_json::json;
+    use std::sync::Mutex;
+    use tower_test::mock;
+
+    use super::*;
+    use crate::metrics::test::mock_prometheus;
+
+    static PERIOD_RESPONSE: &str = r#"{
+        "data": {
+            "result": [
+                { "metric": { "consumer": "prj-mainnet-test.port-a123ds" }, "value": [1700000000, "10.2"] },
+                { "metric": { "consumer": "prj-mainnet-test.port-b456ef" }, "value": [1700000000, "5"] },
+                { "metric": {}, "value": [1700000000, "3"] }
+            ]
+        }
+    }"#;
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-02-02T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    async fn config() -> (Config, crate::metrics::test::Queries) {
+        let (url, queries) = mock_prometheus(hyper::StatusCode::OK, PERIOD_RESPONSE).await;
+        let config = Config {
+            prometheus_url: url,
+            ..crate::utils::test::config()
+        };
+        (config, queries)
+    }
+
+    /// Listed port, with the usage published on its status.
+    fn port(name: &str, usage: Option<serde_json::Value>) -> serde_json::Value {
+        json!({
+            "apiVersion": "demeter.run/v1alpha1",
+            "kind": "BlockfrostPort",
+            "metadata": { "name": name, "namespace": "prj-mainnet-test" },
+            "spec": { "operatorVersion": "1", "network": "mainnet", "throughputTier": "0" },
+            "status": { "usage": usage },
+        })
+    }
+
+    #[tokio::test]
+    async fn test_query_period_usage() {
+        let (config, queries) = config().await;
+        let usage = query_period_usage(&reqwest::Client::new(), &config, now())
+            .await
+            .unwrap();
+
+        // Every port is counted by one query, summed by consumer on Prometheus.
+        let queries = queries.lock().unwrap().clone();
+        assert_eq!(queries.len(), 1);
+        assert!(queries[0].contains("sum+by+%28consumer%29"));
+        assert!(queries[0].contains("%5B86400s%5D"));
+        assert_eq!(
+            usage,
+            HashMap::from([
+                ("prj-mainnet-test.port-a123ds".to_string(), 11),
+                ("prj-mainnet-test.port-b456ef".to_string(), 5),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_ports_usage() {
+        let (config, _) = config().await;
+        let ports = json!({
+            "apiVersion": "demeter.run/v1alpha1",
+            "kind": "BlockfrostPortList",
+            "metadata": {},
+            "items": [
+                port(
+                    "port-a123ds",
+                    Some(json!({ "period": "2024-02", "requests": 11, "updatedAt": "2024-02-01T23:59:00Z" })),
+                ),
+                port("port-b456ef", None),
+            ],
+        });
+
+        let (service, mut handle) = mock::pair::<Request<Body>, Response<Body>>();
+        let patches: Arc<Mutex<Vec<(String, serde_json::Value)>>> = Arc::default();
+        let received = patches.clone();
+        tokio::spawn(async move {
+            while let Some((request, send)) = handle.next_request().await {
+                let method = request.method().clone();
+                let path = request.uri().path().to_string();
+                let body = to_bytes(request.into_body()).await.unwrap();
+                let response = match method {
+                    Method::PATCH => {
+                        let body = serde_json::from_slice(&body).unwrap();
+                        received.lock().unwrap().push((path, body));
+                        port("patched", None)
+                    }
+                    _ => ports.clone(),
+                };
+                send.send_response(Response::new(Body::from(response.to_string())));
+            }
+        });
+
+        let state = State::default();
+        let client = Client::new(service, "default");
+        update_ports_usage(&state, client, &reqwest::Client::new(), &config, now())
+            .await
+            .unwrap();
+
+        // The port whose published usage is current is left untouched.
+        let patches = patches.lock().unwrap().clone();
+        assert_eq!(patches.len(), 1);
+        assert!(patches[0]
+            .0
+            .ends_with("/blockfrostports/port-b456ef/status"));
+        assert_eq!(patches[0].1["status"]["usage"]["requests"], 5);
+        assert_eq!(patches[0].1["status"]["usage"]["period"], "2024-02");
+    }
+
+    #[test]
+    fn test_quota_period() {
+        let now = DateTime::parse_from_rfc3339("2024-02-29T23:59:59Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(quota_period(now), "2024-02");
+        assert_eq!(
+            quota_period_start(now).to_rfc3339(),
+            "2024-02-01T00:00:00+00:00"
+        );
+
+        let next = now + chrono::Duration::try_seconds(1).unwrap();
+        assert_eq!(quota_period(next), "2024-03");
+        assert_eq!(quota_period_start(next), next);
+    }
+}
@@ -0,0 +1,40 @@
+use futures::{StreamExt, TryStreamExt};
+use kube::{
+    runtime::{reflector, watcher, WatchStreamExt},
+    Api, Client, ResourceExt,
+};
+use std::sync::{atomic::Ordering, Arc};
+use tracing::{info, instrument, warn};
+
+use crate::{BlockfrostTier, State};
+
+/// Keeps the names of the BlockfrostTier objects on the state, so ports are validated
+/// against the tiers the proxy enforces. Ports are only reconciled once the tiers are
+/// listed.
+#[instrument("tier watcher", skip_all)]
+pub fn run_tier_watcher(state: Arc<State>) {
+    tokio::spawn(async move {
+        let client = Client::try_default()
+            .await
+            .expect("failed to create kube client");
+        let api = Api::<BlockfrostTier>::all(client);
+
+        let (reader, writer) = reflector::store();
+        let mut stream = reflector(writer, watcher(api, watcher::Config::default()))
+            .default_backoff()
+            .map_ok(move |_| reader.state().iter().map(|tier| tier.name_any()).collect())
+            .boxed();
+
+        while let Some(result) = stream.next().await {
+            match result {
+                Ok(tiers) => {
+                    *state.tiers.write().unwrap() = tiers;
+                    if !state.tiers_ready.swap(true, Ordering::AcqRel) {
+                        info!("tiers listed");
+                    }
+                }
+                Err(err) => warn!(error = err.to_string(), "failed to watch tiers"),
+            }
+        }
+    });
+}
@@ -0,0 +1,162 @@
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::{collections::BTreeMap, fs};
+
+use crate::Error;
+
+/// Status codes not billed when the policy doesn't list the billable ones: requests rejected
+/// by the proxy, rate limited or without an upstream.
+const NON_BILLABLE_STATUS_CODES: &str = "401|402|403|429|503";
+
+/// Class of the requests counted by proxies without the `endpoint_class` label.
+pub const DEFAULT_ENDPOINT_CLASS: &str = "default";
+
+/// Which proxy requests are billed and how many credits each one is worth, read from the
+/// YAML file of USAGE_POLICY_PATH.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct UsagePolicy {
+    /// Billed status codes, e.g. `200` or `2xx`. Every code but the non billable ones when
+    /// not set.
+    #[serde(default, deserialize_with = "deserialize_status_codes")]
+    pub billable_status_codes: Option<Vec<String>>,
+    /// Credits of a request of each endpoint class, 1 when the class is not listed.
+    #[serde(default)]
+    pub weights: BTreeMap<String, f64>,
+}
+
+/// Status codes can be written as numbers, e.g. `404`, or as patterns, e.g. `2xx`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StatusCode {
+    Code(u16),
+    Pattern(String),
+}
+
+fn deserialize_status_codes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    let codes: Option<Vec<StatusCode>> = Deserialize::deserialize(deserializer)?;
+    Ok(codes.map(|codes| {
+        codes
+            .into_iter()
+            .map(|code| match code {
+                StatusCode::Code(code) => code.to_string(),
+                StatusCode::Pattern(pattern) => pattern,
+            })
+            .collect()
+    }))
+}
+
+impl UsagePolicy {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        fs::read_to_string(path)
+            .map_err(|err| err.to_string())
+            .and_then(|content| serde_yaml::from_str(&content).map_err(|err| err.to_string()))
+            .and_then(|policy: Self| policy.validate().map(|_| policy))
+            .map_err(|err| Error::ConfigError(format!("{path}: {err}")))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(codes) = &self.billable_status_codes {
+            if codes.is_empty() {
+                return Err("billableStatusCodes must not be empty".into());
+            }
+
+            let status_code = Regex::new("^[1-5][0-9x]{2}$").unwrap();
+            if let Some(code) = codes.iter().find(|code| !status_code.is_match(code)) {
+                return Err(format!("invalid status code {code}"));
+            }
+        }
+
+        if let Some((class, weight)) = self
+            .weights
+            .iter()
+            .find(|(_, weight)| !weight.is_finite() || **weight < 0.0)
+        {
+            return Err(format!("invalid weight {weight} of {class}"));
+        }
+
+        Ok(())
+    }
+
+    /// Prometheus matcher of the billable requests on the `status_code` label.
+    pub fn status_code_matcher(&self) -> String {
+        match &self.billable_status_codes {
+            Some(codes) => {
+                let codes: Vec<String> = codes.iter().map(|code| code.replace('x', ".")).collect();
+                format!("status_code=~\"{}\"", codes.join("|"))
+            }
+            None => format!("status_code!~\"{NON_BILLABLE_STATUS_CODES}\""),
+        }
+    }
+
+    pub fn weight(&self, endpoint_class: Option<&str>) -> f64 {
+        self.weights
+            .get(endpoint_class.unwrap_or(DEFAULT_ENDPOINT_CLASS))
+            .copied()
+            .unwrap_or(1.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn test_status_code_matcher() {
+        assert_eq!(
+            UsagePolicy::default().status_code_matcher(),
+            "status_code!~\"401|402|403|429|503\""
+        );
+
+        let policy = UsagePolicy {
+            billable_status_codes: Some(vec!["2xx".into(), "404".into()]),
+            ..Default::default()
+        };
+        assert_eq!(policy.status_code_matcher(), "status_code=~\"2..|404\"");
+    }
+
+    #[test]
+    fn test_weight() {
+        let policy = UsagePolicy {
+            weights: BTreeMap::from([("cached".into(), 0.5), ("submit".into(), 5.0)]),
+            ..Default::default()
+        };
+        assert_eq!(policy.weight(Some("cached")), 0.5);
+        assert_eq!(policy.weight(Some("submit")), 5.0);
+        assert_eq!(policy.weight(Some("default")), 1.0);
+        assert_eq!(policy.weight(None), 1.0);
+    }
+
+    #[test]
+    fn test_load_usage_policy() {
+        let path = env::temp_dir().join("blockfrost-usage-policy.yaml");
+        let path = path.to_string_lossy().to_string();
+
+        fs::write(
+            &path,
+            "billableStatusCodes: [2xx, 404]\nweights:\n  cached: 0.5\n  submit: 5\n",
+        )
+        .unwrap();
+        let policy = UsagePolicy::load(&path).unwrap();
+        assert_eq!(policy.billable_status_codes.unwrap(), vec!["2xx", "404"]);
+        assert_eq!(policy.weights["submit"], 5.0);
+
+        for invalid in [
+            "billableStatusCodes: [2xxx]",
+            "billableStatusCodes: [600]",
+            "billableStatusCodes: []",
+            "weights:\n  submit: -1",
+            "weight:\n  submit: 1",
+        ] {
+            fs::write(&path, invalid).unwrap();
+            assert!(UsagePolicy::load(&path).is_err(), "{invalid}");
+        }
+
+        fs::remove_file(&path).unwrap();
+        assert!(UsagePolicy::load(&path).is_err());
+    }
+}
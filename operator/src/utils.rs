@@ -1,13 +1,18 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
 use argon2::Argon2;
 use base64::{engine::general_purpose, Engine};
 use bech32::ToBase32;
+use ipnet::IpNet;
+use k8s_openapi::{api::core::v1::Secret, ByteString};
 use kube::{
-    api::{Patch, PatchParams},
+    api::{ObjectMeta, Patch, PatchParams},
     core::DynamicObject,
     discovery::ApiResource,
-    Api, Client, ResourceExt,
+    Api, Client, Resource, ResourceExt,
 };
 use serde_json::json;
+use sha2::{Digest, Sha256};
 
 use crate::{get_config, BlockfrostPort, Error};
 
@@ -27,6 +32,158 @@ pub async fn patch_resource_status(
     Ok(())
 }
 
+/// Clears the legacy `spec.authToken` once its token is kept in the port Secret.
+pub async fn clear_spec_auth_token(
+    client: Client,
+    crd: &BlockfrostPort,
+) -> Result<(), kube::Error> {
+    let api: Api<BlockfrostPort> = Api::namespaced(client, &crd.namespace().unwrap());
+
+    let spec = json!({ "spec": { "authToken": null } });
+    api.patch(
+        &crd.name_any(),
+        &PatchParams::default(),
+        &Patch::Merge(spec),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Secret key of a fixed token migrated from `spec.authToken`.
+pub const FIXED_AUTH_TOKEN_KEY: &str = "fixedAuthToken";
+
+/// Fixed token of the port: the legacy `spec.authToken`, or the one migrated from it into
+/// the port Secret.
+pub async fn get_fixed_auth_token(
+    client: Client,
+    crd: &BlockfrostPort,
+) -> Result<Option<String>, kube::Error> {
+    if crd.spec.auth_token.is_some() {
+        return Ok(crd.spec.auth_token.clone());
+    }
+    // Ports without a Secret yet can't have a migrated token.
+    let secret_name = crd
+        .status
+        .as_ref()
+        .and_then(|status| status.secret_name.as_ref());
+    if secret_name.is_none() {
+        return Ok(None);
+    }
+
+    let api: Api<Secret> = Api::namespaced(client, &crd.namespace().unwrap());
+    let token = api
+        .get_opt(&build_secret_name(crd))
+        .await?
+        .and_then(|secret| secret.data)
+        .and_then(|mut data| data.remove(FIXED_AUTH_TOKEN_KEY))
+        .and_then(|token| String::from_utf8(token.0).ok());
+    Ok(token)
+}
+
+pub fn build_secret_name(crd: &BlockfrostPort) -> String {
+    format!("blockfrost-auth-{}", crd.name_any())
+}
+
+/// Writes the port keys into a Secret owned by the port, so they are removed with it.
+pub async fn apply_auth_secret(
+    client: Client,
+    crd: &BlockfrostPort,
+    data: BTreeMap<String, String>,
+) -> Result<String, kube::Error> {
+    let namespace = crd.namespace().unwrap();
+    let name = build_secret_name(crd);
+    let api: Api<Secret> = Api::namespaced(client, &namespace);
+
+    let secret = Secret {
+        metadata: ObjectMeta {
+            name: Some(name.clone()),
+            namespace: Some(namespace),
+            owner_references: crd.controller_owner_ref(&()).map(|owner| vec![owner]),
+            ..Default::default()
+        },
+        data: Some(
+            data.into_iter()
+                .map(|(k, v)| (k, ByteString(v.into_bytes())))
+                .collect(),
+        ),
+        type_: Some("Opaque".into()),
+        ..Default::default()
+    };
+
+    let patch_params = PatchParams::apply("blockfrost-operator").force();
+    api.patch(&name, &patch_params, &Patch::Apply(secret))
+        .await?;
+    Ok(name)
+}
+
+/// Hash published on the port status instead of the plaintext key, an HMAC-SHA256 keyed with
+/// API_KEY_HASH_SECRET. Only the operator and the proxy know the secret, so a status can't
+/// be matched against guessed keys.
+pub fn hash_api_key(key: &str, secret: &str) -> String {
+    hex::encode(hmac_sha256(secret.as_bytes(), key.as_bytes()))
+}
+
+/// Unkeyed hash published before the status hashes were keyed. Ports move to the keyed hash
+/// on their next reconcile, and previous keys hashed with it expire with their grace period.
+pub fn legacy_hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Whether `hash` is the status hash of `key`, keyed or legacy.
+pub fn is_api_key_hash(key: &str, secret: &str, hash: &str) -> bool {
+    hash == hash_api_key(key, secret) || hash == legacy_hash_api_key(key)
+}
+
+/// HMAC-SHA256 as in RFC 2104.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0; 64];
+    if key.len() > block.len() {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let inner = Sha256::new()
+        .chain_update(block.map(|byte| byte ^ 0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(block.map(|byte| byte ^ 0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKeyPrefix {
+    pub version: String,
+    pub network: String,
+}
+
+/// Reads the version and network from the bech32 prefix of a key, checking its checksum.
+pub fn parse_api_key(key: &str) -> Option<ApiKeyPrefix> {
+    let (hrp, _, variant) = bech32::decode(key).ok()?;
+    if variant != bech32::Variant::Bech32 {
+        return None;
+    }
+
+    let prefix = hrp.strip_prefix("dmtr_blockfrost_")?.strip_suffix('_')?;
+    let (version, network) = prefix.split_once('_')?;
+
+    Some(ApiKeyPrefix {
+        version: version.into(),
+        network: network.into(),
+    })
+}
+
+/// Parses a CIDR or a single address, e.g. `203.0.113.0/24` or `203.0.113.7`.
+pub fn parse_ip_network(value: &str) -> Option<IpNet> {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .ok()
+}
+
 pub fn build_hostname(key: &str) -> (String, String) {
     let config = get_config();
     let extension_subdomain = &config.extension_subdomain;
@@ -38,9 +195,66 @@ pub fn build_hostname(key: &str) -> (String, String) {
 }
 
 pub async fn build_api_key(crd: &BlockfrostPort) -> Result<String, Error> {
-    let config = get_config();
+    build_api_key_with_salt(crd, &get_config().api_key_salt).await
+}
+
+/// Port key derived with one of the configured salts, for ports issued with a previous one.
+pub async fn build_api_key_with_salt(crd: &BlockfrostPort, salt: &str) -> Result<String, Error> {
+    let namespace = crd.namespace().unwrap();
+    let name = format!("blockfrost-auth-{}", &crd.name_any());
+
+    // Rotation 0 keeps the keys issued before rotation existed.
+    let password = match crd.spec.rotation {
+        Some(rotation) if rotation > 0 => format!("{name}{namespace}{rotation}"),
+        _ => format!("{name}{namespace}"),
+    };
+
+    encode_api_key(crd, &password, salt)
+}
+
+/// Named keys are derived from the key name and its `rotation`, so bumping the rotation of a
+/// key mints a new one while renaming it issues an unrelated key.
+pub async fn build_named_api_key(crd: &BlockfrostPort, key_name: &str) -> Result<String, Error> {
+    build_named_api_key_with_salt(crd, key_name, &get_config().api_key_salt).await
+}
 
+pub async fn build_named_api_key_with_salt(
+    crd: &BlockfrostPort,
+    key_name: &str,
+    salt: &str,
+) -> Result<String, Error> {
     let namespace = crd.namespace().unwrap();
+    let name = format!("blockfrost-auth-{}", &crd.name_any());
+
+    let rotation = crd
+        .spec
+        .keys
+        .iter()
+        .flatten()
+        .find(|key| key.name == key_name)
+        .and_then(|key| key.rotation);
+
+    // Rotation 0 keeps the keys issued before named keys could be rotated.
+    let password = match rotation {
+        Some(rotation) if rotation > 0 => format!("{name}{namespace}:{key_name}:{rotation}"),
+        _ => format!("{name}{namespace}:{key_name}"),
+    };
+
+    encode_api_key(crd, &password, salt)
+}
+
+/// Fingerprint of a salt published on the port status to tell which salt issued its keys.
+/// It is derived like the keys, so it is no easier to reverse than the keys themselves.
+pub fn salt_id(salt: &str) -> String {
+    let mut output = vec![0; 8];
+    let _ =
+        Argon2::default().hash_password_into(b"blockfrost-salt-id", salt.as_bytes(), &mut output);
+    hex::encode(output)
+}
+
+fn encode_api_key(crd: &BlockfrostPort, password: &str, salt: &str) -> Result<String, Error> {
+    let config = get_config();
+
     let network = &crd.spec.network;
     let version = crd
         .spec
@@ -48,17 +262,12 @@ pub async fn build_api_key(crd: &BlockfrostPort) -> Result<String, Error> {
         .clone()
         .unwrap_or(config.default_blockfrost_version.to_string());
 
-    let name = format!("blockfrost-auth-{}", &crd.name_any());
-
-    let password = format!("{name}{namespace}").as_bytes().to_vec();
-
-    let config = get_config();
-    let salt = config.api_key_salt.as_bytes();
+    let salt = salt.as_bytes();
 
     let mut output = vec![0; 8];
 
     let argon2 = Argon2::default();
-    let _ = argon2.hash_password_into(password.as_slice(), salt, &mut output);
+    let _ = argon2.hash_password_into(password.as_bytes(), salt, &mut output);
 
     let base64 = general_purpose::URL_SAFE_NO_PAD.encode(output);
     let with_bech = bech32::encode(
@@ -72,41 +281,180 @@ pub async fn build_api_key(crd: &BlockfrostPort) -> Result<String, Error> {
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use std::env;
 
-    use crate::BlockfrostPortSpec;
+    use crate::{BlockfrostPortKey, BlockfrostPortSpec, Config};
 
     use super::*;
 
-    fn set_configs() {
+    /// Env of the config shared by the tests, `get_config` is only built once.
+    pub(crate) fn set_configs() {
         env::set_var("DNS_ZONE", "dns_zone");
         env::set_var("EXTENSION_SUBDOMAIN", "extension_subdomain");
         env::set_var("API_KEY_SALT", "api_key_salt");
+        env::set_var("API_KEY_SALTS", "*api_key_salt,old_api_key_salt");
+        env::set_var("API_KEY_HASH_SECRET", "api_key_hash_secret");
         env::set_var("METRICS_DELAY", "100");
         env::set_var("PROMETHEUS_URL", "prometheus_url");
         env::set_var("DEFAULT_BLOCKFROST_VERSION", "v1");
     }
 
+    /// Config of the tests, read from the env of `set_configs`.
+    pub(crate) fn config() -> Config {
+        set_configs();
+        Config::from_env()
+    }
+
+    /// Valid port spec, tests set the fields they check with the struct update syntax.
+    pub(crate) fn spec() -> BlockfrostPortSpec {
+        BlockfrostPortSpec {
+            operator_version: "1".into(),
+            network: "preview".into(),
+            throughput_tier: "0".into(),
+            ..Default::default()
+        }
+    }
+
+    fn port(spec: BlockfrostPortSpec) -> BlockfrostPort {
+        let mut crd = BlockfrostPort::new("", spec);
+        crd.metadata.namespace = Some("namespace".to_string());
+        crd
+    }
+
     #[tokio::test]
     async fn test_build_api_key() {
         set_configs();
-        let mut crd = BlockfrostPort::new(
-            "",
-            BlockfrostPortSpec {
-                operator_version: "1".to_string(),
-                network: "preview".to_string(),
-                throughput_tier: "0".to_string(),
-                blockfrost_version: Some("v1".to_string()),
-                auth_token: None,
-            },
-        );
-        crd.metadata.namespace = Some("namespace".to_string());
+        let crd = port(BlockfrostPortSpec {
+            blockfrost_version: Some("v1".to_string()),
+            ..spec()
+        });
 
         let api_key = build_api_key(&crd).await.unwrap();
         assert!(api_key.starts_with("dmtr_blockfrost_v1_preview_"));
         assert!(api_key.len() <= 63);
     }
+
+    #[tokio::test]
+    async fn test_build_api_key_rotation() {
+        set_configs();
+        let mut crd = port(spec());
+        let api_key = build_api_key(&crd).await.unwrap();
+
+        crd.spec.rotation = Some(0);
+        assert_eq!(build_api_key(&crd).await.unwrap(), api_key);
+
+        crd.spec.rotation = Some(1);
+        let rotated_key = build_api_key(&crd).await.unwrap();
+        assert!(rotated_key.starts_with("dmtr_blockfrost_v1_preview_"));
+        assert_ne!(rotated_key, api_key);
+    }
+
+    #[tokio::test]
+    async fn test_build_named_api_key() {
+        set_configs();
+        let mut crd = port(spec());
+        let api_key = build_api_key(&crd).await.unwrap();
+
+        let ci_key = build_named_api_key(&crd, "ci").await.unwrap();
+        let backend_key = build_named_api_key(&crd, "backend").await.unwrap();
+        assert!(ci_key.starts_with("dmtr_blockfrost_v1_preview_"));
+        assert_ne!(ci_key, backend_key);
+        assert_ne!(ci_key, api_key);
+        assert_eq!(build_named_api_key(&crd, "ci").await.unwrap(), ci_key);
+
+        crd.spec.keys = Some(vec![BlockfrostPortKey {
+            name: "ci".into(),
+            rotation: Some(1),
+            ..Default::default()
+        }]);
+        let rotated_ci_key = build_named_api_key(&crd, "ci").await.unwrap();
+        assert_ne!(rotated_ci_key, ci_key);
+        assert_eq!(
+            build_named_api_key(&crd, "backend").await.unwrap(),
+            backend_key
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_api_key_with_salt() {
+        set_configs();
+        let crd = port(spec());
+        let api_key = build_api_key(&crd).await.unwrap();
+        let ci_key = build_named_api_key(&crd, "ci").await.unwrap();
+
+        let current_salt = &get_config().api_key_salt;
+        let salted_key = build_api_key_with_salt(&crd, current_salt).await.unwrap();
+        assert_eq!(salted_key, api_key);
+        let old_key = build_api_key_with_salt(&crd, "old_api_key_salt")
+            .await
+            .unwrap();
+        assert_ne!(old_key, api_key);
+        let old_ci_key = build_named_api_key_with_salt(&crd, "ci", "old_api_key_salt")
+            .await
+            .unwrap();
+        assert_ne!(old_ci_key, ci_key);
+    }
+
+    #[tokio::test]
+    async fn test_parse_api_key() {
+        set_configs();
+        let crd = port(BlockfrostPortSpec {
+            network: "cardano-preview".to_string(),
+            blockfrost_version: Some("v1".to_string()),
+            ..spec()
+        });
+
+        let api_key = build_api_key(&crd).await.unwrap();
+        let prefix = parse_api_key(&api_key).unwrap();
+        assert_eq!(prefix.version, "v1");
+        assert_eq!(prefix.network, "cardano-preview");
+
+        let mut tampered = api_key.clone();
+        tampered.pop();
+        tampered.push(if api_key.ends_with('q') { 'p' } else { 'q' });
+        assert!(parse_api_key(&tampered).is_none());
+        assert!(parse_api_key("dmtr_blockfrost_v1_preview_").is_none());
+        assert!(parse_api_key("random").is_none());
+    }
+
+    #[test]
+    fn test_hash_api_key() {
+        let key = "dmtr_blockfrost_v1_preview_ashjdcnoasdj";
+        let hash = hash_api_key(key, "secret");
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, hash_api_key(key, "secret"));
+        assert_ne!(hash, hash_api_key(key, "other-secret"));
+        assert_ne!(
+            hash,
+            hash_api_key("dmtr_blockfrost_v1_preview_ashjdcnoasdk", "secret")
+        );
+
+        // The legacy unkeyed hash is still recognized.
+        assert_ne!(hash, legacy_hash_api_key(key));
+        assert!(is_api_key_hash(key, "secret", &hash));
+        assert!(is_api_key_hash(key, "secret", &legacy_hash_api_key(key)));
+        assert!(!is_api_key_hash(key, "other-secret", &hash));
+
+        // RFC 4231 test case 2.
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_parse_ip_network() {
+        let network = parse_ip_network("203.0.113.0/24").unwrap();
+        assert!(network.contains(&"203.0.113.7".parse::<IpAddr>().unwrap()));
+
+        let address = parse_ip_network("2001:db8::1").unwrap();
+        assert_eq!(address.prefix_len(), 128);
+
+        assert!(parse_ip_network("203.0.113.0/33").is_none());
+        assert!(parse_ip_network("example.com").is_none());
+    }
+
     #[tokio::test]
     async fn test_build_hostname() {
         set_configs();
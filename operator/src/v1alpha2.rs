@@ -0,0 +1,292 @@
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+    CustomResourceConversion, CustomResourceDefinition, ServiceReference, WebhookClientConfig,
+    WebhookConversion,
+};
+use kube::{core::crd::merge_crds, CustomResource, CustomResourceExt, Resource};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::{
+    controller as v1alpha1, BlockfrostEndpointPolicy, BlockfrostPortKey, BlockfrostPortStatus,
+    Error,
+};
+
+/// Version kept in etcd. v1alpha2 objects are converted by the conversion webhook.
+pub static STORAGE_VERSION: &str = "v1alpha1";
+
+/// Service and cert-manager Certificate of the operator webhook server, as deployed by
+/// bootstrap/feature/webhook.tf.
+pub static WEBHOOK_SERVICE: &str = "blockfrost-operator";
+pub static WEBHOOK_CERTIFICATE: &str = "blockfrost-operator-webhook";
+pub const WEBHOOK_PORT: i32 = 9443;
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(
+    kind = "BlockfrostPort",
+    group = "demeter.run",
+    version = "v1alpha2",
+    shortname = "bfpts",
+    category = "demeter-port",
+    namespaced
+)]
+#[kube(status = "BlockfrostPortStatus")]
+#[kube(printcolumn = r#"
+        {"name": "Network", "jsonPath": ".spec.network", "type": "string"},
+        {"name": "Throughput Tier", "jsonPath":".spec.throughputTier", "type": "string"},
+        {"name": "Endpoint URL", "jsonPath": ".status.endpointUrl", "type": "string"},
+        {"name": "Auth Secret", "jsonPath": ".status.secretName", "type": "string"},
+        {"name": "Ready", "jsonPath": ".status.conditions[?(@.type==\"Ready\")].status", "type": "string"},
+        {"name": "Suspended", "jsonPath": ".spec.suspended", "type": "boolean"}
+    "#)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockfrostPortSpec {
+    pub operator_version: String,
+    /// Network name from the networks configured on the operator, e.g. `cardano-mainnet`.
+    pub network: String,
+    /// Tier name from the tiers configured on the operator and the proxy.
+    pub throughput_tier: String,
+    pub blockfrost_version: Option<String>,
+    #[serde(default)]
+    pub auth: BlockfrostPortAuth,
+    /// Hostnames serving the port besides the extension hostname, authenticated as the port key.
+    #[serde(default)]
+    pub custom_domains: Vec<String>,
+    /// Origins allowed to call the port from a browser, or `*` for any origin.
+    pub allowed_origins: Option<Vec<String>>,
+    /// Client addresses allowed to use the port, as CIDRs or single addresses.
+    pub allowed_ips: Option<Vec<String>>,
+    /// Endpoints the port can call, besides the tier policy.
+    pub endpoints: Option<BlockfrostEndpointPolicy>,
+    /// Rejects the port requests without deleting it, so its keys are kept.
+    pub suspended: Option<bool>,
+    /// Reason returned to the consumer while the port is suspended.
+    pub suspension_reason: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockfrostPortAuth {
+    /// Fixed key for the port instead of the generated one.
+    pub token: Option<String>,
+    /// Bump to mint a new key. The previous key is accepted during the grace period.
+    pub rotation: Option<u32>,
+    /// Additional named keys sharing the port tier limits.
+    #[serde(default)]
+    pub keys: Vec<BlockfrostPortKey>,
+}
+
+impl From<v1alpha1::BlockfrostPortSpec> for BlockfrostPortSpec {
+    fn from(spec: v1alpha1::BlockfrostPortSpec) -> Self {
+        Self {
+            operator_version: spec.operator_version,
+            network: spec.network,
+            throughput_tier: spec.throughput_tier,
+            blockfrost_version: spec.blockfrost_version,
+            auth: BlockfrostPortAuth {
+                token: spec.auth_token,
+                rotation: spec.rotation,
+                keys: spec.keys.unwrap_or_default(),
+            },
+            custom_domains: spec.custom_domains.unwrap_or_default(),
+            allowed_origins: spec.allowed_origins,
+            allowed_ips: spec.allowed_ips,
+            endpoints: spec.endpoints,
+            suspended: spec.suspended,
+            suspension_reason: spec.suspension_reason,
+        }
+    }
+}
+
+impl From<BlockfrostPortSpec> for v1alpha1::BlockfrostPortSpec {
+    fn from(spec: BlockfrostPortSpec) -> Self {
+        Self {
+            operator_version: spec.operator_version,
+            network: spec.network,
+            throughput_tier: spec.throughput_tier,
+            blockfrost_version: spec.blockfrost_version,
+            auth_token: spec.auth.token,
+            rotation: spec.auth.rotation,
+            keys: (!spec.auth.keys.is_empty()).then_some(spec.auth.keys),
+            custom_domains: (!spec.custom_domains.is_empty()).then_some(spec.custom_domains),
+            allowed_origins: spec.allowed_origins,
+            allowed_ips: spec.allowed_ips,
+            endpoints: spec.endpoints,
+            suspended: spec.suspended,
+            suspension_reason: spec.suspension_reason,
+        }
+    }
+}
+
+/// CRD serving both versions, with v1alpha1 as the storage version.
+pub fn blockfrost_port_crd() -> CustomResourceDefinition {
+    merge_crds(
+        vec![v1alpha1::BlockfrostPort::crd(), BlockfrostPort::crd()],
+        STORAGE_VERSION,
+    )
+    .unwrap()
+}
+
+/// Makes the API server convert ports with the webhook server of the operator in
+/// `namespace`. cert-manager injects the caBundle from the webhook certificate.
+pub fn with_conversion_webhook(
+    mut crd: CustomResourceDefinition,
+    namespace: &str,
+) -> CustomResourceDefinition {
+    crd.metadata.annotations = Some(BTreeMap::from([(
+        "cert-manager.io/inject-ca-from".into(),
+        format!("{namespace}/{WEBHOOK_CERTIFICATE}"),
+    )]));
+    crd.spec.conversion = Some(CustomResourceConversion {
+        strategy: "Webhook".into(),
+        webhook: Some(WebhookConversion {
+            conversion_review_versions: vec!["v1".into()],
+            client_config: Some(WebhookClientConfig {
+                service: Some(ServiceReference {
+                    name: WEBHOOK_SERVICE.into(),
+                    namespace: namespace.into(),
+                    path: Some("/convert".into()),
+                    port: Some(WEBHOOK_PORT),
+                }),
+                ..Default::default()
+            }),
+        }),
+    });
+    crd
+}
+
+/// Converts a BlockfrostPort object to the desired apiVersion. Only the spec
+/// changes between versions, metadata and status are kept as they are.
+pub fn convert_object(mut object: Value, desired_api_version: &str) -> Result<Value, Error> {
+    let api_version = object["apiVersion"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    if api_version == desired_api_version {
+        return Ok(object);
+    }
+
+    let v1alpha1 = v1alpha1::BlockfrostPort::api_version(&());
+    let v1alpha2 = BlockfrostPort::api_version(&());
+    let spec = object["spec"].take();
+
+    let spec = if api_version == v1alpha1 && desired_api_version == v1alpha2 {
+        let spec: v1alpha1::BlockfrostPortSpec = serde_json::from_value(spec)?;
+        serde_json::to_value(BlockfrostPortSpec::from(spec))?
+    } else if api_version == v1alpha2 && desired_api_version == v1alpha1 {
+        let spec: BlockfrostPortSpec = serde_json::from_value(spec)?;
+        serde_json::to_value(v1alpha1::BlockfrostPortSpec::from(spec))?
+    } else {
+        return Err(Error::ConversionError(format!(
+            "unsupported conversion from {api_version} to {desired_api_version}"
+        )));
+    };
+
+    object["spec"] = spec;
+    object["apiVersion"] = desired_api_version.into();
+    Ok(object)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn v1alpha1_object() -> Value {
+        json!({
+            "apiVersion": "demeter.run/v1alpha1",
+            "kind": "BlockfrostPort",
+            "metadata": { "name": "blockfrost-port-a123ds", "namespace": "prj-mainnet-test" },
+            "spec": {
+                "operatorVersion": "1",
+                "network": "cardano-mainnet",
+                "throughputTier": "0",
+                "blockfrostVersion": null,
+                "authToken": null,
+                "rotation": 2,
+                "keys": [{ "name": "ci", "expiresAt": null, "rotation": 1 }],
+                "customDomains": ["api.example.com"],
+                "allowedOrigins": ["https://app.example.com"],
+                "allowedIps": ["203.0.113.0/24"],
+                "endpoints": { "allow": null, "deny": ["^/tx/submit$"], "readOnly": true },
+                "suspended": true,
+                "suspensionReason": "unpaid invoice"
+            },
+            "status": { "endpointUrl": "https://mainnet.blockfrost.demeter.run" }
+        })
+    }
+
+    #[test]
+    fn test_convert_object() {
+        let object = convert_object(v1alpha1_object(), "demeter.run/v1alpha2").unwrap();
+        assert_eq!(object["apiVersion"], "demeter.run/v1alpha2");
+        assert_eq!(object["spec"]["network"], "cardano-mainnet");
+        assert_eq!(object["spec"]["auth"]["rotation"], 2);
+        assert_eq!(object["spec"]["auth"]["keys"][0]["name"], "ci");
+        assert_eq!(object["spec"]["auth"]["keys"][0]["rotation"], 1);
+        assert_eq!(object["spec"]["customDomains"][0], "api.example.com");
+        assert_eq!(
+            object["spec"]["allowedOrigins"][0],
+            "https://app.example.com"
+        );
+        assert_eq!(object["status"], v1alpha1_object()["status"]);
+
+        let object = convert_object(object, "demeter.run/v1alpha1").unwrap();
+        assert_eq!(object, v1alpha1_object());
+    }
+
+    #[test]
+    fn test_convert_object_network() {
+        // Any network round-trips, NETWORKS is only checked by the operator validation.
+        let mut v1alpha1 = v1alpha1_object();
+        v1alpha1["spec"]["network"] = "vector-testnet".into();
+        let object = convert_object(v1alpha1.clone(), "demeter.run/v1alpha2").unwrap();
+        assert_eq!(object["spec"]["network"], "vector-testnet");
+
+        let object = convert_object(object, "demeter.run/v1alpha1").unwrap();
+        assert_eq!(object, v1alpha1);
+    }
+
+    #[test]
+    fn test_convert_object_errors() {
+        assert!(matches!(
+            convert_object(v1alpha1_object(), "demeter.run/v1beta1"),
+            Err(Error::ConversionError(_))
+        ));
+    }
+
+    #[test]
+    fn test_blockfrost_port_crd() {
+        let crd = blockfrost_port_crd();
+        let versions: Vec<_> = crd
+            .spec
+            .versions
+            .iter()
+            .map(|v| (v.name.as_str(), v.storage))
+            .collect();
+        assert_eq!(versions, vec![("v1alpha1", true), ("v1alpha2", false)]);
+    }
+
+    #[test]
+    fn test_with_conversion_webhook() {
+        let crd = with_conversion_webhook(blockfrost_port_crd(), "ext-cardano-blockfrost");
+        let conversion = crd.spec.conversion.unwrap();
+        assert_eq!(conversion.strategy, "Webhook");
+
+        let service = conversion
+            .webhook
+            .unwrap()
+            .client_config
+            .unwrap()
+            .service
+            .unwrap();
+        assert_eq!(service.namespace, "ext-cardano-blockfrost");
+        assert_eq!(service.path.as_deref(), Some("/convert"));
+        assert_eq!(
+            crd.metadata.annotations.unwrap()["cert-manager.io/inject-ca-from"],
+            "ext-cardano-blockfrost/blockfrost-operator-webhook"
+        );
+    }
+}
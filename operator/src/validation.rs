@@ -0,0 +1,503 @@
+use chrono::{DateTime, Utc};
+use kube::ResourceExt;
+use regex::Regex;
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use crate::{
+    parse_api_key, parse_ip_network, BlockfrostEndpointPolicy, BlockfrostPort, BlockfrostPortSpec,
+    BlockfrostTierSpec, Config,
+};
+
+pub static CONDITION_READY: &str = "Ready";
+pub static CONDITION_INVALID_NETWORK: &str = "InvalidNetwork";
+pub static CONDITION_UNKNOWN_TIER: &str = "UnknownTier";
+pub static CONDITION_INVALID_KEY: &str = "InvalidKey";
+pub static CONDITION_INVALID_VERSION: &str = "InvalidVersion";
+pub static CONDITION_INVALID_AUTH_TOKEN: &str = "InvalidAuthToken";
+pub static CONDITION_INVALID_CUSTOM_DOMAIN: &str = "InvalidCustomDomain";
+pub static CONDITION_CUSTOM_DOMAIN_CONFLICT: &str = "CustomDomainConflict";
+pub static CONDITION_INVALID_ALLOWED_IP: &str = "InvalidAllowedIp";
+pub static CONDITION_INVALID_ENDPOINT_POLICY: &str = "InvalidEndpointPolicy";
+
+/// Condition types reported besides Ready, one for each kind of validation error.
+pub static ERROR_CONDITIONS: [&str; 9] = [
+    CONDITION_INVALID_NETWORK,
+    CONDITION_UNKNOWN_TIER,
+    CONDITION_INVALID_KEY,
+    CONDITION_INVALID_VERSION,
+    CONDITION_INVALID_AUTH_TOKEN,
+    CONDITION_INVALID_CUSTOM_DOMAIN,
+    CONDITION_CUSTOM_DOMAIN_CONFLICT,
+    CONDITION_INVALID_ALLOWED_IP,
+    CONDITION_INVALID_ENDPOINT_POLICY,
+];
+
+/// Name used for the port main key, so it can't be used by a named key.
+pub static DEFAULT_KEY_NAME: &str = "default";
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    #[error("network {0} is not supported")]
+    InvalidNetwork(String),
+
+    #[error("throughput tier {0} is not configured")]
+    UnknownTier(String),
+
+    #[error("key {0} is invalid: {1}")]
+    InvalidKey(String, String),
+
+    #[error("blockfrost version {0} is not supported")]
+    InvalidVersion(String),
+
+    #[error("auth token is invalid: {0}")]
+    InvalidAuthToken(String),
+
+    #[error("custom domain {0} is invalid: {1}")]
+    InvalidCustomDomain(String, String),
+
+    #[error("custom domain {0} is already used by port {1}")]
+    CustomDomainConflict(String, String),
+
+    #[error("allowed ip {0} is not a CIDR or an address")]
+    InvalidAllowedIp(String),
+
+    #[error("endpoint pattern {0} is not a valid regex")]
+    InvalidEndpointPolicy(String),
+}
+
+impl ValidationError {
+    /// Condition type reported on the port status when this error is found.
+    pub fn condition_type(&self) -> &'static str {
+        match self {
+            ValidationError::InvalidNetwork(_) => CONDITION_INVALID_NETWORK,
+            ValidationError::UnknownTier(_) => CONDITION_UNKNOWN_TIER,
+            ValidationError::InvalidKey(_, _) => CONDITION_INVALID_KEY,
+            ValidationError::InvalidVersion(_) => CONDITION_INVALID_VERSION,
+            ValidationError::InvalidAuthToken(_) => CONDITION_INVALID_AUTH_TOKEN,
+            ValidationError::InvalidCustomDomain(_, _) => CONDITION_INVALID_CUSTOM_DOMAIN,
+            ValidationError::CustomDomainConflict(_, _) => CONDITION_CUSTOM_DOMAIN_CONFLICT,
+            ValidationError::InvalidAllowedIp(_) => CONDITION_INVALID_ALLOWED_IP,
+            ValidationError::InvalidEndpointPolicy(_) => CONDITION_INVALID_ENDPOINT_POLICY,
+        }
+    }
+}
+
+pub fn validate_spec(spec: &BlockfrostPortSpec, config: &Config) -> Vec<ValidationError> {
+    validate_spec_with_tiers(spec, config, &config.tiers, &BTreeMap::new())
+}
+
+/// Validates a spec against the given tiers instead of TIERS, e.g. the BlockfrostTier
+/// objects of the cluster, and against the custom domains already claimed by other ports.
+pub fn validate_spec_with_tiers(
+    spec: &BlockfrostPortSpec,
+    config: &Config,
+    tiers: &[String],
+    claimed_domains: &BTreeMap<String, String>,
+) -> Vec<ValidationError> {
+    let mut errors = vec![];
+
+    if !config.networks.contains(&spec.network) {
+        errors.push(ValidationError::InvalidNetwork(spec.network.clone()));
+    }
+
+    if !tiers.contains(&spec.throughput_tier) {
+        errors.push(ValidationError::UnknownTier(spec.throughput_tier.clone()));
+    }
+
+    let version = spec
+        .blockfrost_version
+        .clone()
+        .unwrap_or(config.default_blockfrost_version.clone());
+    if !config.blockfrost_versions.contains(&version) {
+        errors.push(ValidationError::InvalidVersion(version.clone()));
+    }
+
+    if let Some(auth_token) = &spec.auth_token {
+        match parse_api_key(auth_token) {
+            None => errors.push(ValidationError::InvalidAuthToken(
+                "must be a bech32 key with the dmtr_blockfrost_{version}_{network}_ prefix".into(),
+            )),
+            Some(prefix) if prefix.version != version || prefix.network != spec.network => errors
+                .push(ValidationError::InvalidAuthToken(format!(
+                    "prefix is for {} {}, expected {version} {}",
+                    prefix.version, prefix.network, spec.network
+                ))),
+            Some(_) => {}
+        }
+    }
+
+    let keys = spec.keys.clone().unwrap_or_default();
+    for (i, key) in keys.iter().enumerate() {
+        let invalid = |reason: &str| ValidationError::InvalidKey(key.name.clone(), reason.into());
+
+        if !is_valid_key_name(&key.name) {
+            errors.push(invalid(
+                "name must be lowercase alphanumeric characters or '-'",
+            ));
+        } else if key.name == DEFAULT_KEY_NAME {
+            errors.push(invalid("name is reserved for the port key"));
+        } else if keys[..i].iter().any(|k| k.name == key.name) {
+            errors.push(invalid("name is duplicated"));
+        }
+
+        if let Some(expires_at) = &key.expires_at {
+            if DateTime::parse_from_rfc3339(expires_at).is_err() {
+                errors.push(invalid("expiresAt must be a RFC 3339 date"));
+            }
+        }
+    }
+
+    // Hostnames under the extension are routed by key, they can't be claimed by a port.
+    let extension_hostname = format!(".{}.{}", config.extension_subdomain, config.dns_zone);
+    let domains = spec.custom_domains.clone().unwrap_or_default();
+    for (i, domain) in domains.iter().enumerate() {
+        let invalid =
+            |reason: &str| ValidationError::InvalidCustomDomain(domain.clone(), reason.into());
+
+        if format!(".{domain}").ends_with(&extension_hostname) {
+            errors.push(invalid("must not be under the extension hostname"));
+        } else if !is_valid_hostname(domain) {
+            errors.push(invalid("must be a lowercase fully qualified hostname"));
+        } else if domains[..i].contains(domain) {
+            errors.push(invalid("domain is duplicated"));
+        } else if let Some(owner) = claimed_domains.get(domain) {
+            errors.push(ValidationError::CustomDomainConflict(
+                domain.clone(),
+                owner.clone(),
+            ));
+        }
+    }
+
+    for ip in spec.allowed_ips.iter().flatten() {
+        if parse_ip_network(ip).is_none() {
+            errors.push(ValidationError::InvalidAllowedIp(ip.clone()));
+        }
+    }
+
+    for pattern in spec.endpoints.iter().flat_map(invalid_endpoint_patterns) {
+        errors.push(ValidationError::InvalidEndpointPolicy(pattern));
+    }
+
+    errors
+}
+
+/// Order in which ports claim custom domains. A domain in several ports belongs to the port
+/// created first, ties broken by namespace and name, so the operator and the proxy agree on
+/// its owner.
+pub fn domain_claim_order(crd: &BlockfrostPort) -> (Option<DateTime<Utc>>, String, String) {
+    (
+        crd.metadata.creation_timestamp.as_ref().map(|time| time.0),
+        crd.namespace().unwrap_or_default(),
+        crd.name_any(),
+    )
+}
+
+/// Custom domains of the ports that claimed them before `crd`, with the owner port.
+pub fn claimed_custom_domains<'a>(
+    ports: impl IntoIterator<Item = &'a BlockfrostPort>,
+    crd: &BlockfrostPort,
+) -> BTreeMap<String, String> {
+    let order = domain_claim_order(crd);
+    let mut earlier: Vec<_> = ports
+        .into_iter()
+        .map(|port| (domain_claim_order(port), port))
+        .filter(|(port_order, _)| *port_order < order)
+        .collect();
+    earlier.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut claimed = BTreeMap::new();
+    for ((_, namespace, name), port) in earlier {
+        for domain in port.spec.custom_domains.iter().flatten() {
+            claimed
+                .entry(domain.clone())
+                .or_insert_with(|| format!("{namespace}/{name}"));
+        }
+    }
+    claimed
+}
+
+/// Validates a BlockfrostTier as the proxy loads it, so a tier the proxy would drop is
+/// rejected at apply time.
+pub fn validate_tier_spec(spec: &BlockfrostTierSpec) -> Vec<String> {
+    let mut errors = vec![];
+
+    let interval = Regex::new(r"^\d+[smhd]$").unwrap();
+    for rate in &spec.rates {
+        if !interval.is_match(&rate.interval) {
+            errors.push(format!(
+                "rate interval {} must be a number followed by s, m, h or d",
+                rate.interval
+            ));
+        }
+        if rate.limit < 1 || isize::try_from(rate.limit).is_err() {
+            errors.push(format!("rate limit {} is out of range", rate.limit));
+        }
+    }
+
+    for pattern in spec.endpoints.iter().flat_map(invalid_endpoint_patterns) {
+        errors.push(format!("endpoint pattern {pattern} is not a valid regex"));
+    }
+
+    errors
+}
+
+fn invalid_endpoint_patterns(endpoints: &BlockfrostEndpointPolicy) -> Vec<String> {
+    endpoints
+        .allow
+        .iter()
+        .chain(endpoints.deny.iter())
+        .flatten()
+        .filter(|pattern| Regex::new(pattern).is_err())
+        .cloned()
+        .collect()
+}
+
+/// Fixed auth tokens set before they had to be keys, e.g. random strings. The proxy only
+/// accepts them for ports that were already serving one.
+pub fn is_legacy_auth_token(token: &str) -> bool {
+    parse_api_key(token).is_none()
+}
+
+/// Drops the auth token error of a legacy token the port already serves, so upgrading the
+/// operator doesn't revoke it. Changing the token requires a valid key.
+pub fn grandfather_auth_token(
+    errors: &mut Vec<ValidationError>,
+    spec: &BlockfrostPortSpec,
+    served: bool,
+) {
+    if served && spec.auth_token.as_deref().is_some_and(is_legacy_auth_token) {
+        errors.retain(|error| !matches!(error, ValidationError::InvalidAuthToken(_)));
+    }
+}
+
+fn is_valid_hostname(hostname: &str) -> bool {
+    hostname.len() <= 253
+        && hostname.split('.').count() >= 2
+        && hostname.split('.').all(is_valid_key_name)
+}
+
+fn is_valid_key_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 63
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+#[cfg(test)]
+mod test {
+    use bech32::ToBase32;
+
+    use crate::utils::test::{config, spec};
+    use crate::{BlockfrostPortKey, BlockfrostTierRate};
+
+    use super::*;
+
+    #[test]
+    fn test_valid_spec() {
+        assert!(validate_spec(
+            &BlockfrostPortSpec {
+                network: "cardano-mainnet".into(),
+                ..spec()
+            },
+            &config()
+        )
+        .is_empty());
+        assert!(validate_spec(
+            &BlockfrostPortSpec {
+                throughput_tier: "1".into(),
+                ..spec()
+            },
+            &config()
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_invalid_spec() {
+        let errors = validate_spec(
+            &BlockfrostPortSpec {
+                network: "cardano-testnet".into(),
+                throughput_tier: "9".into(),
+                ..spec()
+            },
+            &config(),
+        );
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError::InvalidNetwork("cardano-testnet".into()),
+                ValidationError::UnknownTier("9".into()),
+            ]
+        );
+        assert_eq!(errors[0].condition_type(), CONDITION_INVALID_NETWORK);
+        assert_eq!(errors[1].condition_type(), CONDITION_UNKNOWN_TIER);
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        let key = |name: &str, expires_at: Option<&str>| BlockfrostPortKey {
+            name: name.into(),
+            expires_at: expires_at.map(|v| v.into()),
+            rotation: None,
+        };
+
+        let mut spec = spec();
+        spec.keys = Some(vec![
+            key("ci", Some("2030-01-01T00:00:00Z")),
+            key("backend", None),
+        ]);
+        assert!(validate_spec(&spec, &config()).is_empty());
+
+        spec.keys = Some(vec![
+            key("ci", None),
+            key("ci", None),
+            key("Partner", None),
+            key(DEFAULT_KEY_NAME, None),
+            key("backend", Some("tomorrow")),
+        ]);
+        let errors = validate_spec(&spec, &config());
+        assert_eq!(errors.len(), 4);
+        assert!(errors
+            .iter()
+            .all(|e| e.condition_type() == CONDITION_INVALID_KEY));
+    }
+
+    #[test]
+    fn test_invalid_custom_domains() {
+        let mut spec = spec();
+        spec.custom_domains = Some(vec!["api.example.com".into(), "cardano.io".into()]);
+        assert!(validate_spec(&spec, &config()).is_empty());
+
+        spec.custom_domains = Some(vec![
+            "localhost".into(),
+            "API.example.com".into(),
+            "key.extension_subdomain.dns_zone".into(),
+            "cardano.io".into(),
+            "cardano.io".into(),
+        ]);
+        let errors = validate_spec(&spec, &config());
+        assert_eq!(errors.len(), 4);
+        assert!(errors
+            .iter()
+            .all(|e| e.condition_type() == CONDITION_INVALID_CUSTOM_DOMAIN));
+        assert!(errors[2].to_string().contains("extension hostname"));
+    }
+
+    #[test]
+    fn test_invalid_endpoint_policy() {
+        let mut spec = spec();
+        spec.endpoints = Some(BlockfrostEndpointPolicy {
+            allow: Some(vec!["^/blocks".into()]),
+            deny: Some(vec!["^/tx/submit$".into(), "^/pools/(".into()]),
+            read_only: Some(true),
+        });
+        let errors = validate_spec(&spec, &config());
+        assert_eq!(
+            errors,
+            vec![ValidationError::InvalidEndpointPolicy("^/pools/(".into())]
+        );
+    }
+
+    #[test]
+    fn test_invalid_allowed_ips() {
+        let mut spec = spec();
+        spec.allowed_ips = Some(vec!["203.0.113.0/24".into(), "2001:db8::1".into()]);
+        assert!(validate_spec(&spec, &config()).is_empty());
+
+        spec.allowed_ips = Some(vec!["203.0.113.0/33".into(), "10.0.0.1".into()]);
+        let errors = validate_spec(&spec, &config());
+        assert_eq!(
+            errors,
+            vec![ValidationError::InvalidAllowedIp("203.0.113.0/33".into())]
+        );
+    }
+
+    #[test]
+    fn test_invalid_version_and_auth_token() {
+        let mut spec = spec();
+        spec.blockfrost_version = Some("v2".into());
+        spec.auth_token = Some("not-a-key".into());
+        let errors = validate_spec(&spec, &config());
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0], ValidationError::InvalidVersion("v2".into()));
+        assert_eq!(errors[1].condition_type(), CONDITION_INVALID_AUTH_TOKEN);
+
+        // Key with the prefix of another network.
+        spec.blockfrost_version = None;
+        let key = |network: &str| {
+            bech32::encode(
+                &format!("dmtr_blockfrost_v1_{network}_"),
+                b"key".to_base32(),
+                bech32::Variant::Bech32,
+            )
+            .unwrap()
+        };
+        spec.auth_token = Some(key("cardano-mainnet"));
+        let errors = validate_spec(&spec, &config());
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().contains("cardano-mainnet"));
+
+        spec.auth_token = Some(key("preview"));
+        assert!(validate_spec(&spec, &config()).is_empty());
+    }
+
+    #[test]
+    fn test_validate_spec_with_tiers() {
+        let tiers = vec!["starter".to_string(), "pro".to_string()];
+        let claimed = BTreeMap::new();
+        assert!(validate_spec_with_tiers(
+            &BlockfrostPortSpec {
+                throughput_tier: "pro".into(),
+                ..spec()
+            },
+            &config(),
+            &tiers,
+            &claimed
+        )
+        .is_empty());
+        assert_eq!(
+            validate_spec_with_tiers(&spec(), &config(), &tiers, &claimed),
+            vec![ValidationError::UnknownTier("0".into())]
+        );
+    }
+
+    #[test]
+    fn test_validate_tier_spec() {
+        let tier = |limit: i64, interval: &str, pattern: &str| BlockfrostTierSpec {
+            rates: vec![BlockfrostTierRate {
+                limit,
+                interval: interval.into(),
+            }],
+            monthly_quota: None,
+            endpoints: Some(BlockfrostEndpointPolicy {
+                deny: Some(vec![pattern.into()]),
+                ..Default::default()
+            }),
+        };
+
+        assert!(validate_tier_spec(&tier(5, "1s", "^/tx/submit$")).is_empty());
+        assert_eq!(validate_tier_spec(&tier(5, "1w", "^/tx")).len(), 1);
+        assert_eq!(validate_tier_spec(&tier(0, "1s", "^/tx")).len(), 1);
+        assert_eq!(validate_tier_spec(&tier(5, "1s", "^/tx(")).len(), 1);
+    }
+
+    #[test]
+    fn test_grandfather_auth_token() {
+        let mut spec = spec();
+        spec.auth_token = Some("legacy-token".into());
+
+        let mut errors = validate_spec(&spec, &config());
+        grandfather_auth_token(&mut errors, &spec, false);
+        assert_eq!(errors.len(), 1);
+
+        // Only the auth token error of a served legacy token is dropped.
+        spec.throughput_tier = "9".into();
+        let mut errors = validate_spec(&spec, &config());
+        grandfather_auth_token(&mut errors, &spec, true);
+        assert_eq!(errors, vec![ValidationError::UnknownTier("9".into())]);
+    }
+}
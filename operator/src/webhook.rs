@@ -0,0 +1,486 @@
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::{
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use kube::{
+    core::{
+        admission::{AdmissionRequest, AdmissionResponse, AdmissionReview},
+        conversion::{ConversionRequest, ConversionResponse, ConversionReview},
+        DynamicObject, Status,
+    },
+    Resource,
+};
+use std::{
+    collections::BTreeMap, fs::File, io::BufReader, net::SocketAddr, str::FromStr, sync::Arc,
+};
+use tokio::net::TcpListener;
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    TlsAcceptor,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    get_config, grandfather_auth_token,
+    v1alpha2::{self, convert_object},
+    validate_spec_with_tiers, validate_tier_spec, BlockfrostPortSpec, BlockfrostTier, Config,
+    Error, State, ValidationError,
+};
+
+/// Runs the validating admission and CRD conversion webhooks when WEBHOOK_ADDR
+/// is configured. The API server only calls webhooks over https, so the server
+/// is always TLS.
+pub fn run_webhook_server(state: Arc<State>) {
+    let config = get_config();
+    let Some(addr) = config.webhook_addr.clone() else {
+        info!("webhook addr not configured, admission webhook disabled");
+        return;
+    };
+
+    tokio::spawn(async move {
+        let addr_result = SocketAddr::from_str(&addr);
+        if let Err(err) = addr_result {
+            error!(error = err.to_string(), "invalid webhook addr");
+            std::process::exit(1);
+        }
+        let addr = addr_result.unwrap();
+
+        let tls_result = load_tls_config(&config.webhook_cert_path, &config.webhook_key_path);
+        if let Err(err) = tls_result {
+            error!(error = err.to_string(), "fail to load webhook certificates");
+            std::process::exit(1);
+        }
+        let acceptor = TlsAcceptor::from(Arc::new(tls_result.unwrap()));
+
+        let listener_result = TcpListener::bind(addr).await;
+        if let Err(err) = listener_result {
+            error!(
+                error = err.to_string(),
+                "fail to bind tcp webhook server listener"
+            );
+            std::process::exit(1);
+        }
+        let listener = listener_result.unwrap();
+
+        info!(addr = addr.to_string(), "webhook listening");
+
+        loop {
+            let accept_result = listener.accept().await;
+            if let Err(err) = accept_result {
+                error!(error = err.to_string(), "accept client webhook server");
+                continue;
+            }
+            let (stream, _) = accept_result.unwrap();
+            let acceptor = acceptor.clone();
+            let state = state.clone();
+
+            tokio::task::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!(error = err.to_string(), "failed webhook tls handshake");
+                        return;
+                    }
+                };
+                let io = TokioIo::new(stream);
+                let service = service_fn(move |req| api_webhook(req, state.clone()));
+
+                if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                    error!(error = err.to_string(), "failed webhook server connection");
+                }
+            });
+        }
+    });
+}
+
+fn load_tls_config(cert_path: &str, key_path: &str) -> Result<ServerConfig, Error> {
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| Error::ConfigError(format!("{path}: {err}")))
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .map_err(|err| Error::ConfigError(format!("{cert_path}: {err}")))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let key = rustls_pemfile::read_all(&mut open(key_path)?)
+        .map_err(|err| Error::ConfigError(format!("{key_path}: {err}")))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or(Error::ConfigError(format!("{key_path}: no private key")))?;
+
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| Error::ConfigError(err.to_string()))
+}
+
+async fn api_webhook(
+    req: Request<Incoming>,
+    state: Arc<State>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let body = req.into_body().collect().await?.to_bytes();
+    Ok(handle_webhook(&method, &path, &body, &state))
+}
+
+/// Answers a webhook request. Every replica serves the webhooks, whether it leads or not,
+/// since the API server can't read v1alpha2 ports without the conversion.
+fn handle_webhook(
+    method: &Method,
+    path: &str,
+    body: &[u8],
+    state: &State,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    if method != Method::POST {
+        return response(StatusCode::NOT_FOUND, "not found".into());
+    }
+
+    let config = get_config();
+    let review = match path {
+        "/validate" => {
+            serde_json::from_slice::<AdmissionReview<DynamicObject>>(body).map(|review| {
+                serde_json::to_string(&validate_admission(
+                    review,
+                    config,
+                    state.known_tiers(config).as_deref(),
+                ))
+            })
+        }
+        "/convert" => serde_json::from_slice::<ConversionReview>(body)
+            .map(|review| serde_json::to_string(&convert_review(review))),
+        _ => return response(StatusCode::NOT_FOUND, "not found".into()),
+    };
+
+    match review {
+        Ok(Ok(body)) => response(StatusCode::OK, body),
+        Ok(Err(err)) => response(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+        Err(err) => response(StatusCode::BAD_REQUEST, err.to_string()),
+    }
+}
+
+fn response(status: StatusCode, body: String) -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(
+            Full::new(body.into())
+                .map_err(|never| match never {})
+                .boxed(),
+        )
+        .unwrap()
+}
+
+/// Validates a BlockfrostPort admission request with the same rules the
+/// controller uses, denying it with the validation messages.
+pub fn validate_admission(
+    review: AdmissionReview<DynamicObject>,
+    config: &Config,
+    tiers: Option<&[String]>,
+) -> AdmissionReview<DynamicObject> {
+    let request: AdmissionRequest<DynamicObject> = match review.try_into() {
+        Ok(request) => request,
+        Err(err) => return AdmissionResponse::invalid(err.to_string()).into_review(),
+    };
+
+    let response = AdmissionResponse::from(&request);
+    let Some(object) = &request.object else {
+        return response.into_review();
+    };
+
+    if request.kind.kind == BlockfrostTier::kind(&()) {
+        let errors = match serde_json::from_value(object.data["spec"].clone()) {
+            Ok(spec) => validate_tier_spec(&spec),
+            Err(err) => vec![format!("spec is invalid: {err}")],
+        };
+        return match errors.is_empty() {
+            true => response.into_review(),
+            false => response.deny(errors.join(", ")).into_review(),
+        };
+    }
+
+    let parse_spec = |object: &DynamicObject| {
+        let spec = object.data["spec"].clone();
+        if request.kind.version == "v1alpha2" {
+            serde_json::from_value::<v1alpha2::BlockfrostPortSpec>(spec)
+                .map(BlockfrostPortSpec::from)
+        } else {
+            serde_json::from_value::<BlockfrostPortSpec>(spec)
+        }
+    };
+
+    let errors: Vec<String> = match parse_spec(object) {
+        Ok(spec) => {
+            // Other ports aren't listed at admission, domain conflicts are reported on the status.
+            let claimed = BTreeMap::new();
+            let mut errors =
+                validate_spec_with_tiers(&spec, config, tiers.unwrap_or_default(), &claimed);
+            // Until the tiers are listed, the tier is only checked by the controller.
+            if tiers.is_none() {
+                errors.retain(|error| !matches!(error, ValidationError::UnknownTier(_)));
+            }
+            // Updates that keep the auth token of the port don't have to fix a legacy token.
+            let previous = request
+                .old_object
+                .as_ref()
+                .and_then(|old| parse_spec(old).ok());
+            let served = previous.is_some_and(|previous| previous.auth_token == spec.auth_token);
+            grandfather_auth_token(&mut errors, &spec, served);
+            errors.iter().map(|e| e.to_string()).collect()
+        }
+        Err(err) => vec![format!("spec is invalid: {err}")],
+    };
+
+    if errors.is_empty() {
+        response.into_review()
+    } else {
+        response.deny(errors.join(", ")).into_review()
+    }
+}
+
+/// Converts BlockfrostPort objects between the served versions. The response
+/// fails as a whole if any object can't be converted.
+pub fn convert_review(review: ConversionReview) -> ConversionReview {
+    let request = match ConversionRequest::from_review(review) {
+        Ok(request) => request,
+        Err(err) => {
+            return ConversionResponse::invalid(Status::failure(&err.to_string(), "InvalidRequest"))
+                .into_review()
+        }
+    };
+
+    let converted: Result<Vec<_>, Error> = request
+        .objects
+        .iter()
+        .map(|object| convert_object(object.clone(), &request.desired_api_version))
+        .collect();
+
+    let response = ConversionResponse::for_request(request);
+    match converted {
+        Ok(objects) => response.success(objects),
+        Err(err) => response.failure(Status::failure(&err.to_string(), "ConversionFailed")),
+    }
+    .into_review()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::test::config;
+
+    fn review(spec: serde_json::Value) -> AdmissionReview<DynamicObject> {
+        let mut review: serde_json::Value =
+            serde_json::from_str(include_str!("../fixtures/admission-review.json")).unwrap();
+        review["request"]["object"]["spec"] = spec;
+        serde_json::from_value(review).unwrap()
+    }
+
+    #[test]
+    fn test_validate_admission() {
+        let config = config();
+        let allowed = validate_admission(
+            review(serde_json::json!({
+                "operatorVersion": "1",
+                "network": "mainnet",
+                "throughputTier": "0"
+            })),
+            &config,
+            Some(&config.tiers),
+        );
+        assert!(allowed.response.unwrap().allowed);
+
+        let denied = validate_admission(
+            review(serde_json::json!({
+                "operatorVersion": "1",
+                "network": "cardano-testnet",
+                "throughputTier": "9"
+            })),
+            &config,
+            Some(&config.tiers),
+        )
+        .response
+        .unwrap();
+        assert!(!denied.allowed);
+        assert!(denied.result.message.contains("cardano-testnet"));
+        assert!(denied.result.message.contains("tier 9"));
+
+        let malformed = validate_admission(
+            review(serde_json::json!({ "network": "mainnet" })),
+            &config,
+            Some(&config.tiers),
+        )
+        .response
+        .unwrap();
+        assert!(!malformed.allowed);
+    }
+
+    #[test]
+    fn test_validate_admission_tier_objects() {
+        // Tiers are checked against the BlockfrostTier objects when there are some.
+        let spec = serde_json::json!({
+            "operatorVersion": "1",
+            "network": "mainnet",
+            "throughputTier": "0"
+        });
+        let denied = validate_admission(review(spec), &config(), Some(&["starter".to_string()]))
+            .response
+            .unwrap();
+        assert!(!denied.allowed);
+        assert!(denied.result.message.contains("tier 0"));
+    }
+
+    #[test]
+    fn test_validate_admission_v1alpha2() {
+        let config = config();
+        let mut review: serde_json::Value =
+            serde_json::from_str(include_str!("../fixtures/admission-review.json")).unwrap();
+        review["request"]["kind"]["version"] = "v1alpha2".into();
+        review["request"]["object"]["spec"] = serde_json::json!({
+            "operatorVersion": "1",
+            "network": "preview",
+            "throughputTier": "9",
+            "auth": { "keys": [{ "name": "ci" }] }
+        });
+        let denied = validate_admission(
+            serde_json::from_value(review).unwrap(),
+            &config,
+            Some(&config.tiers),
+        )
+        .response
+        .unwrap();
+        assert!(!denied.allowed);
+        assert!(denied.result.message.contains("tier 9"));
+    }
+
+    #[test]
+    fn test_validate_admission_tier() {
+        let tier = |limit: i64| {
+            let mut review: serde_json::Value =
+                serde_json::from_str(include_str!("../fixtures/admission-review.json")).unwrap();
+            review["request"]["kind"]["kind"] = "BlockfrostTier".into();
+            review["request"]["object"]["spec"] = serde_json::json!({
+                "rates": [{ "limit": limit, "interval": "1s" }],
+                "endpoints": { "deny": ["^/tx/submit$"] }
+            });
+            serde_json::from_value(review).unwrap()
+        };
+
+        let allowed = validate_admission(tier(5), &config(), None);
+        assert!(allowed.response.unwrap().allowed);
+
+        let denied = validate_admission(tier(0), &config(), None)
+            .response
+            .unwrap();
+        assert!(!denied.allowed);
+        assert!(denied.result.message.contains("rate limit 0"));
+    }
+
+    #[test]
+    fn test_validate_admission_before_tiers() {
+        // Until the tiers are listed, the tier is left to the controller.
+        let spec = serde_json::json!({
+            "operatorVersion": "1",
+            "network": "mainnet",
+            "throughputTier": "9"
+        });
+        let allowed = validate_admission(review(spec), &config(), None);
+        assert!(allowed.response.unwrap().allowed);
+    }
+
+    #[test]
+    fn test_validate_admission_legacy_auth_token() {
+        let config = config();
+        // A legacy auth token is only accepted on updates that keep it.
+        let spec = serde_json::json!({
+            "operatorVersion": "1",
+            "network": "mainnet",
+            "throughputTier": "0",
+            "authToken": "legacy-token"
+        });
+        let denied = validate_admission(review(spec.clone()), &config, Some(&config.tiers))
+            .response
+            .unwrap();
+        assert!(!denied.allowed);
+
+        let mut update: serde_json::Value =
+            serde_json::from_str(include_str!("../fixtures/admission-review.json")).unwrap();
+        update["request"]["operation"] = "UPDATE".into();
+        update["request"]["object"]["spec"] = spec;
+        update["request"]["oldObject"] = update["request"]["object"].clone();
+        let allowed = validate_admission(
+            serde_json::from_value(update).unwrap(),
+            &config,
+            Some(&config.tiers),
+        );
+        assert!(allowed.response.unwrap().allowed);
+    }
+
+    #[tokio::test]
+    async fn test_convert_before_leadership() {
+        crate::utils::test::set_configs();
+        // A standby replica that hasn't listed the tiers yet.
+        let state = State::default();
+        assert_eq!(state.metrics.leader.get(), 0);
+
+        let body = include_str!("../fixtures/conversion-review.json");
+        let response = handle_webhook(&Method::POST, "/convert", body.as_bytes(), &state);
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let review: ConversionReview = serde_json::from_slice(&body).unwrap();
+        assert!(review.response.unwrap().result.is_success());
+
+        let response = handle_webhook(&Method::GET, "/convert", &[], &state);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_convert_review() {
+        let review: ConversionReview =
+            serde_json::from_str(include_str!("../fixtures/conversion-review.json")).unwrap();
+        let response = convert_review(review).response.unwrap();
+        assert!(response.result.is_success());
+        assert_eq!(response.converted_objects.len(), 2);
+        assert!(response
+            .converted_objects
+            .iter()
+            .all(|o| o["apiVersion"] == "demeter.run/v1alpha2"));
+        assert_eq!(response.converted_objects[0]["spec"]["auth"]["rotation"], 1);
+
+        // Networks outside the legacy names are converted too.
+        let mut review: serde_json::Value =
+            serde_json::from_str(include_str!("../fixtures/conversion-review.json")).unwrap();
+        review["request"]["objects"][0]["spec"]["network"] = "vector-testnet".into();
+        let response = convert_review(serde_json::from_value(review).unwrap())
+            .response
+            .unwrap();
+        assert!(response.result.is_success());
+        assert_eq!(
+            response.converted_objects[0]["spec"]["network"],
+            "vector-testnet"
+        );
+
+        let mut review: serde_json::Value =
+            serde_json::from_str(include_str!("../fixtures/conversion-review.json")).unwrap();
+        review["request"]["desiredAPIVersion"] = "demeter.run/v1beta1".into();
+        let response = convert_review(serde_json::from_value(review).unwrap())
+            .response
+            .unwrap();
+        assert!(response.result.is_failure());
+        assert!(response.converted_objects.is_empty());
+    }
+}
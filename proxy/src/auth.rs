@@ -1,9 +1,11 @@
 use std::{collections::HashMap, sync::Arc};
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use futures_util::TryStreamExt;
 
 use operator::{
+    domain_claim_order, is_legacy_auth_token,
     kube::{
         runtime::watcher::{self, Config as ConfigWatcher, Event},
         Api, Client, ResourceExt,
@@ -16,9 +18,99 @@ use pingora::{
 };
 use tokio::pin;
 use tokio::time::{sleep, Duration};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-use crate::{Consumer, State};
+use crate::keys::keyed_hash;
+use crate::{Consumer, CustomDomain, PortUsage, State};
+
+fn has_auth_token(crd: &BlockfrostPort) -> bool {
+    crd.status
+        .as_ref()
+        .is_some_and(|status| !status.auth_token_hash.is_empty())
+}
+
+fn parse_expires_at(value: &Option<String>) -> Option<DateTime<Utc>> {
+    value
+        .as_ref()
+        .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+        .map(|v| v.with_timezone(&Utc))
+}
+
+/// Consumers for every key the port accepts: the port key, a rotated key still in its grace
+/// period, the named keys and the replaced named keys still in their grace period.
+fn port_consumers(crd: &BlockfrostPort) -> Vec<Consumer> {
+    let consumer = Consumer::from(crd);
+    let mut consumers = vec![];
+
+    let status = crd.status.as_ref().unwrap();
+    let previous_expires_at = parse_expires_at(&status.previous_auth_token_expires_at);
+    if let (Some(key_hash), Some(expires_at)) =
+        (&status.previous_auth_token_hash, previous_expires_at)
+    {
+        let key_hash = keyed_hash(key_hash);
+        if expires_at > Utc::now() && key_hash != consumer.key_hash {
+            consumers.push(Consumer {
+                key_hash,
+                expires_at: Some(expires_at),
+                ..consumer.clone()
+            });
+        }
+    }
+
+    for key in status.keys.iter().chain(status.previous_keys.iter()) {
+        consumers.push(Consumer {
+            key_hash: keyed_hash(&key.auth_token_hash),
+            key_name: key.name.clone(),
+            expires_at: parse_expires_at(&key.expires_at),
+            ..consumer.clone()
+        });
+    }
+
+    consumers.push(consumer);
+    consumers
+}
+
+/// Usage published by the operator for the quota period.
+fn port_usage(crd: &BlockfrostPort) -> Option<PortUsage> {
+    let usage = crd.status.as_ref()?.usage.as_ref()?;
+    Some(PortUsage {
+        period: usage.period.clone(),
+        requests: usage.requests,
+    })
+}
+
+/// Adds the claims of the custom domains published on the port status. A domain claimed by
+/// several ports belongs to the port created first, the one the operator accepts the domain
+/// on.
+fn insert_port_domains(domains: &mut HashMap<String, Vec<CustomDomain>>, crd: &BlockfrostPort) {
+    let status = crd.status.as_ref().unwrap();
+    let port = format!("{}.{}", crd.namespace().unwrap_or_default(), crd.name_any());
+
+    for domain in status.custom_domains.iter() {
+        let claims = domains.entry(domain.clone()).or_default();
+        claims.push(CustomDomain {
+            port: port.clone(),
+            key_hash: keyed_hash(&status.auth_token_hash),
+            claim_order: domain_claim_order(crd),
+        });
+        claims.sort_by(|a, b| a.claim_order.cmp(&b.claim_order));
+        if claims[0].port != port {
+            warn!(
+                domain,
+                owner = claims[0].port,
+                "auth: custom domain already in use"
+            );
+        }
+    }
+}
+
+/// Removes the claims of a port, so the next claimer of each domain becomes its owner.
+fn remove_port_domains(domains: &mut HashMap<String, Vec<CustomDomain>>, port: &str) {
+    for claims in domains.values_mut() {
+        claims.retain(|claim| claim.port != port);
+    }
+    domains.retain(|_, claims| !claims.is_empty());
+}
 
 pub struct AuthBackgroundService {
     state: Arc<State>,
@@ -27,6 +119,68 @@ impl AuthBackgroundService {
     pub fn new(state: Arc<State>) -> Self {
         Self { state }
     }
+
+    async fn remove_port(&self, crd: &BlockfrostPort) {
+        let namespace = crd.namespace().unwrap_or_default();
+        let port_name = crd.name_any();
+        let port = format!("{namespace}.{port_name}");
+
+        self.state
+            .consumers
+            .write()
+            .await
+            .retain(|_, consumer| !consumer.is_port(&namespace, &port_name));
+        self.state.limiter.write().await.remove(&port);
+        self.state.quotas.write().await.remove(&port);
+        remove_port_domains(&mut *self.state.domains.write().await, &port);
+    }
+
+    async fn update_port(&self, crd: &BlockfrostPort) {
+        let namespace = crd.namespace().unwrap_or_default();
+        let port_name = crd.name_any();
+        let port = format!("{namespace}.{port_name}");
+        let tier = &crd.spec.throughput_tier;
+
+        // Ports reconciled by previous operators still carry the token on the spec.
+        let legacy_auth_token = crd
+            .status
+            .as_ref()
+            .is_some_and(|status| status.legacy_auth_token);
+        if legacy_auth_token
+            || crd
+                .spec
+                .auth_token
+                .as_deref()
+                .is_some_and(is_legacy_auth_token)
+        {
+            self.state.set_legacy_keys();
+        }
+
+        // Ports are updated on every usage report, so the limiter is only reset when the tier
+        // changes.
+        let mut consumers = self.state.consumers.write().await;
+        let tier_changed = consumers
+            .values()
+            .any(|consumer| consumer.is_port(&namespace, &port_name) && consumer.tier != *tier);
+        consumers.retain(|_, consumer| !consumer.is_port(&namespace, &port_name));
+        for consumer in port_consumers(crd) {
+            consumers.insert(consumer.key_hash.clone(), consumer);
+        }
+        drop(consumers);
+
+        if tier_changed {
+            self.state.limiter.write().await.remove(&port);
+        }
+
+        let mut domains = self.state.domains.write().await;
+        remove_port_domains(&mut domains, &port);
+        insert_port_domains(&mut domains, crd);
+        drop(domains);
+
+        if let Some(usage) = port_usage(crd) {
+            self.state.quotas.write().await.insert(port, usage);
+        }
+    }
 }
 
 #[async_trait]
@@ -61,13 +215,24 @@ impl BackgroundService for AuthBackgroundService {
                     info!("auth: Watcher restarted, reseting consumers");
                     let consumers: HashMap<String, Consumer> = crds
                         .iter()
-                        .map(|crd| {
-                            let consumer = Consumer::from(crd);
-                            (consumer.key.clone(), consumer)
-                        })
+                        .filter(|crd| has_auth_token(crd))
+                        .flat_map(port_consumers)
+                        .map(|consumer| (consumer.key_hash.clone(), consumer))
                         .collect();
                     *self.state.consumers.write().await = consumers;
                     self.state.limiter.write().await.clear();
+                    let mut domains = HashMap::new();
+                    for crd in crds.iter().filter(|crd| has_auth_token(crd)) {
+                        insert_port_domains(&mut domains, crd);
+                    }
+                    *self.state.domains.write().await = domains;
+                    *self.state.quotas.write().await = crds
+                        .iter()
+                        .filter_map(|crd| {
+                            let port = format!("{}.{}", crd.namespace()?, crd.name_any());
+                            Some((port, port_usage(crd)?))
+                        })
+                        .collect();
 
                     if !is_ready {
                         self.state.set_auth_ready();
@@ -77,32 +242,23 @@ impl BackgroundService for AuthBackgroundService {
                     }
                 }
                 // New port created or updated.
-                Ok(Some(Event::Applied(crd))) => match crd.status {
-                    Some(_) => {
-                        info!("auth: Updating consumer: {}", crd.name_any());
-                        let consumer = Consumer::from(&crd);
-                        self.state.limiter.write().await.remove(&consumer.key);
-                        self.state
-                            .consumers
-                            .write()
-                            .await
-                            .insert(consumer.key.clone(), consumer);
-                    }
-                    None => {
-                        // New ports are created without status. When the status is added, a new
-                        // Applied event is triggered.
-                        info!("auth: New port created: {}", crd.name_any());
-                    }
-                },
+                Ok(Some(Event::Applied(crd))) if has_auth_token(&crd) => {
+                    info!("auth: Updating consumer: {}", crd.name_any());
+                    self.update_port(&crd).await;
+                }
+                // New ports are created without status, and invalid ports have their keys
+                // cleared. When the key is added, a new Applied event is triggered.
+                Ok(Some(Event::Applied(crd))) => {
+                    info!("auth: Port without key: {}", crd.name_any());
+                    self.remove_port(&crd).await;
+                }
                 // Port deleted.
                 Ok(Some(Event::Deleted(crd))) => {
                     info!(
                         "auth: Port deleted, removing from state: {}",
                         crd.name_any()
                     );
-                    let consumer = Consumer::from(&crd);
-                    self.state.consumers.write().await.remove(&consumer.key);
-                    self.state.limiter.write().await.remove(&consumer.key);
+                    self.remove_port(&crd).await;
                 }
                 // Empty response from stream. Should never happen.
                 Ok(None) => {
@@ -118,3 +274,229 @@ impl BackgroundService for AuthBackgroundService {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use operator::{
+        k8s_openapi::apimachinery::pkg::apis::meta::v1::Time, BlockfrostPortKeyStatus,
+        BlockfrostPortSpec, BlockfrostPortStatus, BlockfrostPortUsage, DEFAULT_KEY_NAME,
+    };
+
+    use super::*;
+
+    fn port(status: BlockfrostPortStatus) -> BlockfrostPort {
+        let mut crd = BlockfrostPort::new(
+            "port",
+            BlockfrostPortSpec {
+                operator_version: "1".into(),
+                network: "preview".into(),
+                throughput_tier: "0".into(),
+                rotation: Some(1),
+                ..Default::default()
+            },
+        );
+        crd.metadata.namespace = Some("prj-test".into());
+        crd.status = Some(status);
+        crd
+    }
+
+    #[test]
+    fn test_port_consumers_with_previous_key() {
+        let expires_at = Utc::now() + chrono::Duration::try_hours(1).unwrap();
+        let crd = port(BlockfrostPortStatus {
+            auth_token_hash: "new".into(),
+            previous_auth_token_hash: Some("old".into()),
+            previous_auth_token_expires_at: Some(expires_at.to_rfc3339()),
+            ..Default::default()
+        });
+
+        let consumers = port_consumers(&crd);
+        assert_eq!(consumers.len(), 2);
+        assert_eq!(consumers[0].key_hash, keyed_hash("old"));
+        assert!(consumers[0].expires_at.is_some());
+        assert_eq!(consumers[1].key_hash, keyed_hash("new"));
+        assert!(consumers[1].expires_at.is_none());
+        assert_eq!(consumers[0].to_string(), consumers[1].to_string());
+    }
+
+    #[test]
+    fn test_port_consumers_with_expired_previous_key() {
+        let expires_at = Utc::now() - chrono::Duration::try_hours(1).unwrap();
+        let crd = port(BlockfrostPortStatus {
+            auth_token_hash: "new".into(),
+            previous_auth_token_hash: Some("old".into()),
+            previous_auth_token_expires_at: Some(expires_at.to_rfc3339()),
+            ..Default::default()
+        });
+
+        let consumers = port_consumers(&crd);
+        assert_eq!(consumers.len(), 1);
+        assert_eq!(consumers[0].key_hash, keyed_hash("new"));
+    }
+
+    #[test]
+    fn test_port_consumers_with_named_keys() {
+        let crd = port(BlockfrostPortStatus {
+            auth_token_hash: "port".into(),
+            keys: vec![
+                BlockfrostPortKeyStatus {
+                    name: "ci".into(),
+                    auth_token_hash: "ci-key".into(),
+                    expires_at: None,
+                },
+                BlockfrostPortKeyStatus {
+                    name: "partner".into(),
+                    auth_token_hash: "partner-key".into(),
+                    expires_at: Some("2020-01-01T00:00:00Z".into()),
+                },
+            ],
+            previous_keys: vec![BlockfrostPortKeyStatus {
+                name: "ci".into(),
+                auth_token_hash: "old-ci-key".into(),
+                expires_at: Some(
+                    (Utc::now() + chrono::Duration::try_hours(1).unwrap()).to_rfc3339(),
+                ),
+            }],
+            ..Default::default()
+        });
+
+        let consumers = port_consumers(&crd);
+        assert_eq!(consumers.len(), 4);
+        assert_eq!(consumers[0].key_name, "ci");
+        assert!(!consumers[0].is_expired(Utc::now()));
+        assert_eq!(consumers[1].key_name, "partner");
+        assert!(consumers[1].is_expired(Utc::now()));
+        assert_eq!(consumers[2].key_name, "ci");
+        assert_eq!(consumers[2].key_hash, keyed_hash("old-ci-key"));
+        assert!(!consumers[2].is_expired(Utc::now()));
+        assert_eq!(consumers[3].key_name, DEFAULT_KEY_NAME);
+        assert!(consumers.iter().all(|c| c.to_string() == "prj-test.port"));
+    }
+
+    #[test]
+    fn test_suspended_port_consumers() {
+        let mut crd = port(BlockfrostPortStatus {
+            auth_token_hash: "port".into(),
+            keys: vec![BlockfrostPortKeyStatus {
+                name: "ci".into(),
+                auth_token_hash: "ci-key".into(),
+                expires_at: None,
+            }],
+            ..Default::default()
+        });
+        crd.spec.suspended = Some(true);
+        crd.spec.suspension_reason = Some("unpaid invoice".into());
+
+        let consumers = port_consumers(&crd);
+        assert_eq!(consumers.len(), 2);
+        assert!(consumers.iter().all(|consumer| consumer.suspended
+            && consumer.suspension_reason.as_deref() == Some("unpaid invoice")));
+    }
+
+    #[tokio::test]
+    async fn test_update_port_legacy_auth_token() {
+        let state = Arc::new(State::default());
+        let service = AuthBackgroundService::new(state.clone());
+
+        let mut crd = port(BlockfrostPortStatus {
+            auth_token_hash: operator::legacy_hash_api_key("legacy-token"),
+            ..Default::default()
+        });
+        service.update_port(&crd).await;
+        assert!(!state.has_legacy_keys());
+
+        crd.spec.auth_token = Some("legacy-token".into());
+        service.update_port(&crd).await;
+        assert!(state.has_legacy_keys());
+        assert!(state.get_consumer("legacy-token").await.is_some());
+
+        // Once migrated into the Secret, the status tells the token is malformed.
+        let state = Arc::new(State::default());
+        let service = AuthBackgroundService::new(state.clone());
+        crd.spec.auth_token = None;
+        crd.status.as_mut().unwrap().legacy_auth_token = true;
+        service.update_port(&crd).await;
+        assert!(state.has_legacy_keys());
+
+        // The keyed hash the operator publishes once it reconciles the port.
+        crd.status.as_mut().unwrap().auth_token_hash =
+            operator::hash_api_key("legacy-token", &state.api_key_hash_secret);
+        service.update_port(&crd).await;
+        assert!(state.get_consumer("legacy-token").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_update_port_keeps_limiter_and_usage() {
+        let state = Arc::new(State::default());
+        let service = AuthBackgroundService::new(state.clone());
+
+        let mut crd = port(BlockfrostPortStatus {
+            auth_token_hash: "port".into(),
+            ..Default::default()
+        });
+        service.update_port(&crd).await;
+        state
+            .limiter
+            .write()
+            .await
+            .insert("prj-test.port".into(), vec![]);
+
+        crd.status.as_mut().unwrap().usage = Some(BlockfrostPortUsage {
+            period: "2024-01".into(),
+            requests: 10,
+            updated_at: "2024-01-10T00:00:00Z".into(),
+        });
+        service.update_port(&crd).await;
+        assert!(state.limiter.read().await.contains_key("prj-test.port"));
+        assert_eq!(state.quotas.read().await["prj-test.port"].requests, 10);
+
+        crd.spec.throughput_tier = "1".into();
+        service.update_port(&crd).await;
+        assert!(!state.limiter.read().await.contains_key("prj-test.port"));
+        assert_eq!(state.consumers.read().await[&keyed_hash("port")].tier, "1");
+
+        service.remove_port(&crd).await;
+        assert!(state.consumers.read().await.is_empty());
+        assert!(state.quotas.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_update_port_custom_domains() {
+        let state = Arc::new(State::default());
+        let service = AuthBackgroundService::new(state.clone());
+
+        let mut crd = port(BlockfrostPortStatus {
+            auth_token_hash: "port".into(),
+            custom_domains: vec!["api.example.com".into()],
+            ..Default::default()
+        });
+        crd.metadata.creation_timestamp = Some(Time(Utc::now()));
+        service.update_port(&crd).await;
+        let consumer = state.get_consumer_by_domain("api.example.com").await;
+        assert_eq!(consumer.unwrap().key_hash, keyed_hash("port"));
+
+        // A port created later can't take the domain.
+        let mut other = crd.clone();
+        other.metadata.name = Some("other".into());
+        other.metadata.creation_timestamp =
+            Some(Time(Utc::now() + chrono::Duration::try_minutes(1).unwrap()));
+        other.status.as_mut().unwrap().auth_token_hash = "other".into();
+        service.update_port(&other).await;
+        let consumer = state.get_consumer_by_domain("api.example.com").await;
+        assert_eq!(consumer.unwrap().key_hash, keyed_hash("port"));
+
+        // The domain follows the port key when it is rotated.
+        crd.status.as_mut().unwrap().auth_token_hash = "rotated".into();
+        service.update_port(&crd).await;
+        let consumer = state.get_consumer_by_domain("api.example.com").await;
+        assert_eq!(consumer.unwrap().key_hash, keyed_hash("rotated"));
+
+        // Once the owner is removed, the domain goes to the next port claiming it.
+        service.remove_port(&crd).await;
+        let consumer = state.get_consumer_by_domain("api.example.com").await;
+        assert_eq!(consumer.unwrap().key_hash, keyed_hash("other"));
+
+        service.remove_port(&other).await;
+        assert!(state.domains.read().await.is_empty());
+    }
+}
@@ -2,7 +2,12 @@ use std::error::Error;
 use std::{fs, sync::Arc};
 
 use async_trait::async_trait;
+use futures_util::TryStreamExt;
 use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use operator::{
+    kube::{Client, ResourceExt},
+    BlockfrostCacheRule,
+};
 use pingora::{
     server::ShutdownWatch,
     services::{background::BackgroundService, ServiceReadyNotifier},
@@ -10,9 +15,14 @@ use pingora::{
 use regex::Regex;
 use serde::{Deserialize, Deserializer};
 use serde_json::Value;
+use tokio::{pin, time::sleep};
 use tracing::{error, info, warn};
 
-use crate::{config::Config, State};
+use crate::{
+    config::{Config, ConfigSource},
+    resources::watch_resources,
+    State,
+};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct CacheRule {
@@ -32,6 +42,16 @@ impl CacheRule {
         self.endpoint.is_match(uri)
     }
 }
+impl TryFrom<&BlockfrostCacheRule> for CacheRule {
+    type Error = regex::Error;
+
+    fn try_from(crd: &BlockfrostCacheRule) -> Result<Self, Self::Error> {
+        Ok(Self {
+            endpoint: Regex::new(&crd.spec.endpoint)?,
+            duration_s: crd.spec.duration_seconds,
+        })
+    }
+}
 
 pub struct CacheRuleBackgroundService {
     state: Arc<State>,
@@ -60,6 +80,58 @@ impl CacheRuleBackgroundService {
 
         Ok(())
     }
+
+    async fn watch_crds(&self, mut shutdown: ShutdownWatch, ready_notifier: ServiceReadyNotifier) {
+        let client = Client::try_default()
+            .await
+            .expect("failed to create kube client");
+
+        let stream = watch_resources::<BlockfrostCacheRule>(client);
+        pin!(stream);
+        let mut ready_notifier = Some(ready_notifier);
+
+        loop {
+            let result = tokio::select! {
+                _ = shutdown.changed() => {
+                    info!("cache_rules: shutdown requested");
+                    break;
+                }
+                result = stream.try_next() => result,
+            };
+
+            match result {
+                Ok(Some(crds)) => {
+                    // An invalid rule is left out, the others still apply.
+                    let cache_rules = crds
+                        .iter()
+                        .filter_map(|crd| match CacheRule::try_from(crd.as_ref()) {
+                            Ok(cache_rule) => Some(cache_rule),
+                            Err(err) => {
+                                error!(
+                                    error = err.to_string(),
+                                    rule = crd.name_any(),
+                                    "invalid cache_rule"
+                                );
+                                None
+                            }
+                        })
+                        .collect();
+                    *self.state.cache_rules.write().await = cache_rules;
+                    info!("cache_rules modified");
+
+                    if let Some(ready_notifier) = ready_notifier.take() {
+                        self.state.set_cache_rules_ready();
+                        ready_notifier.notify_ready();
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    error!(error = err.to_string(), "error to watch cache_rules");
+                    sleep(std::time::Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -69,6 +141,10 @@ impl BackgroundService for CacheRuleBackgroundService {
         mut shutdown: ShutdownWatch,
         ready_notifier: ServiceReadyNotifier,
     ) {
+        if self.config.config_source == ConfigSource::Kubernetes {
+            return self.watch_crds(shutdown, ready_notifier).await;
+        }
+
         if let Err(err) = self.update_cache_rules().await {
             error!(error = err.to_string(), "error to update cache_rules");
             return;
@@ -140,4 +216,21 @@ mod test {
         assert!(cache_rule.matches("/cacheable/subpath"));
         assert_eq!(cache_rule.duration_s, 42);
     }
+
+    #[test]
+    fn test_from_crd() {
+        let mut crd = BlockfrostCacheRule::new(
+            "epochs",
+            operator::BlockfrostCacheRuleSpec {
+                endpoint: "/epochs.*".into(),
+                duration_seconds: 300,
+            },
+        );
+        let cache_rule = CacheRule::try_from(&crd).unwrap();
+        assert!(cache_rule.matches("/epochs/latest"));
+        assert_eq!(cache_rule.duration_s, 300);
+
+        crd.spec.endpoint = "/epochs(".into();
+        assert!(CacheRule::try_from(&crd).is_err());
+    }
 }
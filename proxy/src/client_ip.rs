@@ -0,0 +1,77 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Address of the client. The socket address is used unless it is a trusted proxy, then
+/// X-Forwarded-For is read from the right skipping the trusted hops, so a client can't
+/// choose its address by sending the header itself.
+pub fn client_ip(
+    socket_ip: Option<IpAddr>,
+    forwarded_for: &str,
+    trusted_proxies: &[IpNet],
+) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|proxy| proxy.contains(ip));
+
+    let mut ip = socket_ip?.to_canonical();
+    let hops = forwarded_for
+        .split(',')
+        .map(str::trim)
+        .filter(|hop| !hop.is_empty())
+        .rev();
+    for hop in hops {
+        if !is_trusted(&ip) {
+            break;
+        }
+        ip = hop.parse::<IpAddr>().ok()?.to_canonical();
+    }
+
+    Some(ip)
+}
+
+pub fn is_allowed(allowed_ips: &[IpNet], ip: Option<IpAddr>) -> bool {
+    ip.is_some_and(|ip| allowed_ips.iter().any(|allowed| allowed.contains(&ip)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(value: &str) -> Option<IpAddr> {
+        Some(value.parse().unwrap())
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+
+        // Untrusted sockets can't forward addresses.
+        let client = client_ip(ip("203.0.113.7"), "198.51.100.1", &trusted);
+        assert_eq!(client, ip("203.0.113.7"));
+
+        // The last hop not added by a trusted proxy is the client.
+        let client = client_ip(
+            ip("10.0.0.1"),
+            "198.51.100.1, 203.0.113.7, 10.0.0.2",
+            &trusted,
+        );
+        assert_eq!(client, ip("203.0.113.7"));
+
+        let client = client_ip(ip("::ffff:10.0.0.1"), "203.0.113.7", &trusted);
+        assert_eq!(client, ip("203.0.113.7"));
+
+        // Without a forwarded address the proxy itself is the client.
+        assert_eq!(client_ip(ip("10.0.0.1"), "", &trusted), ip("10.0.0.1"));
+
+        assert_eq!(client_ip(ip("10.0.0.1"), "unknown", &trusted), None);
+        assert_eq!(client_ip(None, "203.0.113.7", &trusted), None);
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let allowed: Vec<IpNet> = vec!["203.0.113.0/24".parse().unwrap()];
+        assert!(is_allowed(&allowed, ip("203.0.113.7")));
+        assert!(!is_allowed(&allowed, ip("198.51.100.1")));
+        assert!(!is_allowed(&allowed, None));
+        assert!(!is_allowed(&[], ip("203.0.113.7")));
+    }
+}
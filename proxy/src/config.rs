@@ -1,16 +1,33 @@
 use std::{env, path::PathBuf, time::Duration};
 
+use ipnet::IpNet;
+use operator::parse_ip_network;
+
 use crate::endpoints::Endpoint;
 
+/// Where tiers, cache rules and routes are read from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigSource {
+    /// TOML files, reloaded when they change.
+    File,
+    /// BlockfrostTier, BlockfrostCacheRule and BlockfrostRoute objects.
+    Kubernetes,
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
+    pub config_source: ConfigSource,
     pub proxy_addr: String,
     pub proxy_namespace: String,
+    // Key of the hashes the operator publishes on the port status.
+    pub api_key_hash_secret: String,
     pub proxy_tiers_path: PathBuf,
     pub proxy_tiers_poll_interval: Duration,
     pub prometheus_addr: String,
     pub ssl_crt_path: String,
     pub ssl_key_path: String,
+    // Directory with a `{domain}/tls.crt` and `{domain}/tls.key` for each port custom domain
+    pub custom_domains_certs_path: Option<PathBuf>,
     // Dolos settings
     pub dolos_enabled: bool,
 
@@ -27,6 +44,9 @@ pub struct Config {
     // Forbidden endpoints
     pub forbidden_endpoints: Vec<Endpoint>,
 
+    // Load balancers whose X-Forwarded-For entries are trusted for the client address
+    pub trusted_proxies: Vec<IpNet>,
+
     // Health endpoint
     pub health_endpoint: String,
     pub readiness_endpoint: String,
@@ -38,12 +58,21 @@ pub struct Config {
 
 impl Config {
     pub fn new() -> Self {
+        let config_source = match env::var("PROXY_CONFIG_SOURCE").as_deref() {
+            Ok("kubernetes") => ConfigSource::Kubernetes,
+            Ok("file") | Err(_) => ConfigSource::File,
+            Ok(other) => panic!("PROXY_CONFIG_SOURCE must be file or kubernetes, got {other}"),
+        };
+        // The files are only required when the config is read from them.
+        let required = config_source == ConfigSource::File;
+
         Self {
+            config_source,
             proxy_addr: env::var("PROXY_ADDR").expect("PROXY_ADDR must be set"),
             proxy_namespace: env::var("PROXY_NAMESPACE").expect("PROXY_NAMESPACE must be set"),
-            proxy_tiers_path: env::var("PROXY_TIERS_PATH")
-                .map(|v| v.into())
-                .expect("PROXY_TIERS_PATH must be set"),
+            api_key_hash_secret: env::var("API_KEY_HASH_SECRET")
+                .expect("API_KEY_HASH_SECRET must be set"),
+            proxy_tiers_path: path_from_env("PROXY_TIERS_PATH", required),
             proxy_tiers_poll_interval: env::var("PROXY_TIERS_POLL_INTERVAL")
                 .map(|v| {
                     Duration::from_secs(
@@ -55,10 +84,9 @@ impl Config {
             prometheus_addr: env::var("PROMETHEUS_ADDR").expect("PROMETHEUS_ADDR must be set"),
             ssl_crt_path: env::var("SSL_CRT_PATH").expect("SSL_CRT_PATH must be set"),
             ssl_key_path: env::var("SSL_KEY_PATH").expect("SSL_KEY_PATH must be set"),
+            custom_domains_certs_path: env::var("CUSTOM_DOMAINS_CERTS_PATH").ok().map(Into::into),
             dolos_enabled: env::var("DOLOS_ENABLED").unwrap_or("false".to_string()) == "true",
-            cache_rules_path: env::var("CACHE_RULES_PATH")
-                .map(|v| v.into())
-                .expect("CACHE_RULES_PATH must be set"),
+            cache_rules_path: path_from_env("CACHE_RULES_PATH", required),
             cache_db_path: env::var("CACHE_DB_PATH").expect("CACHE_DB_PATH must be set"),
             cache_failed_requests_seconds: env::var("CACHE_FAILED_REQUESTS_SECONDS")
                 .unwrap_or("20".to_string())
@@ -73,9 +101,14 @@ impl Config {
                 .split(',')
                 .map(|endpoint| Endpoint::new(endpoint).expect("Invalid forbidden endpoint regex"))
                 .collect(),
-            routing_config_path: env::var("ROUTING_CONFIG_PATH")
-                .map(|v| v.into())
-                .expect("ROUTING_CONFIG_PATH must be set"),
+            trusted_proxies: env::var("TRUSTED_PROXIES")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(|value| parse_ip_network(value).expect("Invalid trusted proxy CIDR"))
+                .collect(),
+            routing_config_path: path_from_env("ROUTING_CONFIG_PATH", required),
             routing_poll_interval: env::var("ROUTING_POLL_INTERVAL")
                 .ok()
                 .and_then(|v| v.parse::<u64>().ok())
@@ -95,6 +128,14 @@ impl Config {
     }
 }
 
+fn path_from_env(name: &str, required: bool) -> PathBuf {
+    match env::var(name) {
+        Ok(value) => value.into(),
+        Err(_) if !required => PathBuf::new(),
+        Err(_) => panic!("{name} must be set"),
+    }
+}
+
 fn endpoint_from_env(name: &str, default: &str) -> String {
     let value = env::var(name).unwrap_or_else(|_| default.to_string());
     normalize_endpoint(&value)
@@ -127,6 +168,7 @@ mod tests {
         unsafe {
             env::set_var("PROXY_ADDR", "0.0.0.0:8000");
             env::set_var("PROXY_NAMESPACE", "namespace");
+            env::set_var("API_KEY_HASH_SECRET", "api_key_hash_secret");
             env::set_var("PROXY_TIERS_PATH", path);
             env::set_var("PROMETHEUS_ADDR", "0.0.0.0:8001");
             env::set_var("SSL_CRT_PATH", "ssl_crt_path");
@@ -140,6 +182,7 @@ mod tests {
             env::set_var("READINESS_ENDPOINT", "/readyz");
             env::set_var("GRACE_PERIOD_SECONDS", "30");
             env::set_var("GRACEFUL_SHUTDOWN_TIMEOUT_SECONDS", "5");
+            env::set_var("TRUSTED_PROXIES", "10.0.0.0/8, 192.168.1.1");
         }
 
         let config = Config::new();
@@ -150,5 +193,7 @@ mod tests {
         assert_eq!(config.readiness_endpoint, "/readyz");
         assert_eq!(config.grace_period_seconds, 30);
         assert_eq!(config.graceful_shutdown_timeout_seconds, 5);
+        assert_eq!(config.config_source, ConfigSource::File);
+        assert_eq!(config.trusted_proxies.len(), 2);
     }
 }
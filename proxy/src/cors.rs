@@ -0,0 +1,158 @@
+use pingora::http::{Method, RequestHeader, ResponseHeader};
+use pingora::Result;
+
+const ALLOW_METHODS: &str = "GET, POST, OPTIONS";
+const MAX_AGE_SECONDS: &str = "86400";
+const CORS_HEADERS: [&str; 6] = [
+    "access-control-allow-origin",
+    "access-control-allow-credentials",
+    "access-control-allow-methods",
+    "access-control-allow-headers",
+    "access-control-expose-headers",
+    "access-control-max-age",
+];
+
+pub fn request_origin(req: &RequestHeader) -> Option<&str> {
+    req.headers.get("origin").and_then(|v| v.to_str().ok())
+}
+
+pub fn is_preflight(req: &RequestHeader) -> bool {
+    req.method == Method::OPTIONS
+        && request_origin(req).is_some()
+        && req.headers.contains_key("access-control-request-method")
+}
+
+/// Value of `Access-Control-Allow-Origin` for the origin, when the port allows it.
+pub fn allowed_origin<'a>(allowed_origins: &[String], origin: &'a str) -> Option<&'a str> {
+    if allowed_origins.iter().any(|allowed| allowed == "*") {
+        return Some("*");
+    }
+
+    allowed_origins
+        .iter()
+        .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+        .then_some(origin)
+}
+
+/// Answer to a preflight request. Origins are only allowed by an explicit `allowedOrigins`
+/// policy, so preflights of unknown ports, or of ports without a policy, get no CORS headers.
+pub fn preflight_response(
+    req: &RequestHeader,
+    allowed_origins: &[String],
+) -> Result<ResponseHeader> {
+    let mut header = ResponseHeader::build(204, None)?;
+    header.insert_header("vary", "Origin")?;
+    header.insert_header("content-length", "0")?;
+
+    let origin = request_origin(req).unwrap_or_default();
+    let Some(allow_origin) = allowed_origin(allowed_origins, origin) else {
+        return Ok(header);
+    };
+
+    header.insert_header("access-control-allow-origin", allow_origin)?;
+    header.insert_header("access-control-allow-methods", ALLOW_METHODS)?;
+    if let Some(headers) = req.headers.get("access-control-request-headers") {
+        header.insert_header("access-control-allow-headers", headers.clone())?;
+    }
+    header.insert_header("access-control-max-age", MAX_AGE_SECONDS)?;
+    Ok(header)
+}
+
+/// Replaces the upstream CORS headers with the port policy.
+pub fn apply_policy(
+    resp: &mut ResponseHeader,
+    allowed_origins: &[String],
+    origin: Option<&str>,
+) -> Result<()> {
+    for name in CORS_HEADERS {
+        resp.remove_header(name);
+    }
+    resp.append_header("vary", "Origin")?;
+
+    if let Some(allow_origin) = origin.and_then(|origin| allowed_origin(allowed_origins, origin)) {
+        resp.insert_header("access-control-allow-origin", allow_origin)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn preflight(origin: &str) -> RequestHeader {
+        let mut req = RequestHeader::build(Method::OPTIONS, b"/blocks/latest", None).unwrap();
+        req.insert_header("origin", origin).unwrap();
+        req.insert_header("access-control-request-method", "GET")
+            .unwrap();
+        req.insert_header("access-control-request-headers", "dmtr-api-key")
+            .unwrap();
+        req
+    }
+
+    fn header<'a>(resp: &'a ResponseHeader, name: &str) -> Option<&'a str> {
+        resp.headers.get(name).map(|v| v.to_str().unwrap())
+    }
+
+    #[test]
+    fn test_allowed_origin() {
+        let allowed = vec!["https://app.example.com/".to_string()];
+        assert_eq!(
+            allowed_origin(&allowed, "https://app.example.com"),
+            Some("https://app.example.com")
+        );
+        assert_eq!(allowed_origin(&allowed, "https://evil.example.com"), None);
+        assert_eq!(allowed_origin(&[], "https://app.example.com"), None);
+        assert_eq!(
+            allowed_origin(&["*".to_string()], "https://app.example.com"),
+            Some("*")
+        );
+    }
+
+    #[test]
+    fn test_preflight_response() {
+        let req = preflight("https://app.example.com");
+        assert!(is_preflight(&req));
+
+        let allowed = vec!["https://app.example.com".to_string()];
+        let resp = preflight_response(&req, &allowed).unwrap();
+        assert_eq!(resp.status, 204);
+        assert_eq!(
+            header(&resp, "access-control-allow-origin"),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            header(&resp, "access-control-allow-headers"),
+            Some("dmtr-api-key")
+        );
+
+        let resp = preflight_response(&preflight("https://evil.example.com"), &allowed);
+        assert_eq!(header(&resp.unwrap(), "access-control-allow-origin"), None);
+
+        // Unknown ports and ports without a policy don't reflect the origin.
+        let resp = preflight_response(&preflight("https://evil.example.com"), &[]).unwrap();
+        assert_eq!(resp.status, 204);
+        assert_eq!(header(&resp, "access-control-allow-origin"), None);
+        assert_eq!(header(&resp, "access-control-allow-methods"), None);
+    }
+
+    #[test]
+    fn test_apply_policy() {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("access-control-allow-origin", "*")
+            .unwrap();
+        resp.insert_header("access-control-allow-credentials", "true")
+            .unwrap();
+
+        let allowed = vec!["https://app.example.com".to_string()];
+        apply_policy(&mut resp, &allowed, Some("https://evil.example.com")).unwrap();
+        assert_eq!(header(&resp, "access-control-allow-origin"), None);
+        assert_eq!(header(&resp, "access-control-allow-credentials"), None);
+        assert_eq!(header(&resp, "vary"), Some("Origin"));
+
+        apply_policy(&mut resp, &allowed, Some("https://app.example.com")).unwrap();
+        assert_eq!(
+            header(&resp, "access-control-allow-origin"),
+            Some("https://app.example.com")
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use pingora::{
+    listeners::TlsAccept,
+    tls::{
+        error::ErrorStack,
+        ext,
+        pkey::{PKey, Private},
+        ssl::{NameType, SslRef},
+        x509::X509,
+    },
+};
+use tracing::{error, warn};
+
+use crate::State;
+
+/// Certificate chain and key of a custom domain.
+struct DomainCertificate {
+    modified: SystemTime,
+    chain: Vec<X509>,
+    key: PKey<Private>,
+}
+
+/// Certificates read from `{path}/{domain}/tls.crt` and `{path}/{domain}/tls.key`,
+/// read again when the certificate file changes.
+struct CertificateStore {
+    path: PathBuf,
+    certificates: RwLock<HashMap<String, Arc<DomainCertificate>>>,
+}
+impl CertificateStore {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            certificates: Default::default(),
+        }
+    }
+
+    fn get(&self, domain: &str) -> Result<Arc<DomainCertificate>, Box<dyn Error>> {
+        let cert_path = self.path.join(domain).join("tls.crt");
+        let key_path = self.path.join(domain).join("tls.key");
+
+        let modified = fs::metadata(&cert_path)?.modified()?;
+        if let Some(certificate) = self
+            .certificates
+            .read()
+            .unwrap()
+            .get(domain)
+            .filter(|certificate| certificate.modified == modified)
+        {
+            return Ok(certificate.clone());
+        }
+
+        let chain = X509::stack_from_pem(&fs::read(&cert_path)?)?;
+        if chain.is_empty() {
+            return Err(format!("no certificate in {}", cert_path.display()).into());
+        }
+        let key = PKey::private_key_from_pem(&fs::read(&key_path)?)?;
+
+        let certificate = Arc::new(DomainCertificate {
+            modified,
+            chain,
+            key,
+        });
+        self.certificates
+            .write()
+            .unwrap()
+            .insert(domain.to_string(), certificate.clone());
+        Ok(certificate)
+    }
+}
+
+/// Picks the certificate of the custom domain requested with SNI. Other
+/// hostnames, or domains without a certificate, get the default certificate.
+pub struct DomainCertificates {
+    state: Arc<State>,
+    store: CertificateStore,
+}
+impl DomainCertificates {
+    pub fn new(state: Arc<State>, path: PathBuf) -> Self {
+        Self {
+            state,
+            store: CertificateStore::new(path),
+        }
+    }
+}
+
+fn use_certificate(ssl: &mut SslRef, certificate: &DomainCertificate) -> Result<(), ErrorStack> {
+    ext::ssl_use_certificate(ssl, &certificate.chain[0])?;
+    ext::ssl_use_private_key(ssl, &certificate.key)?;
+    for cert in certificate.chain[1..].iter() {
+        ext::ssl_add_chain_cert(ssl, cert)?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl TlsAccept for DomainCertificates {
+    async fn certificate_callback(&self, ssl: &mut SslRef) {
+        let Some(domain) = ssl
+            .servername(NameType::HOST_NAME)
+            .map(|d| d.to_lowercase())
+        else {
+            return;
+        };
+
+        // Only domains published by a port are looked up, so SNI can't point at other files.
+        if !self.state.domains.read().await.contains_key(&domain) {
+            return;
+        }
+
+        match self.store.get(&domain) {
+            Ok(certificate) => {
+                if let Err(err) = use_certificate(ssl, &certificate) {
+                    error!(
+                        domain,
+                        error = err.to_string(),
+                        "error to use domain certificate"
+                    );
+                }
+            }
+            Err(err) => {
+                warn!(
+                    domain,
+                    error = err.to_string(),
+                    "domain certificate not found, using the default"
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use openssl::asn1::Asn1Time;
+    use pingora::tls::{
+        hash::MessageDigest,
+        x509::{X509Builder, X509NameBuilder},
+    };
+
+    use super::*;
+
+    fn write_certificate(dir: &std::path::Path, domain: &str) {
+        let key = PKey::generate_ed25519().unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", domain).unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        let not_before = Asn1Time::days_from_now(0).unwrap();
+        let not_after = Asn1Time::days_from_now(1).unwrap();
+        builder.set_not_before(&not_before).unwrap();
+        builder.set_not_after(&not_after).unwrap();
+        builder.sign(&key, MessageDigest::null()).unwrap();
+
+        let dir = dir.join(domain);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("tls.crt"), builder.build().to_pem().unwrap()).unwrap();
+        fs::write(dir.join("tls.key"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_domain_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = CertificateStore::new(dir.path().into());
+        assert!(store.get("api.example.com").is_err());
+
+        write_certificate(dir.path(), "api.example.com");
+        let certificate = store.get("api.example.com").unwrap();
+        assert_eq!(certificate.chain.len(), 1);
+
+        // Loaded once while the file doesn't change.
+        let cached = store.get("api.example.com").unwrap();
+        assert!(Arc::ptr_eq(&certificate, &cached));
+    }
+}
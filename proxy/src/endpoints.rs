@@ -1,4 +1,25 @@
+use operator::BlockfrostEndpointPolicy;
+use pingora::http::Method;
 use regex::{Error as RegexError, Regex};
+use serde::{Deserialize, Deserializer};
+
+/// Endpoints submitting or evaluating transactions, weighted apart by the usage policy.
+const SUBMIT_ENDPOINTS: [&str; 3] = [
+    "/tx/submit",
+    "/utils/txs/evaluate",
+    "/utils/txs/evaluate/utxos",
+];
+
+/// Class of a request on the `endpoint_class` label of the request metric.
+pub fn endpoint_class(path: &str, cache_hit: bool) -> &'static str {
+    if SUBMIT_ENDPOINTS.contains(&path.trim_end_matches('/')) {
+        return "submit";
+    }
+    if cache_hit {
+        return "cached";
+    }
+    "default"
+}
 
 #[derive(Debug, Clone)]
 pub struct Endpoint {
@@ -17,6 +38,71 @@ impl Endpoint {
     }
 }
 
+/// Endpoints a port or a tier can call.
+#[derive(Debug, Clone, Default)]
+pub struct EndpointPolicy {
+    allow: Option<Vec<Endpoint>>,
+    deny: Vec<Endpoint>,
+    read_only: bool,
+}
+
+impl EndpointPolicy {
+    /// Policy that denies every request, used when a port policy can't be read.
+    pub fn deny_all() -> Self {
+        Self {
+            allow: Some(vec![]),
+            ..Default::default()
+        }
+    }
+
+    pub fn allows(&self, method: &Method, path: &str) -> bool {
+        if self.read_only && ![Method::GET, Method::HEAD, Method::OPTIONS].contains(method) {
+            return false;
+        }
+        if self.deny.iter().any(|endpoint| endpoint.matches(path)) {
+            return false;
+        }
+        self.allow
+            .as_ref()
+            .is_none_or(|allow| allow.iter().any(|endpoint| endpoint.matches(path)))
+    }
+}
+
+impl TryFrom<&BlockfrostEndpointPolicy> for EndpointPolicy {
+    type Error = RegexError;
+
+    fn try_from(value: &BlockfrostEndpointPolicy) -> Result<Self, Self::Error> {
+        let endpoints = |patterns: &Vec<String>| -> Result<Vec<Endpoint>, RegexError> {
+            patterns
+                .iter()
+                .map(|pattern| Endpoint::new(pattern))
+                .collect()
+        };
+
+        Ok(Self {
+            allow: value.allow.as_ref().map(endpoints).transpose()?,
+            deny: value
+                .deny
+                .as_ref()
+                .map(endpoints)
+                .transpose()?
+                .unwrap_or_default(),
+            read_only: value.read_only.unwrap_or_default(),
+        })
+    }
+}
+
+pub fn deserialize_endpoint_policy<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<EndpointPolicy>, D::Error> {
+    let value: Option<BlockfrostEndpointPolicy> = Deserialize::deserialize(deserializer)?;
+    value
+        .as_ref()
+        .map(EndpointPolicy::try_from)
+        .transpose()
+        .map_err(<D::Error as serde::de::Error>::custom)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -33,4 +119,43 @@ mod test {
             !fe.matches("/pools/pool18v9r8afalh50l4lstct2awdc3zspnvurcs7t45nv29uc2mnxc6c/blocks")
         );
     }
+
+    #[test]
+    fn test_endpoint_class() {
+        assert_eq!(endpoint_class("/tx/submit", false), "submit");
+        assert_eq!(endpoint_class("/utils/txs/evaluate/", false), "submit");
+        assert_eq!(endpoint_class("/blocks/latest", true), "cached");
+        assert_eq!(endpoint_class("/blocks/latest", false), "default");
+        assert_eq!(endpoint_class("/txs/submit/status", false), "default");
+    }
+
+    #[test]
+    fn test_endpoint_policy() {
+        let policy = EndpointPolicy::try_from(&BlockfrostEndpointPolicy {
+            allow: None,
+            deny: Some(vec![r"^/tx/submit$".into()]),
+            read_only: None,
+        })
+        .unwrap();
+        assert!(policy.allows(&Method::GET, "/blocks/latest"));
+        assert!(!policy.allows(&Method::POST, "/tx/submit"));
+
+        let policy = EndpointPolicy::try_from(&BlockfrostEndpointPolicy {
+            allow: Some(vec![r"^/blocks".into(), r"^/utils/txs/evaluate$".into()]),
+            deny: None,
+            read_only: Some(true),
+        })
+        .unwrap();
+        assert!(policy.allows(&Method::GET, "/blocks/latest"));
+        assert!(!policy.allows(&Method::GET, "/epochs/latest"));
+        assert!(!policy.allows(&Method::POST, "/utils/txs/evaluate"));
+
+        assert!(!EndpointPolicy::deny_all().allows(&Method::GET, "/blocks/latest"));
+
+        let invalid = BlockfrostEndpointPolicy {
+            allow: Some(vec![r"^/pools/(".into()]),
+            ..Default::default()
+        };
+        assert!(EndpointPolicy::try_from(&invalid).is_err());
+    }
 }
@@ -0,0 +1,93 @@
+use std::borrow::Cow;
+
+use once_cell::sync::Lazy;
+use openssl::{hash::MessageDigest, pkey::PKey, rand::rand_bytes, sign::Signer};
+use operator::{hash_api_key, legacy_hash_api_key, parse_api_key};
+use regex::{Captures, Regex};
+
+/// Secret of the keyed hashes, generated on start so it never leaves the process.
+static SECRET: Lazy<[u8; 32]> = Lazy::new(|| {
+    let mut secret = [0; 32];
+    rand_bytes(&mut secret).expect("failed to generate the key hash secret");
+    secret
+});
+
+static KEY_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)dmtr_[\w-]*").unwrap());
+
+/// HMAC-SHA256 of a key hash published on a port status. Consumers are indexed by it, so
+/// the proxy memory holds no value that can be matched against a key or a port status
+/// without the process secret.
+pub fn keyed_hash(key_hash: &str) -> String {
+    let pkey = PKey::hmac(SECRET.as_slice()).unwrap();
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey).unwrap();
+    signer.update(key_hash.as_bytes()).unwrap();
+    signer
+        .sign_to_vec()
+        .unwrap()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Keyed hash of a request key, through its status hash so both sides match.
+pub fn hash_key(key: &str, secret: &str) -> String {
+    keyed_hash(&hash_api_key(key, secret))
+}
+
+/// Keyed hash of a request key through the unkeyed status hash, for the ports the operator
+/// hasn't moved to keyed hashes yet.
+pub fn legacy_hash_key(key: &str) -> String {
+    keyed_hash(&legacy_hash_api_key(key))
+}
+
+/// Replaces the keys in a log line, e.g. in the hostname of authenticated endpoint urls,
+/// keeping the version and network of well formed keys.
+pub fn redact_keys(text: &str) -> Cow<'_, str> {
+    KEY_REGEX.replace_all(text, |captures: &Captures| {
+        match parse_api_key(&captures[0]) {
+            Some(prefix) => format!(
+                "dmtr_blockfrost_{}_{}_[redacted]",
+                prefix.version, prefix.network
+            ),
+            None => "dmtr_[redacted]".to_string(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    static KEY: &str = "dmtr_blockfrost_v1_preview_15feryxhrdz9m6y09mrz26ed6";
+
+    #[test]
+    fn test_keyed_hash() {
+        let key_hash = hash_api_key(KEY, "secret");
+        assert_eq!(hash_key(KEY, "secret"), keyed_hash(&key_hash));
+        assert_ne!(keyed_hash(&key_hash), key_hash);
+        assert_ne!(
+            hash_key(KEY, "secret"),
+            hash_key("dmtr_blockfrost_v1_preview_1other", "secret")
+        );
+        assert_ne!(hash_key(KEY, "secret"), legacy_hash_key(KEY));
+        assert_eq!(legacy_hash_key(KEY), keyed_hash(&legacy_hash_api_key(KEY)));
+        assert_eq!(hash_key(KEY, "secret").len(), 64);
+    }
+
+    #[test]
+    fn test_redact_keys() {
+        let summary = format!("GET /blocks/latest, Host: {KEY}.blockfrost-m1.demeter.run");
+        assert_eq!(
+            redact_keys(&summary),
+            "GET /blocks/latest, Host: dmtr_blockfrost_v1_preview_[redacted].blockfrost-m1.demeter.run"
+        );
+        assert_eq!(
+            redact_keys("Host: dmtr_blockfrost_truncated.demeter.run"),
+            "Host: dmtr_[redacted].demeter.run"
+        );
+        assert_eq!(
+            redact_keys("GET /blocks/latest, Host: api.example.com"),
+            "GET /blocks/latest, Host: api.example.com"
+        );
+    }
+}
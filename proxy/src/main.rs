@@ -1,10 +1,15 @@
 use auth::AuthBackgroundService;
 use cache_rules::{CacheRule, CacheRuleBackgroundService};
+use chrono::{DateTime, Utc};
 use config::Config;
+use domains::DomainCertificates;
 use dotenv::dotenv;
+use endpoints::{deserialize_endpoint_policy, EndpointPolicy};
+use ipnet::IpNet;
+use keys::{hash_key, keyed_hash, legacy_hash_key};
 use once_cell::sync::Lazy;
 use operator::kube::ResourceExt;
-use operator::BlockfrostPort;
+use operator::{parse_ip_network, BlockfrostPort, DEFAULT_KEY_NAME};
 use pingora::{
     listeners::tls::TlsSettings,
     server::{
@@ -12,6 +17,7 @@ use pingora::{
         Server,
     },
     services::background::background_service,
+    tls::ssl::SslFiletype,
 };
 use pingora_cache::eviction::simple_lru::Manager;
 use pingora_limits::rate::Rate;
@@ -32,22 +38,29 @@ use std::{
 };
 use tiers::TierBackgroundService;
 use tokio::sync::RwLock;
-use tracing::Level;
+use tracing::{warn, Level};
 
 use crate::utils::handle_legacy_networks;
 
 mod auth;
 mod cache_rules;
+mod client_ip;
 mod config;
+mod cors;
+mod domains;
 mod endpoints;
+mod keys;
 mod proxy;
 mod redb_storage;
+mod resources;
 mod routing;
 mod tiers;
 mod utils;
 
 static CACHE: Lazy<ReDbCache> = Lazy::new(|| ReDbCache::new(Config::new().cache_db_path));
 static EVICTION: Lazy<Manager> = Lazy::new(|| Manager::new(Config::new().cache_max_size_bytes));
+// Collectors are registered once in the default registry and shared by every State.
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
 
 fn main() {
     dotenv().ok();
@@ -55,12 +68,17 @@ fn main() {
     tracing_subscriber::fmt().with_max_level(Level::INFO).init();
 
     let config: Arc<Config> = Arc::default();
-    let state: Arc<State> = Arc::default();
+    let state = Arc::new(State {
+        api_key_hash_secret: config.api_key_hash_secret.clone(),
+        ..Default::default()
+    });
 
     let opt = Opt::default();
-    let mut server_conf = ServerConf::default();
-    server_conf.grace_period_seconds = Some(config.grace_period_seconds);
-    server_conf.graceful_shutdown_timeout_seconds = Some(config.graceful_shutdown_timeout_seconds);
+    let server_conf = ServerConf {
+        grace_period_seconds: Some(config.grace_period_seconds),
+        graceful_shutdown_timeout_seconds: Some(config.graceful_shutdown_timeout_seconds),
+        ..Default::default()
+    };
 
     let mut server = Server::new_with_opt_and_conf(Some(opt), server_conf);
     server.bootstrap();
@@ -84,6 +102,7 @@ fn main() {
         "Routing Service",
         RoutingBackgroundService::new(
             state.clone(),
+            config.config_source,
             Arc::new(config.routing_config_path.clone()),
             config.routing_poll_interval,
         ),
@@ -99,8 +118,21 @@ fn main() {
         BlockfrostProxy::new(state.clone(), config.clone()),
     );
 
-    let mut tls_settings =
-        TlsSettings::intermediate(&config.ssl_crt_path, &config.ssl_key_path).unwrap();
+    let mut tls_settings = match &config.custom_domains_certs_path {
+        Some(path) => {
+            let certificates = DomainCertificates::new(state.clone(), path.clone());
+            let mut tls_settings = TlsSettings::with_callbacks(Box::new(certificates)).unwrap();
+            // Hostnames without a domain certificate get the default one.
+            tls_settings
+                .set_certificate_chain_file(&config.ssl_crt_path)
+                .unwrap();
+            tls_settings
+                .set_private_key_file(&config.ssl_key_path, SslFiletype::PEM)
+                .unwrap();
+            tls_settings
+        }
+        None => TlsSettings::intermediate(&config.ssl_crt_path, &config.ssl_key_path).unwrap(),
+    };
 
     // {
     //     use std::ops::DerefMut;
@@ -129,17 +161,48 @@ fn main() {
 
 #[derive(Default)]
 pub struct State {
+    // Indexed by the keyed hash of each key, the plaintext keys are never kept.
     consumers: RwLock<HashMap<String, Consumer>>,
     tiers: RwLock<HashMap<String, Tier>>,
     limiter: RwLock<HashMap<String, Vec<(TierRate, Rate)>>>,
+    // Monthly usage of each port, keyed as the limiter.
+    quotas: RwLock<HashMap<String, PortUsage>>,
+    // Every port claiming each custom domain, the owner first.
+    domains: RwLock<HashMap<String, Vec<CustomDomain>>>,
     metrics: Metrics,
     cache_rules: RwLock<Vec<CacheRule>>,
     lifecycle: LifecycleState,
+    // Set once a port with a legacy auth token is seen, malformed keys are only looked up then.
+    legacy_keys: AtomicBool,
+    // Key of the status hashes, shared with the operator.
+    api_key_hash_secret: String,
 }
 impl State {
     pub async fn get_consumer(&self, key: &str) -> Option<Consumer> {
+        // Ports still publishing unkeyed hashes are matched until the operator rehashes them.
         let consumers = self.consumers.read().await.clone();
-        consumers.get(key).cloned()
+        consumers
+            .get(&hash_key(key, &self.api_key_hash_secret))
+            .or_else(|| consumers.get(&legacy_hash_key(key)))
+            .filter(|consumer| !consumer.is_expired(Utc::now()))
+            .cloned()
+    }
+
+    pub async fn get_consumer_by_domain(&self, host: &str) -> Option<Consumer> {
+        let key_hash = self
+            .domains
+            .read()
+            .await
+            .get(host)?
+            .first()?
+            .key_hash
+            .clone();
+        self.consumers
+            .read()
+            .await
+            .get(&key_hash)
+            .filter(|consumer| !consumer.is_expired(Utc::now()))
+            .cloned()
     }
 
     pub fn get_cache() -> &'static ReDbCache {
@@ -168,6 +231,14 @@ impl State {
         self.lifecycle.routing_ready.store(true, Ordering::Release);
     }
 
+    pub fn set_legacy_keys(&self) {
+        self.legacy_keys.store(true, Ordering::Release);
+    }
+
+    pub fn has_legacy_keys(&self) -> bool {
+        self.legacy_keys.load(Ordering::Acquire)
+    }
+
     pub fn is_ready(&self) -> bool {
         self.lifecycle.auth_ready.load(Ordering::Acquire)
             && self.lifecycle.tiers_ready.load(Ordering::Acquire)
@@ -189,8 +260,28 @@ pub struct Consumer {
     namespace: String,
     port_name: String,
     tier: String,
-    key: String,
+    key_hash: String,
+    key_name: String,
     network: String,
+    // Set for keys that are only accepted until the rotation grace period ends.
+    expires_at: Option<DateTime<Utc>>,
+    // Browser origins of the port, the upstream CORS headers are kept when not set.
+    allowed_origins: Option<Vec<String>>,
+    // Client networks of the port, any client is allowed when not set.
+    allowed_ips: Option<Vec<IpNet>>,
+    endpoint_policy: Option<EndpointPolicy>,
+    // Suspended ports stay known, so their requests get an explanation instead of a 401.
+    suspended: bool,
+    suspension_reason: Option<String>,
+}
+impl Consumer {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn is_port(&self, namespace: &str, port_name: &str) -> bool {
+        self.namespace == namespace && self.port_name == port_name
+    }
 }
 impl Display for Consumer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -201,24 +292,83 @@ impl From<&BlockfrostPort> for Consumer {
     fn from(value: &BlockfrostPort) -> Self {
         let network = handle_legacy_networks(&value.spec.network);
         let tier = value.spec.throughput_tier.to_string();
-        let key = value.status.as_ref().unwrap().auth_token.clone();
+        let key_hash = keyed_hash(&value.status.as_ref().unwrap().auth_token_hash);
         let namespace = value.metadata.namespace.as_ref().unwrap().clone();
         let port_name = value.name_any();
+        let endpoint_policy = value.spec.endpoints.as_ref().map(|policy| {
+            EndpointPolicy::try_from(policy).unwrap_or_else(|err| {
+                warn!(
+                    port_name,
+                    error = err.to_string(),
+                    "invalid endpoint policy"
+                );
+                EndpointPolicy::deny_all()
+            })
+        });
 
         Self {
             namespace,
             port_name,
             tier,
-            key,
+            key_hash,
+            key_name: DEFAULT_KEY_NAME.to_string(),
             network,
+            expires_at: None,
+            allowed_origins: value.spec.allowed_origins.clone(),
+            // Invalid entries are reported by the operator and never match.
+            allowed_ips: value
+                .spec
+                .allowed_ips
+                .as_ref()
+                .map(|ips| ips.iter().filter_map(|ip| parse_ip_network(ip)).collect()),
+            endpoint_policy,
+            suspended: value.spec.suspended.unwrap_or_default(),
+            suspension_reason: value.spec.suspension_reason.clone(),
         }
     }
 }
 
+/// Custom domain of a port, requests on it are authenticated as the port key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomDomain {
+    port: String,
+    key_hash: String,
+    // Order of the claim, see `operator::domain_claim_order`.
+    claim_order: (Option<DateTime<Utc>>, String, String),
+}
+
+/// Requests of a port in a quota period, as published by the operator plus the requests this
+/// proxy accepted since then.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PortUsage {
+    period: String,
+    requests: u64,
+}
+impl PortUsage {
+    /// Whether the quota is exhausted, starting over when the period changes.
+    pub fn is_exhausted(&mut self, period: &str, quota: u64) -> bool {
+        if self.period != period {
+            self.period = period.to_string();
+            self.requests = 0;
+        }
+        self.requests >= quota
+    }
+
+    /// Counts a request admitted by the tier rate limits.
+    pub fn count(&mut self) {
+        self.requests += 1;
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Tier {
     name: String,
     rates: Vec<TierRate>,
+    /// Requests allowed per calendar month, shared by every key of the port.
+    #[serde(default)]
+    monthly_quota: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_endpoint_policy")]
+    endpoints: Option<EndpointPolicy>,
 }
 #[derive(Debug, Clone, Deserialize)]
 pub struct TierRate {
@@ -230,15 +380,16 @@ pub fn deserialize_duration<'de, D: Deserializer<'de>>(
     deserializer: D,
 ) -> Result<Duration, D::Error> {
     let value: String = Deserialize::deserialize(deserializer)?;
+    parse_duration(&value).map_err(<D::Error as serde::de::Error>::custom)
+}
+
+/// Parses a tier interval, a number followed by s, m, h or d.
+pub fn parse_duration(value: &str) -> Result<Duration, &'static str> {
     let regex = Regex::new(r"([\d]+)([\w])").unwrap();
-    let captures = regex.captures(&value);
-    if captures.is_none() {
-        return Err(<D::Error as serde::de::Error>::custom(
-            "Invalid tier interval format",
-        ));
-    }
+    let captures = regex
+        .captures(value)
+        .ok_or("Invalid tier interval format")?;
 
-    let captures = captures.unwrap();
     let number = captures.get(1).unwrap().as_str().parse::<u64>().unwrap();
     let symbol = captures.get(2).unwrap().as_str();
 
@@ -247,13 +398,11 @@ pub fn deserialize_duration<'de, D: Deserializer<'de>>(
         "m" => Ok(Duration::from_secs(number * 60)),
         "h" => Ok(Duration::from_secs(number * 60 * 60)),
         "d" => Ok(Duration::from_secs(number * 60 * 60 * 24)),
-        _ => Err(<D::Error as serde::de::Error>::custom(
-            "Invalid symbol tier interval",
-        )),
+        _ => Err("Invalid symbol tier interval"),
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Metrics {
     http_total_request: prometheus::IntCounterVec,
     http_request_duration_seconds: prometheus::HistogramVec,
@@ -269,6 +418,8 @@ impl Metrics {
                 "status_code",
                 "network",
                 "tier",
+                "key",
+                "endpoint_class",
             ]
         )
         .unwrap();
@@ -298,6 +449,7 @@ impl Metrics {
         namespace: &str,
         instance: &str,
         status: &u16,
+        endpoint_class: &str,
     ) {
         self.http_total_request
             .with_label_values(&[
@@ -307,6 +459,8 @@ impl Metrics {
                 &status.to_string(),
                 &consumer.network,
                 &consumer.tier,
+                &consumer.key_name,
+                endpoint_class,
             ])
             .inc()
     }
@@ -331,6 +485,6 @@ impl Metrics {
 }
 impl Default for Metrics {
     fn default() -> Self {
-        Self::new()
+        METRICS.clone()
     }
 }
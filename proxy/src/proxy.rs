@@ -1,6 +1,8 @@
 use crate::routing::{Backend, ROUTER};
 use async_trait::async_trait;
+use chrono::Utc;
 use once_cell::sync::Lazy;
+use operator::{parse_api_key, quota_period, ApiKeyPrefix};
 use pingora::http::{RequestHeader, ResponseHeader, StatusCode};
 use pingora::Result;
 use pingora::{
@@ -16,8 +18,12 @@ use std::time::{Duration, Instant, SystemTime};
 use tracing::info;
 
 use crate::cache_rules::CacheRule;
+use crate::client_ip::{client_ip, is_allowed};
 use crate::config::Config;
-use crate::{Consumer, State, Tier};
+use crate::endpoints::endpoint_class;
+use crate::keys::redact_keys;
+use crate::utils::handle_legacy_networks;
+use crate::{cors, Consumer, State, Tier};
 
 static DMTR_API_KEY: &str = "dmtr-api-key";
 static CACHE_HIT_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
@@ -36,6 +42,22 @@ static CACHE_MISS_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
     )
     .unwrap()
 });
+static REJECTED_REQUEST_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "blockfrost_proxy_http_rejected_request",
+        "Number of requests of a known consumer rejected before reaching the backend.",
+        &["consumer", "namespace", "network", "tier", "reason"]
+    )
+    .unwrap()
+});
+static UNAUTHORIZED_REQUEST_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "blockfrost_proxy_http_unauthorized_request",
+        "Number of requests rejected without a consumer, by reason.",
+        &["reason"]
+    )
+    .unwrap()
+});
 static LAST_BYRON_BLOCK: u32 = 4490510;
 
 fn resolve_backend_for_config(config: &Config, network: &str, path: &str) -> Backend {
@@ -102,6 +124,28 @@ fn is_byron_block_path(path: &str) -> bool {
     false
 }
 
+/// Keys embed the network of their port, so a key can't be used against another network.
+fn is_key_network(prefix: &ApiKeyPrefix, consumer: &Consumer) -> bool {
+    handle_legacy_networks(&prefix.network) == consumer.network
+}
+
+/// Error body in the Blockfrost API format.
+fn error_body(status: StatusCode, message: &str) -> serde_json::Value {
+    serde_json::json!({
+        "status_code": status.as_u16(),
+        "error": status.canonical_reason().unwrap_or_default(),
+        "message": message,
+    })
+}
+
+/// Error body of a request from a client outside the port allowedIps.
+fn ip_not_allowed_body() -> serde_json::Value {
+    error_body(
+        StatusCode::FORBIDDEN,
+        "Client IP is not allowed for this port",
+    )
+}
+
 pub struct BlockfrostProxy {
     state: Arc<State>,
     config: Arc<Config>,
@@ -121,7 +165,7 @@ impl BlockfrostProxy {
 
     async fn has_limiter(&self, consumer: &Consumer) -> bool {
         let rate_limiter_map = self.state.limiter.read().await;
-        rate_limiter_map.get(&consumer.key).is_some()
+        rate_limiter_map.get(&consumer.to_string()).is_some()
     }
 
     async fn add_limiter(&self, consumer: &Consumer, tier: &Tier) {
@@ -135,7 +179,7 @@ impl BlockfrostProxy {
             .limiter
             .write()
             .await
-            .insert(consumer.key.clone(), rates);
+            .insert(consumer.to_string(), rates);
     }
 
     async fn limiter(&self, consumer: &Consumer) -> Result<bool> {
@@ -151,11 +195,13 @@ impl BlockfrostProxy {
         }
 
         let rate_limiter_map = self.state.limiter.read().await;
-        let rates = rate_limiter_map.get(&consumer.key).unwrap();
+        // Limits are shared by every key of the port.
+        let limiter_key = consumer.to_string();
+        let rates = rate_limiter_map.get(&limiter_key).unwrap();
 
         if rates
             .iter()
-            .any(|(t, r)| r.observe(&consumer.key, 1) > t.limit)
+            .any(|(t, r)| r.observe(&limiter_key, 1) > t.limit)
         {
             return Ok(true);
         }
@@ -163,22 +209,180 @@ impl BlockfrostProxy {
         Ok(false)
     }
 
-    fn extract_key(&self, session: &Session) -> String {
-        let host = session
+    async fn monthly_quota(&self, consumer: &Consumer) -> Option<u64> {
+        self.state
+            .tiers
+            .read()
+            .await
+            .get(&consumer.tier)
+            .and_then(|tier| tier.monthly_quota)
+    }
+
+    /// Status of a request rejected by the port monthly quota or the tier rate limits. Only
+    /// admitted requests are counted on the quota, rate limited ones are not billed.
+    async fn check_limits(&self, consumer: &Consumer) -> Result<Option<StatusCode>> {
+        let quota = self.monthly_quota(consumer).await;
+        let period = quota_period(Utc::now());
+
+        if let Some(quota) = quota {
+            let mut quotas = self.state.quotas.write().await;
+            let usage = quotas.entry(consumer.to_string()).or_default();
+            if usage.is_exhausted(&period, quota) {
+                return Ok(Some(StatusCode::PAYMENT_REQUIRED));
+            }
+        }
+
+        if self.limiter(consumer).await? {
+            return Ok(Some(StatusCode::TOO_MANY_REQUESTS));
+        }
+
+        if quota.is_some() {
+            let mut quotas = self.state.quotas.write().await;
+            quotas.entry(consumer.to_string()).or_default().count();
+        }
+        Ok(None)
+    }
+
+    async fn respond_json(
+        &self,
+        session: &mut Session,
+        status: StatusCode,
+        body: &serde_json::Value,
+    ) -> Result<()> {
+        let body = body.to_string();
+        let mut header = ResponseHeader::build(status, None)?;
+        header.insert_header("content-type", "application/json")?;
+        header.insert_header("content-length", body.len().to_string())?;
+        session
+            .write_response_header(Box::new(header), false)
+            .await?;
+        session
+            .write_response_body(Some(body.into_bytes().into()), true)
+            .await
+    }
+
+    async fn respond_suspended(&self, session: &mut Session, consumer: &Consumer) -> Result<()> {
+        let mut body = error_body(StatusCode::FORBIDDEN, "Port is suspended");
+        body["suspended"] = true.into();
+        body["reason"] = consumer.suspension_reason.clone().into();
+        self.respond_json(session, StatusCode::FORBIDDEN, &body)
+            .await
+    }
+
+    /// Message for a request denied by the port or the tier endpoint policy.
+    async fn endpoint_denied(&self, session: &Session, consumer: &Consumer) -> Option<String> {
+        let method = &session.req_header().method;
+        let path = session.req_header().uri.path();
+
+        if let Some(policy) = &consumer.endpoint_policy {
+            if !policy.allows(method, path) {
+                return Some(format!("{method} {path} is not allowed for this port"));
+            }
+        }
+
+        let tiers = self.state.tiers.read().await;
+        let policy = tiers.get(&consumer.tier)?.endpoints.as_ref()?;
+        if !policy.allows(method, path) {
+            return Some(format!(
+                "{method} {path} is not allowed for the {} tier",
+                consumer.tier
+            ));
+        }
+        None
+    }
+
+    async fn respond_quota_exceeded(&self, session: &mut Session) -> Result<()> {
+        let body = error_body(
+            StatusCode::PAYMENT_REQUIRED,
+            "Monthly request quota exceeded",
+        );
+        session.set_keepalive(None);
+        self.respond_json(session, StatusCode::PAYMENT_REQUIRED, &body)
+            .await
+    }
+
+    fn extract_host<'a>(&self, session: &'a Session) -> &'a str {
+        session
             .get_header("host")
             .and_then(|v| v.to_str().ok())
             .or_else(|| session.req_header().uri.authority().map(|a| a.as_str()))
-            .unwrap();
+            .unwrap()
+    }
+
+    /// Key of the request, borrowed from the headers so it is never copied.
+    fn extract_key<'a>(&self, session: &'a Session) -> &'a str {
+        let host = self.extract_host(session);
 
         let captures = self.host_regex.captures(host).unwrap();
 
-        let key = session
+        session
             .get_header(DMTR_API_KEY)
             .and_then(|v| v.to_str().ok())
             .or_else(|| captures.get(1).map(|v| v.as_str()))
-            .unwrap_or_default();
+            .unwrap_or_default()
+    }
+
+    /// Consumer of the request, or the reason it is unauthorized. A key header wins, then
+    /// the custom domain of a port, then the key in the hostname.
+    async fn extract_consumer(&self, session: &Session) -> std::result::Result<Consumer, &str> {
+        if session.get_header(DMTR_API_KEY).is_none() {
+            let host = self.extract_host(session);
+            let domain = host.split(':').next().unwrap_or_default();
+            if let Some(consumer) = self.state.get_consumer_by_domain(domain).await {
+                return Ok(consumer);
+            }
+        }
 
-        key.to_string()
+        let key = self.extract_key(session);
+        if key.is_empty() {
+            return Err("missing_key");
+        }
+        // Checked before the lookup, so malformed keys never wait on the consumers lock unless
+        // a port still serves a legacy auth token, which predates the key format.
+        let Some(prefix) = parse_api_key(key) else {
+            if !self.state.has_legacy_keys() {
+                return Err("malformed_key");
+            }
+            return self.state.get_consumer(key).await.ok_or("malformed_key");
+        };
+        let consumer = self.state.get_consumer(key).await.ok_or("unknown_key")?;
+        if !is_key_network(&prefix, &consumer) {
+            return Err("network_mismatch");
+        }
+        Ok(consumer)
+    }
+
+    fn is_client_allowed(&self, session: &Session, consumer: &Consumer) -> bool {
+        let Some(allowed_ips) = &consumer.allowed_ips else {
+            return true;
+        };
+
+        let socket_ip = session
+            .client_addr()
+            .and_then(|addr| addr.as_inet())
+            .map(|addr| addr.ip());
+        let forwarded_for = session
+            .req_header()
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+        let ip = client_ip(socket_ip, &forwarded_for, &self.config.trusted_proxies);
+        is_allowed(allowed_ips, ip)
+    }
+
+    fn count_rejected(&self, consumer: &Consumer, reason: &str) {
+        REJECTED_REQUEST_COUNTER
+            .with_label_values(&[
+                &consumer.to_string(),
+                &consumer.namespace,
+                &consumer.network,
+                &consumer.tier,
+                reason,
+            ])
+            .inc();
     }
 
     fn is_forbidden_endpoint(&self, path: &str) -> bool {
@@ -200,6 +404,17 @@ impl BlockfrostProxy {
         None
     }
 
+    async fn respond_preflight(&self, session: &mut Session, ctx: &mut Context) -> Result<()> {
+        ctx.is_preflight_request = true;
+        let consumer = self.extract_consumer(session).await.ok();
+        let allowed_origins = consumer
+            .as_ref()
+            .and_then(|consumer| consumer.allowed_origins.as_deref())
+            .unwrap_or_default();
+        let header = cors::preflight_response(session.req_header(), allowed_origins)?;
+        session.write_response_header(Box::new(header), true).await
+    }
+
     async fn respond_health(&self, session: &mut Session, ctx: &mut Context) {
         self.respond_status(session, ctx, 200, "OK").await;
     }
@@ -235,8 +450,12 @@ pub struct Context {
     instance: String,
     consumer: Consumer,
     cache_rule: Option<CacheRule>,
+    // Set when the response is served from the cache.
+    cache_hit: bool,
     endpoint: String,
     is_probe_request: bool,
+    // Preflights are answered by the proxy and not counted as port requests.
+    is_preflight_request: bool,
     start_time: Option<Instant>,
     resolved_by: String,
 }
@@ -253,7 +472,6 @@ impl ProxyHttp for BlockfrostProxy {
         Self::CTX: Send + Sync,
     {
         ctx.start_time = Some(Instant::now());
-        let state = self.state.clone();
 
         let path = session.req_header().uri.path();
 
@@ -273,28 +491,65 @@ impl ProxyHttp for BlockfrostProxy {
             return Ok(true);
         }
 
+        if cors::is_preflight(session.req_header()) {
+            self.respond_preflight(session, ctx).await?;
+            return Ok(true);
+        }
+
         if self.is_forbidden_endpoint(path) {
             dbg!(path);
             let _ = session.respond_error(501).await;
             return Ok(true);
         }
 
-        let key = self.extract_key(session);
-        let consumer = state.get_consumer(&key).await;
-        if consumer.is_none() {
-            let _ = session.respond_error(401).await;
+        ctx.consumer = match self.extract_consumer(session).await {
+            Ok(consumer) => consumer,
+            Err(reason) => {
+                UNAUTHORIZED_REQUEST_COUNTER
+                    .with_label_values(&[reason])
+                    .inc();
+                let _ = session.respond_error(401).await;
+                return Ok(true);
+            }
+        };
+
+        if ctx.consumer.suspended {
+            self.count_rejected(&ctx.consumer, "suspended");
+            let _ = self.respond_suspended(session, &ctx.consumer).await;
+            return Ok(true);
+        }
+
+        if !self.is_client_allowed(session, &ctx.consumer) {
+            self.count_rejected(&ctx.consumer, "ip_not_allowed");
+            let _ = self
+                .respond_json(session, StatusCode::FORBIDDEN, &ip_not_allowed_body())
+                .await;
             return Ok(true);
         }
 
-        ctx.consumer = consumer.unwrap();
+        if let Some(message) = self.endpoint_denied(session, &ctx.consumer).await {
+            self.count_rejected(&ctx.consumer, "endpoint_not_allowed");
+            let body = error_body(StatusCode::FORBIDDEN, &message);
+            let _ = self
+                .respond_json(session, StatusCode::FORBIDDEN, &body)
+                .await;
+            return Ok(true);
+        }
 
         let backend = resolve_backend_for_config(self.config.as_ref(), &ctx.consumer.network, path);
         ctx.instance = format_instance_for_config(backend, &ctx.consumer.network);
         ctx.resolved_by = backend.as_str().to_string();
 
-        if self.limiter(&ctx.consumer).await? {
-            let _ = session.respond_error(429).await;
-            return Ok(true);
+        match self.check_limits(&ctx.consumer).await? {
+            Some(StatusCode::PAYMENT_REQUIRED) => {
+                let _ = self.respond_quota_exceeded(session).await;
+                return Ok(true);
+            }
+            Some(status) => {
+                let _ = session.respond_error(status.as_u16()).await;
+                return Ok(true);
+            }
+            None => {}
         }
 
         let cache_rule = self.get_rule(path).await;
@@ -332,13 +587,29 @@ impl ProxyHttp for BlockfrostProxy {
         Ok(())
     }
 
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if let Some(allowed_origins) = &ctx.consumer.allowed_origins {
+            let origin = cors::request_origin(session.req_header());
+            cors::apply_policy(upstream_response, allowed_origins, origin)?;
+        }
+        Ok(())
+    }
+
     async fn logging(
         &self,
         session: &mut Session,
         _e: Option<&pingora::Error>,
         ctx: &mut Self::CTX,
     ) {
-        if !ctx.is_probe_request {
+        if !ctx.is_probe_request && !ctx.is_preflight_request {
             let response_code = session
                 .response_written()
                 .map_or(0, |resp| resp.status.as_u16());
@@ -348,6 +619,7 @@ impl ProxyHttp for BlockfrostProxy {
                 &self.config.proxy_namespace,
                 &ctx.instance,
                 &response_code,
+                endpoint_class(&ctx.endpoint, ctx.cache_hit),
             );
             if let Some(start) = ctx.start_time {
                 let dur = start.elapsed();
@@ -373,6 +645,12 @@ impl ProxyHttp for BlockfrostProxy {
         }
     }
 
+    /// Summary of the request on the access and error logs, without the keys sent in the
+    /// hostname.
+    fn request_summary(&self, session: &Session, _ctx: &Self::CTX) -> String {
+        redact_keys(&session.as_ref().request_summary()).into_owned()
+    }
+
     // Cache related stuff
 
     /// Build cache key from the request.
@@ -440,6 +718,7 @@ impl ProxyHttp for BlockfrostProxy {
     where
         Self::CTX: Send + Sync,
     {
+        ctx.cache_hit = true;
         let _ = &CACHE_HIT_COUNTER
             .with_label_values(&[
                 &ctx.cache_rule.clone().unwrap().endpoint.to_string(),
@@ -467,6 +746,7 @@ impl ProxyHttp for BlockfrostProxy {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::ConfigSource;
     use crate::routing::{BackendConfig, BackendsConfig, RouteConfig, RoutingConfig};
     use once_cell::sync::Lazy;
     use std::path::PathBuf;
@@ -476,13 +756,16 @@ mod tests {
 
     fn base_config() -> Config {
         Config {
+            config_source: ConfigSource::File,
             proxy_addr: "0.0.0.0:0".to_string(),
             proxy_namespace: "proxy".to_string(),
+            api_key_hash_secret: String::new(),
             proxy_tiers_path: PathBuf::from("/tmp"),
             proxy_tiers_poll_interval: Duration::from_secs(1),
             prometheus_addr: "0.0.0.0:0".to_string(),
             ssl_crt_path: "crt".to_string(),
             ssl_key_path: "key".to_string(),
+            custom_domains_certs_path: None,
             dolos_enabled: true,
             routing_config_path: PathBuf::from("/tmp/routing.toml"),
             routing_poll_interval: Duration::from_secs(1),
@@ -491,6 +774,7 @@ mod tests {
             cache_failed_requests_seconds: 5,
             cache_max_size_bytes: 1024,
             forbidden_endpoints: vec![],
+            trusted_proxies: vec![],
             health_endpoint: "/health".to_string(),
             readiness_endpoint: "/ready".to_string(),
             grace_period_seconds: 30,
@@ -582,4 +866,75 @@ mod tests {
             Backend::Blockfrost
         );
     }
+
+    #[test]
+    fn key_network_matches_consumer() {
+        let consumer = Consumer {
+            network: "cardano-mainnet".to_string(),
+            ..Default::default()
+        };
+        let prefix = |network: &str| ApiKeyPrefix {
+            version: "v1".to_string(),
+            network: network.to_string(),
+        };
+
+        assert!(is_key_network(&prefix("mainnet"), &consumer));
+        assert!(is_key_network(&prefix("cardano-mainnet"), &consumer));
+        assert!(!is_key_network(&prefix("preview"), &consumer));
+    }
+
+    #[test]
+    fn ip_not_allowed_body_has_reason() {
+        let body = ip_not_allowed_body();
+        assert_eq!(body["status_code"], 403);
+        assert_eq!(body["error"], "Forbidden");
+        assert_eq!(body["message"], "Client IP is not allowed for this port");
+    }
+
+    #[test]
+    fn quota_resets_on_period_change() {
+        let mut usage = crate::PortUsage::default();
+        assert!(!usage.is_exhausted("2024-01", 2));
+        usage.count();
+        usage.count();
+        assert!(usage.is_exhausted("2024-01", 2));
+
+        assert!(!usage.is_exhausted("2024-02", 2));
+        usage.count();
+        assert_eq!(
+            usage,
+            crate::PortUsage {
+                period: "2024-02".into(),
+                requests: 1
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_keep_quota() {
+        let state = Arc::new(State::default());
+        let tier: Tier = serde_json::from_value(serde_json::json!({
+            "name": "tier0",
+            "rates": [{ "limit": 1, "interval": "1m" }],
+            "monthly_quota": 10
+        }))
+        .unwrap();
+        state.tiers.write().await.insert("tier0".into(), tier);
+        let proxy = BlockfrostProxy::new(state.clone(), Arc::new(base_config()));
+        let consumer = Consumer {
+            namespace: "prj-test".into(),
+            port_name: "port".into(),
+            tier: "tier0".into(),
+            ..Default::default()
+        };
+
+        assert_eq!(proxy.check_limits(&consumer).await.unwrap(), None);
+        for _ in 0..3 {
+            assert_eq!(
+                proxy.check_limits(&consumer).await.unwrap(),
+                Some(StatusCode::TOO_MANY_REQUESTS)
+            );
+        }
+        assert_eq!(state.quotas.read().await["prj-test.port"].requests, 1);
+    }
 }
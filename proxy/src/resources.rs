@@ -0,0 +1,25 @@
+use std::{fmt::Debug, hash::Hash, sync::Arc};
+
+use futures_util::{Stream, TryStreamExt};
+use operator::kube::{
+    runtime::{reflector, watcher},
+    Api, Client, Resource, ResourceExt,
+};
+use serde::de::DeserializeOwned;
+
+/// Every `K` object in the cluster sorted by name, listed again after each
+/// change. The first item is the initial list.
+pub fn watch_resources<K>(client: Client) -> impl Stream<Item = Result<Vec<Arc<K>>, watcher::Error>>
+where
+    K: Resource + Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+    K::DynamicType: Default + Eq + Hash + Clone,
+{
+    let (reader, writer) = reflector::store();
+    let api = Api::<K>::all(client);
+
+    reflector(writer, watcher(api, watcher::Config::default())).map_ok(move |_| {
+        let mut objects = reader.state();
+        objects.sort_by_key(|object| object.name_any());
+        objects
+    })
+}
@@ -1,19 +1,23 @@
 use std::{error::Error, fs, sync::Arc};
 
 use async_trait::async_trait;
+use futures_util::TryStreamExt;
 use notify::{Event, PollWatcher, RecursiveMode, Watcher};
+use operator::{kube::Client, BlockfrostRoute};
 use pingora::{
     server::ShutdownWatch,
     services::{background::BackgroundService, ServiceReadyNotifier},
 };
+use tokio::{pin, time::sleep};
 use tracing::{error, info, warn};
 
-use crate::State;
+use crate::{config::ConfigSource, resources::watch_resources, State};
 
-use super::{RoutingConfig, ROUTER};
+use super::{RouteConfig, RoutingConfig, ROUTER};
 
 pub struct RoutingBackgroundService {
     state: Arc<State>,
+    config_source: ConfigSource,
     config_path: Arc<std::path::PathBuf>,
     poll_interval: std::time::Duration,
 }
@@ -21,11 +25,13 @@ pub struct RoutingBackgroundService {
 impl RoutingBackgroundService {
     pub fn new(
         state: Arc<State>,
+        config_source: ConfigSource,
         config_path: Arc<std::path::PathBuf>,
         poll_interval: std::time::Duration,
     ) -> Self {
         Self {
             state,
+            config_source,
             config_path,
             poll_interval,
         }
@@ -38,6 +44,65 @@ impl RoutingBackgroundService {
         ROUTER.store(std::sync::Arc::new(router));
         Ok(())
     }
+
+    /// Builds the router from BlockfrostRoute objects. The backends and the default backend
+    /// still come from the routing file when it is set, its routes are ignored.
+    fn update_router_from_crds(&self, crds: &[Arc<BlockfrostRoute>]) -> Result<(), Box<dyn Error>> {
+        let mut cfg = if self.config_path.as_os_str().is_empty() {
+            RoutingConfig::default()
+        } else {
+            toml::from_str(&fs::read_to_string(&*self.config_path)?)?
+        };
+        cfg.routes = crds
+            .iter()
+            .map(|crd| RouteConfig::from(crd.as_ref()))
+            .collect();
+
+        let router = cfg.build_router()?;
+        ROUTER.store(std::sync::Arc::new(router));
+        Ok(())
+    }
+
+    async fn watch_crds(&self, mut shutdown: ShutdownWatch, ready_notifier: ServiceReadyNotifier) {
+        let client = Client::try_default()
+            .await
+            .expect("failed to create kube client");
+
+        let stream = watch_resources::<BlockfrostRoute>(client);
+        pin!(stream);
+        let mut ready_notifier = Some(ready_notifier);
+
+        loop {
+            let result = tokio::select! {
+                _ = shutdown.changed() => {
+                    info!("routing: shutdown requested");
+                    break;
+                }
+                result = stream.try_next() => result,
+            };
+
+            match result {
+                Ok(Some(crds)) => {
+                    // An invalid set of routes keeps the previous router.
+                    if let Err(err) = self.update_router_from_crds(&crds) {
+                        warn!(error = err.to_string(), "invalid routing reload");
+                        continue;
+                    }
+                    info!("routing modified");
+
+                    if let Some(ready_notifier) = ready_notifier.take() {
+                        self.state.set_routing_ready();
+                        ready_notifier.notify_ready();
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    error!(error = err.to_string(), "error to watch routing");
+                    sleep(std::time::Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -47,6 +112,10 @@ impl BackgroundService for RoutingBackgroundService {
         mut shutdown: ShutdownWatch,
         ready_notifier: ServiceReadyNotifier,
     ) {
+        if self.config_source == ConfigSource::Kubernetes {
+            return self.watch_crds(shutdown, ready_notifier).await;
+        }
+
         if let Err(err) = self.update_router().await {
             error!(error = err.to_string(), "error to update routing");
             return;
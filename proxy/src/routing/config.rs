@@ -1,3 +1,4 @@
+use operator::BlockfrostRoute;
 use serde::Deserialize;
 
 use super::router::Router;
@@ -22,6 +23,15 @@ pub struct RouteConfig {
     pub backend: String,
 }
 
+impl From<&BlockfrostRoute> for RouteConfig {
+    fn from(crd: &BlockfrostRoute) -> Self {
+        Self {
+            path: crd.spec.path.clone(),
+            backend: crd.spec.backend.as_str().to_string(),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct BackendConfig {
     pub template: String,
@@ -41,6 +51,16 @@ fn default_backend() -> String {
     "blockfrost".to_string()
 }
 
+impl Default for RoutingConfig {
+    fn default() -> Self {
+        Self {
+            default_backend: default_backend(),
+            backends: BackendsConfig::default(),
+            routes: vec![],
+        }
+    }
+}
+
 impl BackendConfig {
     pub(crate) fn supports_network(&self, network: &str) -> bool {
         self.supported_networks.is_empty()
@@ -149,6 +169,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn route_from_crd() {
+        let crd = BlockfrostRoute::new(
+            "tx-submit",
+            operator::BlockfrostRouteSpec {
+                path: "/tx/submit".into(),
+                backend: operator::RouteBackend::Submitapi,
+            },
+        );
+        let cfg = RoutingConfig {
+            routes: vec![RouteConfig::from(&crd)],
+            ..Default::default()
+        };
+        let router = cfg.build_router().unwrap();
+        assert_eq!(router.resolve("/tx/submit"), Backend::SubmitApi);
+        assert_eq!(router.resolve("/blocks/abc"), Backend::Blockfrost);
+    }
+
     #[test]
     fn routing_config_deserializes_backend_support() {
         let cfg: RoutingConfig = toml::from_str(
@@ -2,15 +2,57 @@ use std::error::Error;
 use std::{fs, sync::Arc};
 
 use async_trait::async_trait;
+use futures_util::TryStreamExt;
 use notify::{Event, PollWatcher, RecursiveMode, Watcher};
+use operator::{
+    kube::{Client, ResourceExt},
+    BlockfrostTier,
+};
 use pingora::{
     server::ShutdownWatch,
     services::{background::BackgroundService, ServiceReadyNotifier},
 };
 use serde_json::Value;
+use tokio::{pin, time::sleep};
 use tracing::{error, info, warn};
 
-use crate::{config::Config, State, Tier};
+use crate::{
+    config::{Config, ConfigSource},
+    endpoints::EndpointPolicy,
+    parse_duration,
+    resources::watch_resources,
+    State, Tier, TierRate,
+};
+
+fn tier_from_crd(crd: &BlockfrostTier) -> Result<Tier, String> {
+    let rates = crd
+        .spec
+        .rates
+        .iter()
+        .map(|rate| {
+            Ok(TierRate {
+                limit: isize::try_from(rate.limit).map_err(|_| "Tier limit out of range")?,
+                interval: parse_duration(&rate.interval)?,
+            })
+        })
+        .collect::<Result<_, &str>>()
+        .map_err(|err| format!("tier {}: {err}", crd.name_any()))?;
+
+    let endpoints = crd
+        .spec
+        .endpoints
+        .as_ref()
+        .map(EndpointPolicy::try_from)
+        .transpose()
+        .map_err(|err| format!("tier {}: {err}", crd.name_any()))?;
+
+    Ok(Tier {
+        name: crd.name_any(),
+        rates,
+        monthly_quota: crd.spec.monthly_quota,
+        endpoints,
+    })
+}
 
 pub struct TierBackgroundService {
     state: Arc<State>,
@@ -32,15 +74,68 @@ impl TierBackgroundService {
         }
 
         let tiers = serde_json::from_value::<Vec<Tier>>(tiers_value.unwrap().to_owned())?;
+        self.set_tiers(tiers).await;
+
+        Ok(())
+    }
 
+    async fn set_tiers(&self, tiers: Vec<Tier>) {
         *self.state.tiers.write().await = tiers
             .into_iter()
             .map(|tier| (tier.name.clone(), tier))
             .collect();
 
         self.state.limiter.write().await.clear();
+    }
 
-        Ok(())
+    async fn watch_crds(&self, mut shutdown: ShutdownWatch, ready_notifier: ServiceReadyNotifier) {
+        let client = Client::try_default()
+            .await
+            .expect("failed to create kube client");
+
+        let stream = watch_resources::<BlockfrostTier>(client);
+        pin!(stream);
+        let mut ready_notifier = Some(ready_notifier);
+
+        loop {
+            let result = tokio::select! {
+                _ = shutdown.changed() => {
+                    info!("tiers: shutdown requested");
+                    break;
+                }
+                result = stream.try_next() => result,
+            };
+
+            match result {
+                Ok(Some(crds)) => {
+                    // An invalid tier keeps its last valid version, so its ports keep their
+                    // limits. Tiers are validated at apply time by the operator webhook.
+                    let previous = self.state.tiers.read().await.clone();
+                    let tiers = crds
+                        .iter()
+                        .filter_map(|crd| match tier_from_crd(crd) {
+                            Ok(tier) => Some(tier),
+                            Err(err) => {
+                                error!(error = err, "invalid tier, keeping the last valid one");
+                                previous.get(&crd.name_any()).cloned()
+                            }
+                        })
+                        .collect();
+                    self.set_tiers(tiers).await;
+                    info!("tiers modified");
+
+                    if let Some(ready_notifier) = ready_notifier.take() {
+                        self.state.set_tiers_ready();
+                        ready_notifier.notify_ready();
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    error!(error = err.to_string(), "error to watch tiers");
+                    sleep(std::time::Duration::from_secs(1)).await;
+                }
+            }
+        }
     }
 }
 
@@ -51,6 +146,10 @@ impl BackgroundService for TierBackgroundService {
         mut shutdown: ShutdownWatch,
         ready_notifier: ServiceReadyNotifier,
     ) {
+        if self.config.config_source == ConfigSource::Kubernetes {
+            return self.watch_crds(shutdown, ready_notifier).await;
+        }
+
         if let Err(err) = self.update_tiers().await {
             error!(error = err.to_string(), "error to update tiers");
             return;
@@ -106,3 +205,40 @@ impl BackgroundService for TierBackgroundService {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use operator::{BlockfrostEndpointPolicy, BlockfrostTierRate, BlockfrostTierSpec};
+
+    use super::*;
+
+    #[test]
+    fn test_tier_from_crd() {
+        let crd = BlockfrostTier::new(
+            "1",
+            BlockfrostTierSpec {
+                rates: vec![BlockfrostTierRate {
+                    limit: 10,
+                    interval: "1m".into(),
+                }],
+                monthly_quota: Some(1000),
+                endpoints: None,
+            },
+        );
+        let tier = tier_from_crd(&crd).unwrap();
+        assert_eq!(tier.name, "1");
+        assert_eq!(tier.rates[0].interval, std::time::Duration::from_secs(60));
+        assert_eq!(tier.monthly_quota, Some(1000));
+
+        let mut invalid = crd.clone();
+        invalid.spec.rates[0].interval = "1".into();
+        assert!(tier_from_crd(&invalid).is_err());
+
+        let mut invalid = crd.clone();
+        invalid.spec.endpoints = Some(BlockfrostEndpointPolicy {
+            deny: Some(vec!["^/pools/(".into()]),
+            ..Default::default()
+        });
+        assert!(tier_from_crd(&invalid).is_err());
+    }
+}